@@ -12,7 +12,9 @@ function App() {
   if (!userId) {
     return <Login userId={userId} setUserId={setUserId} />;
   } else {
-    const client = new ServerSentEventClient(baseUrl, userId);
+    // Create a token with `reportctl token --subject <userId>` when the server requires one
+    const token = import.meta.env.VITE_SSE_TOKEN;
+    const client = new ServerSentEventClient(baseUrl, userId, token);
     return <ReportStatusTable client={client} />;
   }
 }
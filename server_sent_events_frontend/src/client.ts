@@ -1,4 +1,10 @@
-import { Report, ReportStatusUpdate } from "./report";
+import {
+  Report,
+  ReportDeleted,
+  ReportList,
+  ReportProgress,
+  ReportStatusUpdate,
+} from "./report";
 
 // FIXME(ytmimi) I don't know how to type the event type from the server sent event so I'm just using `any`
 export function reportStatusUpdateEventListener(
@@ -24,20 +30,63 @@ export function reportStatusUpdateEventListener(
 
   const newReportList = [...reportList];
   newReportList[reportIndex].reportStatus = statusUpdate.status;
+  newReportList[reportIndex].statusReason = statusUpdate.reason;
   reportUpdateFunction(newReportList);
 }
 
+// FIXME(ytmimi) I don't know how to type the event type from the server sent event so I'm just using `any`
+export function reportProgressEventListener(
+  e: any,
+  reportList: Report[],
+  reportUpdateFunction: (r: Report[]) => void,
+) {
+  console.log(`reportProgressEventListener got data: ${e.data}`);
+  const reportProgress: ReportProgress = JSON.parse(e.data);
+
+  const reportIndex = reportList.findIndex(
+    (value) => value.reportId === reportProgress.id,
+  );
+
+  if (reportIndex === -1) {
+    console.log(`Could not find a reportId '${reportProgress.id}'`);
+    return;
+  }
+
+  const newReportList = [...reportList];
+  newReportList[reportIndex].progress = reportProgress.progress;
+  reportUpdateFunction(newReportList);
+}
+
+// FIXME(ytmimi) I don't know how to type the event type from the server sent event so I'm just using `any`
+export function reportDeletedEventListener(
+  e: any,
+  reportList: Report[],
+  reportUpdateFunction: (r: Report[]) => void,
+) {
+  console.log(`reportDeletedEventListener got data: ${e.data}`);
+  const reportDeleted: ReportDeleted = JSON.parse(e.data);
+  reportUpdateFunction(
+    reportList.filter((value) => value.reportId !== reportDeleted.id),
+  );
+}
+
 export class ServerSentEventClient {
   baseUrl: string;
   userId: string;
+  // Signed token identifying the user. Only needed when the server has `SSE_JWT_SECRET` set
+  token?: string;
   eventSource: EventSource;
 
-  constructor(baseUrl: string, userId: string) {
+  constructor(baseUrl: string, userId: string, token?: string) {
     this.baseUrl = baseUrl;
     this.userId = userId;
+    this.token = token;
 
-    // URL for server sent events
-    const url = `${this.baseUrl}/sse?user_id=${this.userId}`;
+    // URL for server sent events. `EventSource` can't set headers, so the token goes in the URL
+    let url = `${this.baseUrl}/sse?user_id=${this.userId}`;
+    if (this.token) {
+      url += `&access_token=${encodeURIComponent(this.token)}`;
+    }
     this.eventSource = new EventSource(url);
 
     // Close the connection if we experience an error
@@ -54,34 +103,75 @@ export class ServerSentEventClient {
     this.eventSource.addEventListener("report_status_update", listener);
   }
 
-  async listReports(): Promise<Report[]> {
-    const url = `${this.baseUrl}/reports?user_id=${this.userId}`;
+  // FIXME(ytmimi) I don't know how to type the event type from the server sent event so I'm just using `any`
+  addReportProgressEventListener(listener: (e: any) => void) {
+    this.eventSource.addEventListener("report_progress", listener);
+  }
 
-    const response = await fetch(url);
+  // FIXME(ytmimi) I don't know how to type the event type from the server sent event so I'm just using `any`
+  addReportDeletedEventListener(listener: (e: any) => void) {
+    this.eventSource.addEventListener("report_deleted", listener);
+  }
 
-    if (!response.ok) {
-      console.log(response.statusText);
-      return [];
-    }
+  authHeaders(): Record<string, string> {
+    return this.token ? { Authorization: `Bearer ${this.token}` } : {};
+  }
 
-    const data = await response.json();
-    console.log(`Listing reports ${JSON.stringify(data)}`);
-
-    // The API should return a response of `Report[]`
-    // [
-    //   {
-    //      "userId":"046fe7f4-c0e6-4d51-81ab-572deddc8142",
-    //      "reportId":"9c676d07-98b8-4e10-bf9e-dfad292676ef",
-    //      "reportStatus":"pending"
-    //   }
-    // ]
-    return data;
+  async listReports(): Promise<Report[]> {
+    const reports: Report[] = [];
+    let cursor: string | null = null;
+
+    // Keep fetching pages until the server stops sending a `nextCursor`
+    do {
+      let url = `${this.baseUrl}/reports?user_id=${this.userId}`;
+      if (cursor) {
+        url += `&cursor=${encodeURIComponent(cursor)}`;
+      }
+
+      const response = await fetch(url, { headers: this.authHeaders() });
+
+      if (!response.ok) {
+        console.log(response.statusText);
+        return reports;
+      }
+
+      const page: ReportList = await response.json();
+      console.log(`Listing reports ${JSON.stringify(page)}`);
+
+      // The API should return a response of `ReportList`
+      // {
+      //   "reports": [
+      //     {
+      //        "userId":"046fe7f4-c0e6-4d51-81ab-572deddc8142",
+      //        "reportId":"9c676d07-98b8-4e10-bf9e-dfad292676ef",
+      //        "reportStatus":"pending",
+      //        "name":"Quarterly sales",
+      //        "parameters":{},
+      //        "createdAt":"2024-01-01T12:00:00Z",
+      //        "updatedAt":"2024-01-01T12:00:00Z",
+      //        "archived":false
+      //     }
+      //   ],
+      //   "nextCursor": null
+      // }
+      reports.push(...page.reports);
+      cursor = page.nextCursor;
+    } while (cursor);
+
+    return reports;
   }
 
-  async newReport(): Promise<Report | null> {
+  async newReport(
+    name: string,
+    parameters: Record<string, unknown> = {},
+  ): Promise<Report | null> {
     const url = `${this.baseUrl}/new/report?user_id=${this.userId}`;
 
-    const response = await fetch(url, { method: "POST" });
+    const response = await fetch(url, {
+      method: "POST",
+      headers: { "Content-Type": "application/json", ...this.authHeaders() },
+      body: JSON.stringify({ name, parameters }),
+    });
 
     if (!response.ok) {
       console.log(response.statusText);
@@ -95,8 +185,29 @@ export class ServerSentEventClient {
     // {
     //    "userId":"046fe7f4-c0e6-4d51-81ab-572deddc8142",
     //    "reportId":"9c676d07-98b8-4e10-bf9e-dfad292676ef",
-    //    "reportStatus":"pending"
+    //    "reportStatus":"pending",
+    //    "name":"Quarterly sales",
+    //    "parameters":{},
+    //    "createdAt":"2024-01-01T12:00:00Z",
+    //    "updatedAt":"2024-01-01T12:00:00Z"
     // }
     return data;
   }
+
+  async deleteReport(reportId: string): Promise<boolean> {
+    const url = `${this.baseUrl}/report/${reportId}?user_id=${this.userId}`;
+
+    const response = await fetch(url, {
+      method: "DELETE",
+      headers: this.authHeaders(),
+    });
+
+    if (!response.ok) {
+      console.log(response.statusText);
+      return false;
+    }
+
+    // The `report_deleted` event removes the report from the table
+    return true;
+  }
 }
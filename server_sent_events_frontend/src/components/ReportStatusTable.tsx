@@ -1,7 +1,9 @@
 import { useState, useEffect } from "react";
-import { Report } from "../report";
+import { Report, ReportStatus } from "../report";
 import {
   ServerSentEventClient,
+  reportDeletedEventListener,
+  reportProgressEventListener,
   reportStatusUpdateEventListener,
 } from "../client";
 import { StatusCircle } from "./StatusCircle";
@@ -24,6 +26,14 @@ export function ReportStatusTable({
     reportStatusUpdateEventListener(e, reports, setReports),
   );
 
+  client.addReportProgressEventListener((e) =>
+    reportProgressEventListener(e, reports, setReports),
+  );
+
+  client.addReportDeletedEventListener((e) =>
+    reportDeletedEventListener(e, reports, setReports),
+  );
+
   return (
     <>
       <h1>Report Status</h1>
@@ -31,7 +41,7 @@ export function ReportStatusTable({
       <button
         className="new-report"
         onClick={() => {
-          client.newReport().then((data) => {
+          client.newReport(`Report ${reports.length + 1}`).then((data) => {
             if (!data) {
               console.log("Didn't get a new report");
               return;
@@ -50,8 +60,10 @@ export function ReportStatusTable({
         <thead>
           <tr>
             <th style={{ width: "10%" }}></th>
-            <th style={{ width: "45%" }}>Report Status</th>
-            <th style={{ width: "45%" }}>Report ID</th>
+            <th style={{ width: "20%" }}>Name</th>
+            <th style={{ width: "20%" }}>Report Status</th>
+            <th style={{ width: "40%" }}>Report ID</th>
+            <th style={{ width: "10%" }}></th>
           </tr>
         </thead>
         <tbody>
@@ -60,8 +72,21 @@ export function ReportStatusTable({
               <td>
                 <StatusCircle status={report.reportStatus} />
               </td>
-              <td>{report.reportStatus}</td>
+              <td>{report.name}</td>
+              <td>
+                {report.reportStatus}
+                {report.reportStatus === ReportStatus.Processing &&
+                report.progress !== undefined
+                  ? ` (${report.progress}%)`
+                  : ""}
+                {report.statusReason ? ` - ${report.statusReason}` : ""}
+              </td>
               <td>{report.reportId}</td>
+              <td>
+                <button onClick={() => client.deleteReport(report.reportId)}>
+                  Delete
+                </button>
+              </td>
             </tr>
           ))}
         </tbody>
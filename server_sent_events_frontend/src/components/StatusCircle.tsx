@@ -9,6 +9,8 @@ export function StatusCircle({ status }: { status: ReportStatus }) {
     color = "orange";
   } else if (status == ReportStatus.Processing) {
     color = "#93C47D"; // light green
+  } else if (status == ReportStatus.Paused) {
+    color = "gray";
   } else if (status == ReportStatus.Canceled) {
     color = "#E06666"; // light red
   } else if (status == ReportStatus.Failed) {
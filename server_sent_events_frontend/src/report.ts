@@ -2,6 +2,7 @@ export enum ReportStatus {
   Pending = "pending",
   Queued = "queued",
   Processing = "processing",
+  Paused = "paused",
   Canceled = "canceled",
   Failed = "failed",
   Completed = "completed",
@@ -11,9 +12,36 @@ export type Report = {
   userId: string;
   reportId: string;
   reportStatus: ReportStatus;
+  name: string;
+  parameters: Record<string, unknown>;
+  createdAt: string;
+  updatedAt: string;
+  statusChangedAt: string;
+  // Why the report ended up in its current status, e.g. when the watchdog timed it out
+  statusReason?: string;
+  // Percent complete. Only set while the report is processing
+  progress?: number;
+  archived: boolean;
+};
+
+export type ReportList = {
+  reports: Report[];
+  // Pass this back as the `cursor` query parameter to get the next page
+  nextCursor: string | null;
 };
 
 export type ReportStatusUpdate = {
   id: string;
   status: ReportStatus;
+  source?: "kafka" | "api" | "watchdog" | "worker" | "simulator" | "websocket";
+  reason?: string;
+};
+
+export type ReportProgress = {
+  id: string;
+  progress: number;
+};
+
+export type ReportDeleted = {
+  id: string;
 };
@@ -0,0 +1,299 @@
+//! Figure out who is making a request.
+//!
+//! When `SSE_JWT_SECRET` is set every request needs an HS256 JWT whose `sub` claim is the user.
+//! The token can be sent in the `Authorization: Bearer <token>` header, the `sse_token` cookie,
+//! or the `access_token` query parameter, since browsers can't set headers on an `EventSource`.
+//!
+//! Without a secret the app only trusts the `username` (v1-v3) or `user_id` (v4) query parameter
+//! when [AuthConfig::insecure_query_auth] is set. Otherwise every request is rejected.
+use axum::async_trait;
+use axum::extract::{FromRequestParts, Query};
+use axum::http::request::Parts;
+use axum::http::{header, StatusCode};
+use axum::response::{IntoResponse, Response};
+use headers::{Cookie, HeaderMapExt};
+use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use uuid::Uuid;
+
+use crate::config::AuthConfig;
+
+/// Name of the cookie that can hold the token
+const TOKEN_COOKIE: &str = "sse_token";
+
+/// Name of the query parameter that can hold the token
+const TOKEN_QUERY_PARAM: &str = "access_token";
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+struct Claims {
+    sub: String,
+    exp: u64,
+}
+
+/// Validates tokens. Added to every request as an extension so that the extractors can get to it
+/// no matter which version's state the router uses
+#[derive(Clone)]
+pub(crate) struct Authenticator {
+    key: Option<Arc<DecodingKey>>,
+    insecure_query_auth: bool,
+}
+
+impl Authenticator {
+    pub(crate) fn new(config: &AuthConfig) -> Self {
+        match (&config.jwt_secret, config.insecure_query_auth) {
+            (Some(_), _) => {}
+            (None, true) => tracing::warn!(
+                "SSE_JWT_SECRET isn't set. Users are identified by the query string without any authentication"
+            ),
+            (None, false) => tracing::error!(
+                "SSE_JWT_SECRET isn't set and insecure query string auth isn't allowed. Every request will be rejected"
+            ),
+        }
+        Self {
+            key: config
+                .jwt_secret
+                .as_ref()
+                .map(|secret| Arc::new(DecodingKey::from_secret(secret.as_bytes()))),
+            insecure_query_auth: config.insecure_query_auth,
+        }
+    }
+
+    fn authenticate<T>(
+        &self,
+        parts: &Parts,
+        legacy_param: &'static str,
+        parse: impl Fn(&str) -> Option<T>,
+    ) -> Result<T, AuthError> {
+        let query = Query::<HashMap<String, String>>::try_from_uri(&parts.uri)
+            .map(|Query(query)| query)
+            .unwrap_or_default();
+
+        let Some(key) = &self.key else {
+            if !self.insecure_query_auth {
+                return Err(AuthError::NotConfigured);
+            }
+            let user = query
+                .get(legacy_param)
+                .ok_or(AuthError::MissingUser(legacy_param))?;
+            return parse(user).ok_or(AuthError::InvalidUser(legacy_param));
+        };
+
+        let header_token = parts
+            .headers
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+        let cookie = parts.headers.typed_get::<Cookie>();
+        let cookie_token = cookie.as_ref().and_then(|cookie| cookie.get(TOKEN_COOKIE));
+        let query_token = query.get(TOKEN_QUERY_PARAM).map(String::as_str);
+
+        let token = header_token
+            .or(cookie_token)
+            .or(query_token)
+            .ok_or(AuthError::MissingToken)?;
+
+        let mut validation = Validation::new(Algorithm::HS256);
+        validation.set_required_spec_claims(&["exp", "sub"]);
+        let claims = jsonwebtoken::decode::<Claims>(token, key, &validation)
+            .map_err(|err| AuthError::InvalidToken(err.to_string()))?
+            .claims;
+
+        parse(&claims.sub)
+            .ok_or_else(|| AuthError::InvalidToken(format!("invalid subject {:?}", claims.sub)))
+    }
+}
+
+/// Create a token for `subject` that expires after `ttl`
+pub fn issue_token(
+    secret: &str,
+    subject: &str,
+    ttl: Duration,
+) -> Result<String, jsonwebtoken::errors::Error> {
+    let claims = Claims {
+        sub: subject.to_owned(),
+        exp: jsonwebtoken::get_current_timestamp() + ttl.as_secs(),
+    };
+    jsonwebtoken::encode(
+        &Header::new(Algorithm::HS256),
+        &claims,
+        &EncodingKey::from_secret(secret.as_bytes()),
+    )
+}
+
+#[derive(Debug)]
+pub(crate) enum AuthError {
+    /// The router wasn't set up with an [Authenticator], or it has no way to authenticate users
+    NotConfigured,
+    MissingToken,
+    InvalidToken(String),
+    /// The legacy query parameter wasn't sent
+    MissingUser(&'static str),
+    /// The legacy query parameter couldn't be parsed
+    InvalidUser(&'static str),
+}
+
+impl IntoResponse for AuthError {
+    fn into_response(self) -> Response {
+        let (status, message) = match self {
+            AuthError::NotConfigured => {
+                tracing::error!("the router has no `Authenticator` that can authenticate users");
+                (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "unable to authenticate the request".to_owned(),
+                )
+            }
+            AuthError::MissingToken => (StatusCode::UNAUTHORIZED, "missing token".to_owned()),
+            AuthError::InvalidToken(reason) => {
+                (StatusCode::UNAUTHORIZED, format!("invalid token: {reason}"))
+            }
+            AuthError::MissingUser(param) => (
+                StatusCode::BAD_REQUEST,
+                format!("missing `{param}` query parameter"),
+            ),
+            AuthError::InvalidUser(param) => (
+                StatusCode::BAD_REQUEST,
+                format!("invalid `{param}` query parameter"),
+            ),
+        };
+        (status, message).into_response()
+    }
+}
+
+fn authenticator(parts: &Parts) -> Result<&Authenticator, AuthError> {
+    parts
+        .extensions
+        .get::<Authenticator>()
+        .ok_or(AuthError::NotConfigured)
+}
+
+/// The user making a v1-v3 request
+#[derive(Debug)]
+pub(crate) struct Username(pub(crate) String);
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for Username {
+    type Rejection = AuthError;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        authenticator(parts)?
+            .authenticate(parts, "username", |user| Some(user.to_owned()))
+            .map(Username)
+    }
+}
+
+/// The user making a v4 request
+#[derive(Debug)]
+pub(crate) struct UserId(pub(crate) Uuid);
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for UserId {
+    type Rejection = AuthError;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        authenticator(parts)?
+            .authenticate(parts, "user_id", |user| user.parse().ok())
+            .map(UserId)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use axum::http::Request;
+
+    fn parts(uri: &str, headers: &[(&str, &str)]) -> Parts {
+        let mut request = Request::builder().uri(uri);
+        for (name, value) in headers {
+            request = request.header(*name, *value);
+        }
+        request.body(()).unwrap().into_parts().0
+    }
+
+    fn jwt_config(secret: &str) -> AuthConfig {
+        AuthConfig {
+            jwt_secret: Some(secret.to_owned()),
+            insecure_query_auth: false,
+        }
+    }
+
+    fn user_id(authenticator: &Authenticator, parts: &Parts) -> Result<Uuid, AuthError> {
+        authenticator.authenticate(parts, "user_id", |user| user.parse().ok())
+    }
+
+    #[test]
+    fn test_token_sources() {
+        let authenticator = Authenticator::new(&jwt_config("secret"));
+        let user = Uuid::new_v4();
+        let token = issue_token("secret", &user.to_string(), Duration::from_secs(60)).unwrap();
+
+        let bearer = format!("Bearer {token}");
+        let cookie = format!("theme=dark; sse_token={token}");
+        let query = format!("/sse?access_token={token}");
+
+        for parts in [
+            parts("/sse", &[("authorization", &bearer)]),
+            parts("/sse", &[("cookie", &cookie)]),
+            parts(&query, &[]),
+        ] {
+            assert_eq!(user_id(&authenticator, &parts).unwrap(), user);
+        }
+
+        // The query string can't be used to pick another user
+        let spoofed = parts(&format!("{query}&user_id={}", Uuid::new_v4()), &[]);
+        assert_eq!(user_id(&authenticator, &spoofed).unwrap(), user);
+    }
+
+    #[test]
+    fn test_rejected_tokens() {
+        let authenticator = Authenticator::new(&jwt_config("secret"));
+        let legacy = parts(&format!("/sse?user_id={}", Uuid::new_v4()), &[]);
+        assert!(matches!(
+            user_id(&authenticator, &legacy),
+            Err(AuthError::MissingToken)
+        ));
+
+        let forged = issue_token(
+            "other",
+            &Uuid::new_v4().to_string(),
+            Duration::from_secs(60),
+        );
+        let forged = parts(&format!("/sse?access_token={}", forged.unwrap()), &[]);
+        assert!(matches!(
+            user_id(&authenticator, &forged),
+            Err(AuthError::InvalidToken(_))
+        ));
+
+        let username = issue_token("secret", "alice", Duration::from_secs(60)).unwrap();
+        let username = parts(&format!("/sse?access_token={username}"), &[]);
+        assert!(matches!(
+            user_id(&authenticator, &username),
+            Err(AuthError::InvalidToken(_))
+        ));
+    }
+
+    #[test]
+    fn test_legacy_query_string() {
+        let authenticator = Authenticator::new(&AuthConfig {
+            jwt_secret: None,
+            insecure_query_auth: true,
+        });
+        let user = Uuid::new_v4();
+        let legacy = parts(&format!("/sse?user_id={user}"), &[]);
+        assert_eq!(user_id(&authenticator, &legacy).unwrap(), user);
+
+        let missing = parts("/sse", &[]);
+        assert!(matches!(
+            user_id(&authenticator, &missing),
+            Err(AuthError::MissingUser("user_id"))
+        ));
+
+        // The query string is only trusted when it's explicitly allowed
+        let authenticator = Authenticator::new(&AuthConfig::default());
+        assert!(matches!(
+            user_id(&authenticator, &legacy),
+            Err(AuthError::NotConfigured)
+        ));
+    }
+}
@@ -0,0 +1,419 @@
+//! Command line tool for driving the v4 report status app.
+//!
+//! This replaces the old `update_report_status.sh` script. Statuses are validated locally against
+//! the server's state machine before any request is sent.
+use std::process::ExitCode;
+use std::time::Duration;
+
+use clap::{Parser, Subcommand, ValueEnum};
+use futures::StreamExt as _;
+use reqwest::{Client, Response};
+use uuid::Uuid;
+
+use server_sent_events::sse_parser::{SseEvent, SseParser};
+use server_sent_events::{
+    ensure_report_table, get_dynamo_db_client, issue_token, NewReport, Report, ReportList,
+    ReportStatus, ReportStatusUpdate, SchemaError, StateMachine,
+};
+
+#[derive(Debug, Parser)]
+#[command(about = "Create, list and update v4 reports, or tail a user's event stream")]
+struct Cli {
+    /// Base URL of the v4 app
+    #[arg(
+        long,
+        env = "REPORTCTL_BASE_URL",
+        default_value = "http://localhost:3000/v4"
+    )]
+    base_url: String,
+    /// How results are printed
+    #[arg(long, value_enum, default_value_t = Output::Pretty)]
+    output: Output,
+    /// Token sent with every request. Required when the server has `SSE_JWT_SECRET` set
+    #[arg(long, env = "REPORTCTL_TOKEN", hide_env_values = true)]
+    token: Option<String>,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum Output {
+    /// Human readable output
+    Pretty,
+    /// One JSON object per line
+    Json,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Create a new report
+    Create {
+        #[arg(long)]
+        user_id: Uuid,
+        /// Name of the new report
+        #[arg(long)]
+        name: String,
+        /// Report parameters given as `key=value`. Values that parse as JSON are sent as JSON
+        #[arg(long = "param", value_parser = parse_parameter)]
+        parameters: Vec<(String, serde_json::Value)>,
+    },
+    /// List all of a user's reports
+    List {
+        #[arg(long)]
+        user_id: Uuid,
+        /// Only list reports with one of these statuses
+        #[arg(long, value_parser = parse_status, value_delimiter = ',')]
+        status: Vec<ReportStatus>,
+        /// Also list archived reports
+        #[arg(long)]
+        include_archived: bool,
+    },
+    /// Change the status of a report
+    SetStatus {
+        #[arg(long)]
+        user_id: Uuid,
+        #[arg(long)]
+        report_id: Uuid,
+        #[arg(long, value_parser = parse_status)]
+        status: ReportStatus,
+    },
+    /// Print events from a user's server sent event stream as they arrive
+    Tail {
+        #[arg(long)]
+        user_id: Uuid,
+    },
+    /// Create the DynamoDB table used by the v4 app if it doesn't already exist
+    InitDb,
+    /// Print a token for a user, signed with the server's secret
+    Token {
+        /// The v4 user id, or the username for v1-v3
+        #[arg(long)]
+        subject: String,
+        #[arg(long, env = "SSE_JWT_SECRET", hide_env_values = true)]
+        secret: String,
+        /// How long the token is valid for
+        #[arg(long, default_value_t = 3600)]
+        ttl_secs: u64,
+    },
+}
+
+#[derive(Debug)]
+enum CliError {
+    /// The request never got a response
+    Request(reqwest::Error),
+    /// The server responded with a non 2xx status code
+    Response {
+        status: reqwest::StatusCode,
+        body: String,
+    },
+    /// The requested status change was rejected before sending it to the server
+    Validation(String),
+    /// The DynamoDB table could not be created or has the wrong schema
+    Schema(SchemaError),
+    /// The token could not be created
+    Token(jsonwebtoken::errors::Error),
+}
+
+impl std::fmt::Display for CliError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CliError::Request(err) => write!(f, "request failed: {err}"),
+            CliError::Response { status, body } if body.is_empty() => {
+                write!(f, "server responded with {status}")
+            }
+            CliError::Response { status, body } => {
+                write!(f, "server responded with {status}: {body}")
+            }
+            CliError::Validation(message) => f.write_str(message),
+            CliError::Schema(err) => write!(f, "{err}"),
+            CliError::Token(err) => write!(f, "unable to create the token: {err}"),
+        }
+    }
+}
+
+impl From<reqwest::Error> for CliError {
+    fn from(value: reqwest::Error) -> Self {
+        CliError::Request(value)
+    }
+}
+
+fn parse_status(s: &str) -> Result<ReportStatus, String> {
+    s.parse().map_err(|err| format!("{err}"))
+}
+
+fn parse_parameter(s: &str) -> Result<(String, serde_json::Value), String> {
+    let Some((key, value)) = s.split_once('=') else {
+        return Err(format!("expected `key=value`, found {s:?}"));
+    };
+    let value =
+        serde_json::from_str(value).unwrap_or_else(|_| serde_json::Value::String(value.to_owned()));
+    Ok((key.to_owned(), value))
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let cli = Cli::parse();
+    let client = match build_client(cli.token.as_deref()) {
+        Ok(client) => client,
+        Err(err) => {
+            eprintln!("error: {err}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let result = match cli.command {
+        Command::Create {
+            user_id,
+            name,
+            parameters,
+        } => {
+            let new_report = NewReport::new(name, parameters.into_iter().collect());
+            create(&client, &cli.base_url, cli.output, user_id, new_report).await
+        }
+        Command::List {
+            user_id,
+            status,
+            include_archived,
+        } => {
+            let filter = ReportFilter {
+                statuses: status,
+                include_archived,
+            };
+            list(&client, &cli.base_url, cli.output, user_id, &filter).await
+        }
+        Command::SetStatus {
+            user_id,
+            report_id,
+            status,
+        } => set_status(&client, &cli.base_url, user_id, report_id, status).await,
+        Command::Tail { user_id } => tail(&client, &cli.base_url, cli.output, user_id).await,
+        Command::InitDb => init_db().await,
+        Command::Token {
+            subject,
+            secret,
+            ttl_secs,
+        } => token(&subject, &secret, Duration::from_secs(ttl_secs)),
+    };
+
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("error: {err}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Build a client that sends the token with every request
+fn build_client(token: Option<&str>) -> Result<Client, CliError> {
+    let mut headers = reqwest::header::HeaderMap::new();
+    if let Some(token) = token {
+        let value = format!("Bearer {token}")
+            .parse()
+            .map_err(|_| CliError::Validation("the token isn't a valid header value".to_owned()))?;
+        headers.insert(reqwest::header::AUTHORIZATION, value);
+    }
+    Ok(Client::builder().default_headers(headers).build()?)
+}
+
+/// Turn non 2xx responses into a [CliError::Response]
+async fn check_status(response: Response) -> Result<Response, CliError> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+    let body = response.text().await.unwrap_or_default();
+    Err(CliError::Response { status, body })
+}
+
+async fn create(
+    client: &Client,
+    base_url: &str,
+    output: Output,
+    user_id: Uuid,
+    new_report: NewReport,
+) -> Result<(), CliError> {
+    let response = client
+        .post(format!("{base_url}/new/report"))
+        .query(&[("user_id", user_id)])
+        .json(&new_report)
+        .send()
+        .await?;
+    let report: Report = check_status(response).await?.json().await?;
+    print_report(output, &report);
+    Ok(())
+}
+
+#[derive(Debug, Default)]
+struct ReportFilter {
+    statuses: Vec<ReportStatus>,
+    include_archived: bool,
+}
+
+/// Fetch every page of the user's reports
+async fn fetch_reports(
+    client: &Client,
+    base_url: &str,
+    user_id: Uuid,
+    filter: &ReportFilter,
+) -> Result<Vec<Report>, CliError> {
+    let mut query = vec![
+        ("user_id", user_id.to_string()),
+        ("include_archived", filter.include_archived.to_string()),
+    ];
+    if !filter.statuses.is_empty() {
+        let statuses = filter.statuses.iter().map(|s| s.to_string());
+        query.push(("status", statuses.collect::<Vec<_>>().join(",")));
+    }
+
+    let mut reports = vec![];
+    let mut cursor = None;
+    loop {
+        let mut request = client.get(format!("{base_url}/reports")).query(&query);
+        if let Some(cursor) = &cursor {
+            request = request.query(&[("cursor", cursor)]);
+        }
+
+        let page: ReportList = check_status(request.send().await?).await?.json().await?;
+        cursor = page.next_cursor().map(str::to_owned);
+        reports.extend(page.into_reports());
+
+        if cursor.is_none() {
+            return Ok(reports);
+        }
+    }
+}
+
+async fn list(
+    client: &Client,
+    base_url: &str,
+    output: Output,
+    user_id: Uuid,
+    filter: &ReportFilter,
+) -> Result<(), CliError> {
+    for report in fetch_reports(client, base_url, user_id, filter).await? {
+        print_report(output, &report);
+    }
+    Ok(())
+}
+
+async fn set_status(
+    client: &Client,
+    base_url: &str,
+    user_id: Uuid,
+    report_id: Uuid,
+    status: ReportStatus,
+) -> Result<(), CliError> {
+    let response = client
+        .get(format!("{base_url}/report/{report_id}"))
+        .query(&[("user_id", user_id)])
+        .send()
+        .await?;
+    let report: Report = match response.status() {
+        reqwest::StatusCode::NOT_FOUND | reqwest::StatusCode::FORBIDDEN => {
+            return Err(CliError::Validation(format!(
+                "report {report_id} does not belong to user {user_id}"
+            )));
+        }
+        _ => check_status(response).await?.json().await?,
+    };
+
+    let response = client
+        .get(format!("{base_url}/state-machine"))
+        .send()
+        .await?;
+    let state_machine: StateMachine = check_status(response).await?.json().await?;
+
+    state_machine
+        .transition(report.report_status(), status)
+        .map_err(|err| CliError::Validation(format!("report {report_id}: {err}")))?;
+
+    let response = client
+        .put(format!("{base_url}/report"))
+        .query(&[("user_id", user_id)])
+        .json(&ReportStatusUpdate::new(report_id, status))
+        .send()
+        .await?;
+    check_status(response).await?;
+    eprintln!(
+        "requested {report_id} transition from {} to {status}",
+        report.report_status()
+    );
+    Ok(())
+}
+
+async fn tail(
+    client: &Client,
+    base_url: &str,
+    output: Output,
+    user_id: Uuid,
+) -> Result<(), CliError> {
+    let response = client
+        .get(format!("{base_url}/sse"))
+        .query(&[("user_id", user_id)])
+        .header(reqwest::header::ACCEPT, "text/event-stream")
+        .send()
+        .await?;
+    let mut body = check_status(response).await?.bytes_stream();
+
+    let mut parser = SseParser::default();
+    while let Some(chunk) = body.next().await {
+        for event in parser.feed(&chunk?) {
+            print_event(output, &event);
+        }
+    }
+    Ok(())
+}
+
+async fn init_db() -> Result<(), CliError> {
+    let client = get_dynamo_db_client().await;
+    ensure_report_table(&client)
+        .await
+        .map_err(CliError::Schema)?;
+    eprintln!("DynamoDB table is ready");
+    Ok(())
+}
+
+fn token(subject: &str, secret: &str, ttl: Duration) -> Result<(), CliError> {
+    let token = issue_token(secret, subject, ttl).map_err(CliError::Token)?;
+    println!("{token}");
+    Ok(())
+}
+
+fn print_report(output: Output, report: &Report) {
+    match output {
+        Output::Pretty => {
+            let progress = report
+                .progress()
+                .map(|progress| format!(" {progress:>3}%"))
+                .unwrap_or_default();
+            let reason = report
+                .status_reason()
+                .map(|reason| format!(" [{reason}]"))
+                .unwrap_or_default();
+            println!(
+                "{}  {:<10}{progress}  {:?}  (updated {}){reason}",
+                report.report_id(),
+                report.report_status(),
+                report.name(),
+                report.updated_at().to_rfc3339(),
+            )
+        }
+        Output::Json => println!(
+            "{}",
+            serde_json::to_string(report).expect("reports serialize to json")
+        ),
+    }
+}
+
+fn print_event(output: Output, event: &SseEvent) {
+    let name = event.event.as_deref().unwrap_or("message");
+    match output {
+        Output::Pretty => println!("[{name}] {}", event.data),
+        Output::Json => {
+            // Keep structured payloads structured, and fall back to a plain string otherwise
+            let line = serde_json::json!({ "event": name, "id": event.id, "data": event.json() });
+            println!("{line}");
+        }
+    }
+}
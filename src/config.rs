@@ -0,0 +1,653 @@
+//! App configuration.
+//!
+//! Everything can be configured with environment variables, which makes it easy to change how
+//! the demo app behaves without recompiling it. Unset or invalid values fall back to the defaults.
+//! The exceptions are the report state machine, which has to be valid for the app to start, and
+//! authentication, which has to be set up explicitly.
+use std::fmt::Debug;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::v4::{ReportStatus, StateMachine, StateMachineError};
+
+#[derive(Debug, Clone, Default)]
+pub struct Config {
+    pub auth: AuthConfig,
+    pub v1: V1Config,
+    pub v2: V2Config,
+    pub v4: V4Config,
+}
+
+impl Config {
+    pub fn from_env() -> Result<Self, ConfigError> {
+        Ok(Self {
+            auth: AuthConfig::from_env()?,
+            v1: V1Config::from_env(),
+            v2: V2Config::from_env(),
+            v4: V4Config::from_env()?,
+        })
+    }
+}
+
+/// How users are authenticated across every version of the app
+#[derive(Debug, Clone, Default)]
+pub struct AuthConfig {
+    /// Secret used to validate HS256 JWTs. Read from `SSE_JWT_SECRET`
+    pub jwt_secret: Option<String>,
+    /// Identify users by the query string without any authentication when there's no
+    /// `jwt_secret`. Only meant for local development. Read from `SSE_INSECURE_QUERY_AUTH`
+    pub insecure_query_auth: bool,
+}
+
+impl AuthConfig {
+    /// Fails when there's neither a secret nor the insecure opt-in, so the app can't end up
+    /// trusting the query string by accident
+    pub fn from_env() -> Result<Self, ConfigError> {
+        let config = Self {
+            jwt_secret: env_var("SSE_JWT_SECRET"),
+            insecure_query_auth: env_var("SSE_INSECURE_QUERY_AUTH").unwrap_or_default(),
+        };
+        if config.jwt_secret.is_none() && !config.insecure_query_auth {
+            return Err(ConfigError::MissingJwtSecret);
+        }
+        Ok(config)
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    /// The state machine file couldn't be read or isn't a valid state machine
+    StateMachine { path: String, reason: String },
+    /// Neither `SSE_JWT_SECRET` nor `SSE_INSECURE_QUERY_AUTH` is set
+    MissingJwtSecret,
+}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigError::StateMachine { path, reason } => {
+                write!(f, "invalid state machine in {path}: {reason}")
+            }
+            ConfigError::MissingJwtSecret => write!(
+                f,
+                "SSE_JWT_SECRET isn't set. Set SSE_INSECURE_QUERY_AUTH=true to identify users by \
+                 the query string without any authentication"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// Settings for the v1 demo streams
+#[derive(Debug, Clone)]
+pub struct V1Config {
+    /// Where `?stream=fixture` looks for recorded `.jsonl` files. Read from `SSE_FIXTURES_DIR`
+    pub fixtures_dir: PathBuf,
+}
+
+impl Default for V1Config {
+    fn default() -> Self {
+        Self {
+            fixtures_dir: PathBuf::from("fixtures"),
+        }
+    }
+}
+
+impl V1Config {
+    pub fn from_env() -> Self {
+        let default = Self::default();
+        Self {
+            fixtures_dir: env_var("SSE_FIXTURES_DIR").unwrap_or(default.fixtures_dir),
+        }
+    }
+}
+
+/// Settings for the v2 and v3 messaging apps
+#[derive(Debug, Clone, Default)]
+pub struct V2Config {
+    pub mailbox: MailboxConfig,
+}
+
+impl V2Config {
+    pub fn from_env() -> Self {
+        Self {
+            mailbox: MailboxConfig::from_env(),
+        }
+    }
+}
+
+/// Settings for buffering messages sent to users who aren't connected
+#[derive(Debug, Clone)]
+pub struct MailboxConfig {
+    /// How many messages are kept for each user. The oldest message is dropped to make room.
+    /// Read from `SSE_MAILBOX_MAX_MESSAGES`. `0` turns the mailbox off
+    pub max_messages: usize,
+    /// How long a message is kept before it's dropped. Read from `SSE_MAILBOX_TTL_SECS`
+    pub ttl: Duration,
+}
+
+impl Default for MailboxConfig {
+    fn default() -> Self {
+        Self {
+            max_messages: 100,
+            ttl: Duration::from_secs(60 * 60),
+        }
+    }
+}
+
+impl MailboxConfig {
+    pub fn from_env() -> Self {
+        let default = Self::default();
+        Self {
+            max_messages: env_var("SSE_MAILBOX_MAX_MESSAGES").unwrap_or(default.max_messages),
+            ttl: env_var("SSE_MAILBOX_TTL_SECS")
+                .filter(|secs| *secs > 0)
+                .map(Duration::from_secs)
+                .unwrap_or(default.ttl),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct V4Config {
+    /// Token required to use the `/v4/admin` endpoints. They're disabled when this isn't set.
+    /// Read from `SSE_ADMIN_TOKEN`
+    pub admin_token: Option<String>,
+    pub watchdog: WatchdogConfig,
+    pub workers: WorkerConfig,
+    /// Drives new reports through their statuses without a real worker.
+    /// Only enabled when `SSE_SIMULATOR=true`
+    pub simulator: Option<SimulatorConfig>,
+    /// Valid report status transitions.
+    /// Loaded from the JSON file at `SSE_STATE_MACHINE`, or the default lifecycle when it's unset
+    pub state_machine: StateMachine,
+    pub webhooks: WebhookConfig,
+    pub event_log: EventLogConfig,
+    pub limits: LimitsConfig,
+    /// Where `/v4/sse?record=true` connections are recorded. Recording is disabled when this
+    /// isn't set. Read from `SSE_RECORDINGS_DIR`
+    pub recordings_dir: Option<PathBuf>,
+    /// How status updates get from the API to the event loop. Read from `SSE_MESSAGE_BUS`
+    pub message_bus: MessageBus,
+    /// Run the watchdog and the task that requeues reports with expired leases.
+    /// Read from `SSE_BACKGROUND_TASKS`
+    pub background_tasks: bool,
+}
+
+impl Default for V4Config {
+    fn default() -> Self {
+        Self {
+            admin_token: None,
+            watchdog: WatchdogConfig::default(),
+            workers: WorkerConfig::default(),
+            simulator: None,
+            state_machine: StateMachine::default(),
+            webhooks: WebhookConfig::default(),
+            event_log: EventLogConfig::default(),
+            limits: LimitsConfig::default(),
+            recordings_dir: None,
+            message_bus: MessageBus::default(),
+            background_tasks: true,
+        }
+    }
+}
+
+/// Carries status updates from the API to the event loop
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum MessageBus {
+    /// Publish to the `v4_messages` Kafka topic, so other services see the updates too
+    #[default]
+    Kafka,
+    /// Hand updates straight to the event loop, so the app runs without Kafka
+    InProcess,
+}
+
+impl FromStr for MessageBus {
+    type Err = String;
+
+    fn from_str(bus: &str) -> Result<Self, Self::Err> {
+        match bus {
+            "kafka" => Ok(MessageBus::Kafka),
+            "in-process" => Ok(MessageBus::InProcess),
+            _ => Err(format!("expected `kafka` or `in-process`, found {bus:?}")),
+        }
+    }
+}
+
+impl V4Config {
+    pub fn from_env() -> Result<Self, ConfigError> {
+        let simulator = env_var("SSE_SIMULATOR")
+            .unwrap_or(false)
+            .then(SimulatorConfig::from_env);
+        let state_machine = match std::env::var("SSE_STATE_MACHINE") {
+            Ok(path) => load_state_machine(&path, simulator.is_some())?,
+            Err(_) => StateMachine::default(),
+        };
+
+        Ok(Self {
+            admin_token: env_var("SSE_ADMIN_TOKEN"),
+            watchdog: WatchdogConfig::from_env().for_state_machine(&state_machine),
+            workers: WorkerConfig::from_env(),
+            simulator,
+            state_machine,
+            webhooks: WebhookConfig::from_env(),
+            event_log: EventLogConfig::from_env(),
+            limits: LimitsConfig::from_env(),
+            recordings_dir: env_var("SSE_RECORDINGS_DIR"),
+            message_bus: env_var("SSE_MESSAGE_BUS").unwrap_or_default(),
+            background_tasks: env_var("SSE_BACKGROUND_TASKS").unwrap_or(true),
+        })
+    }
+}
+
+/// Settings for delivering report transitions to webhooks
+#[derive(Debug, Clone)]
+pub struct WebhookConfig {
+    /// How many times a delivery is attempted before giving up.
+    /// Read from `SSE_WEBHOOK_MAX_ATTEMPTS`
+    pub max_attempts: u32,
+    /// How long to wait before the first retry. The wait doubles after every failed attempt.
+    /// Read from `SSE_WEBHOOK_INITIAL_BACKOFF_MS`
+    pub initial_backoff: Duration,
+    /// Upper bound on the wait between attempts. Read from `SSE_WEBHOOK_MAX_BACKOFF_SECS`
+    pub max_backoff: Duration,
+    /// How long to wait for the webhook to respond. Read from `SSE_WEBHOOK_TIMEOUT_SECS`
+    pub timeout: Duration,
+    /// How many webhooks each user can register. Read from `SSE_WEBHOOK_MAX_PER_USER`
+    pub max_per_user: usize,
+    /// Allow webhooks on loopback, private and link-local addresses, e.g. for local development.
+    /// Read from `SSE_WEBHOOK_ALLOW_PRIVATE_TARGETS`
+    pub allow_private_targets: bool,
+}
+
+impl Default for WebhookConfig {
+    fn default() -> Self {
+        Self {
+            max_attempts: 5,
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(60),
+            timeout: Duration::from_secs(10),
+            max_per_user: 10,
+            allow_private_targets: false,
+        }
+    }
+}
+
+impl WebhookConfig {
+    pub fn from_env() -> Self {
+        let default = Self::default();
+        Self {
+            max_attempts: env_var("SSE_WEBHOOK_MAX_ATTEMPTS")
+                .filter(|attempts| *attempts > 0)
+                .unwrap_or(default.max_attempts),
+            initial_backoff: env_var("SSE_WEBHOOK_INITIAL_BACKOFF_MS")
+                .map(Duration::from_millis)
+                .unwrap_or(default.initial_backoff),
+            max_backoff: env_var("SSE_WEBHOOK_MAX_BACKOFF_SECS")
+                .map(Duration::from_secs)
+                .unwrap_or(default.max_backoff),
+            timeout: env_var("SSE_WEBHOOK_TIMEOUT_SECS")
+                .filter(|secs| *secs > 0)
+                .map(Duration::from_secs)
+                .unwrap_or(default.timeout),
+            max_per_user: env_var("SSE_WEBHOOK_MAX_PER_USER")
+                .filter(|max| *max > 0)
+                .unwrap_or(default.max_per_user),
+            allow_private_targets: env_var("SSE_WEBHOOK_ALLOW_PRIVATE_TARGETS")
+                .unwrap_or(default.allow_private_targets),
+        }
+    }
+}
+
+/// Settings for the per-user log of recent events, which lets clients catch up on what they
+/// missed while they weren't connected
+#[derive(Debug, Clone)]
+pub struct EventLogConfig {
+    /// How many recent events are kept for each user. Read from `SSE_EVENT_LOG_SIZE`
+    pub max_events: usize,
+    /// How long `/v4/poll` waits for a new event before responding with nothing.
+    /// Read from `SSE_LONG_POLL_TIMEOUT_SECS`
+    pub long_poll_timeout: Duration,
+}
+
+impl Default for EventLogConfig {
+    fn default() -> Self {
+        Self {
+            max_events: 100,
+            long_poll_timeout: Duration::from_secs(30),
+        }
+    }
+}
+
+impl EventLogConfig {
+    pub fn from_env() -> Self {
+        let default = Self::default();
+        Self {
+            max_events: env_var("SSE_EVENT_LOG_SIZE")
+                .filter(|size| *size > 0)
+                .unwrap_or(default.max_events),
+            long_poll_timeout: env_var("SSE_LONG_POLL_TIMEOUT_SECS")
+                .filter(|secs| *secs > 0)
+                .map(Duration::from_secs)
+                .unwrap_or(default.long_poll_timeout),
+        }
+    }
+}
+
+/// Limits that stop a single client from overwhelming the app. A limit of `0` turns it off
+#[derive(Debug, Clone)]
+pub struct LimitsConfig {
+    /// Open SSE and WebSocket connections per user. Read from `SSE_MAX_CONNECTIONS_PER_USER`
+    pub max_connections_per_user: Option<usize>,
+    /// Open SSE and WebSocket connections across all users. Read from `SSE_MAX_CONNECTIONS`
+    pub max_connections: Option<usize>,
+    /// Requests per minute each user can make to the endpoints that change reports.
+    /// Read from `SSE_RATE_LIMIT_PER_MINUTE`
+    pub requests_per_minute: Option<u32>,
+    /// How many requests a user can make at once before they're limited to the steady rate.
+    /// Read from `SSE_RATE_LIMIT_BURST`
+    pub burst: u32,
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        Self {
+            max_connections_per_user: Some(10),
+            max_connections: Some(10_000),
+            requests_per_minute: Some(60),
+            burst: 20,
+        }
+    }
+}
+
+impl LimitsConfig {
+    pub fn from_env() -> Self {
+        let default = Self::default();
+        Self {
+            max_connections_per_user: env_var("SSE_MAX_CONNECTIONS_PER_USER")
+                .map_or(default.max_connections_per_user, non_zero),
+            max_connections: env_var("SSE_MAX_CONNECTIONS")
+                .map_or(default.max_connections, non_zero),
+            requests_per_minute: env_var("SSE_RATE_LIMIT_PER_MINUTE")
+                .map_or(default.requests_per_minute, non_zero),
+            burst: env_var("SSE_RATE_LIMIT_BURST")
+                .filter(|burst| *burst > 0)
+                .unwrap_or(default.burst),
+        }
+    }
+}
+
+/// `0` turns a limit off
+fn non_zero<T: Default + PartialEq>(limit: T) -> Option<T> {
+    (limit != T::default()).then_some(limit)
+}
+
+/// Transitions the workers make when they claim and finish reports. Lease expiry moves reports
+/// back to `queued` outside of the state machine
+const WORKER_TRANSITIONS: [(ReportStatus, ReportStatus); 3] = [
+    (ReportStatus::Queued, ReportStatus::Processing),
+    (ReportStatus::Processing, ReportStatus::Completed),
+    (ReportStatus::Processing, ReportStatus::Failed),
+];
+
+/// Transitions the simulator makes on its way to `completed` or `failed`
+const SIMULATOR_TRANSITIONS: [(ReportStatus, ReportStatus); 4] = [
+    (ReportStatus::Pending, ReportStatus::Queued),
+    (ReportStatus::Queued, ReportStatus::Processing),
+    (ReportStatus::Processing, ReportStatus::Completed),
+    (ReportStatus::Processing, ReportStatus::Failed),
+];
+
+/// Check that the state machine allows the transitions the app makes on its own.
+/// A graph without them would leave workers or the simulator unable to move reports along
+fn check_required_transitions(
+    state_machine: &StateMachine,
+    simulator: bool,
+) -> Result<(), StateMachineError> {
+    state_machine.require("workers", &WORKER_TRANSITIONS)?;
+    if simulator {
+        state_machine.require("simulator", &SIMULATOR_TRANSITIONS)?;
+    }
+    Ok(())
+}
+
+fn load_state_machine(path: &str, simulator: bool) -> Result<StateMachine, ConfigError> {
+    let error = |reason: String| ConfigError::StateMachine {
+        path: path.to_owned(),
+        reason,
+    };
+    let contents = std::fs::read_to_string(path).map_err(|err| error(err.to_string()))?;
+    let state_machine = serde_json::from_str(&contents).map_err(|err| error(err.to_string()))?;
+    check_required_transitions(&state_machine, simulator).map_err(|err| error(err.to_string()))?;
+    tracing::info!("loaded the report state machine from {path}");
+    Ok(state_machine)
+}
+
+/// Settings for the task that fails reports which are stuck in the same status
+#[derive(Debug, Clone)]
+pub struct WatchdogConfig {
+    /// How often to look for stuck reports. Read from `SSE_WATCHDOG_INTERVAL_SECS`
+    pub interval: Duration,
+    /// How long a report can stay in a status before it's failed.
+    /// Read from `SSE_WATCHDOG_QUEUED_TIMEOUT_SECS` and `SSE_WATCHDOG_PROCESSING_TIMEOUT_SECS`.
+    /// A timeout of `0` turns the watchdog off for that status.
+    pub timeouts: Vec<(ReportStatus, Duration)>,
+}
+
+impl Default for WatchdogConfig {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(60),
+            timeouts: vec![
+                (ReportStatus::Queued, Duration::from_secs(60 * 60)),
+                (ReportStatus::Processing, Duration::from_secs(60 * 60)),
+            ],
+        }
+    }
+}
+
+impl WatchdogConfig {
+    pub fn from_env() -> Self {
+        let default = Self::default();
+        let timeout = |status: ReportStatus, name: &str| {
+            let default = default
+                .timeouts
+                .iter()
+                .find(|(s, _)| *s == status)
+                .map(|(_, timeout)| *timeout);
+            env_var(name)
+                .map(Duration::from_secs)
+                .or(default)
+                .filter(|timeout| !timeout.is_zero())
+                .map(|timeout| (status, timeout))
+        };
+
+        Self {
+            interval: env_var("SSE_WATCHDOG_INTERVAL_SECS")
+                .filter(|secs| *secs > 0)
+                .map(Duration::from_secs)
+                .unwrap_or(default.interval),
+            timeouts: [
+                timeout(ReportStatus::Queued, "SSE_WATCHDOG_QUEUED_TIMEOUT_SECS"),
+                timeout(
+                    ReportStatus::Processing,
+                    "SSE_WATCHDOG_PROCESSING_TIMEOUT_SECS",
+                ),
+            ]
+            .into_iter()
+            .flatten()
+            .collect(),
+        }
+    }
+
+    /// Stop watching statuses the state machine can't fail. The watchdog's updates are validated
+    /// like any other, so it would find the same reports stuck forever
+    pub fn for_state_machine(mut self, state_machine: &StateMachine) -> Self {
+        self.timeouts.retain(|(status, _)| {
+            let can_fail = state_machine
+                .transition(*status, ReportStatus::Failed)
+                .is_ok();
+            if !can_fail {
+                tracing::warn!(
+                    "not watching for stuck {status} reports since the state machine doesn't let them fail"
+                );
+            }
+            can_fail
+        });
+        self
+    }
+}
+
+/// Settings for the workers that claim and process queued reports
+#[derive(Debug, Clone)]
+pub struct WorkerConfig {
+    /// Token workers send as `Authorization: Bearer <token>`. The admin token works too.
+    /// The worker endpoints are disabled when neither is set. Read from `SSE_WORKER_TOKEN`
+    pub token: Option<String>,
+    /// How long a claimed report belongs to a worker without a heartbeat.
+    /// Read from `SSE_WORKER_LEASE_SECS`
+    pub lease_duration: Duration,
+    /// How often to requeue reports with expired leases.
+    /// Read from `SSE_WORKER_LEASE_CHECK_INTERVAL_SECS`
+    pub lease_check_interval: Duration,
+}
+
+impl Default for WorkerConfig {
+    fn default() -> Self {
+        Self {
+            token: None,
+            lease_duration: Duration::from_secs(60),
+            lease_check_interval: Duration::from_secs(10),
+        }
+    }
+}
+
+impl WorkerConfig {
+    pub fn from_env() -> Self {
+        let default = Self::default();
+        let seconds = |name: &str| {
+            env_var(name)
+                .filter(|secs| *secs > 0)
+                .map(Duration::from_secs)
+        };
+
+        Self {
+            token: env_var("SSE_WORKER_TOKEN"),
+            lease_duration: seconds("SSE_WORKER_LEASE_SECS").unwrap_or(default.lease_duration),
+            lease_check_interval: seconds("SSE_WORKER_LEASE_CHECK_INTERVAL_SECS")
+                .unwrap_or(default.lease_check_interval),
+        }
+    }
+}
+
+/// Settings for the simulated report worker.
+///
+/// Each delay is randomized between half and one and a half times the configured value so that
+/// reports created together don't all change status at the same time.
+#[derive(Debug, Clone)]
+pub struct SimulatorConfig {
+    /// How long a report stays `pending`. Read from `SSE_SIMULATOR_QUEUE_DELAY_MS`
+    pub queue_delay: Duration,
+    /// How long a report stays `queued`. Read from `SSE_SIMULATOR_START_DELAY_MS`
+    pub start_delay: Duration,
+    /// How long a report stays `processing`. Read from `SSE_SIMULATOR_PROCESSING_DELAY_MS`
+    pub processing_delay: Duration,
+    /// Chance between `0.0` and `1.0` that a report fails instead of completing.
+    /// Read from `SSE_SIMULATOR_FAILURE_RATE`
+    pub failure_rate: f64,
+}
+
+impl Default for SimulatorConfig {
+    fn default() -> Self {
+        Self {
+            queue_delay: Duration::from_secs(2),
+            start_delay: Duration::from_secs(5),
+            processing_delay: Duration::from_secs(10),
+            failure_rate: 0.1,
+        }
+    }
+}
+
+impl SimulatorConfig {
+    pub fn from_env() -> Self {
+        let default = Self::default();
+        let millis = |name: &str| env_var(name).map(Duration::from_millis);
+
+        Self {
+            queue_delay: millis("SSE_SIMULATOR_QUEUE_DELAY_MS").unwrap_or(default.queue_delay),
+            start_delay: millis("SSE_SIMULATOR_START_DELAY_MS").unwrap_or(default.start_delay),
+            processing_delay: millis("SSE_SIMULATOR_PROCESSING_DELAY_MS")
+                .unwrap_or(default.processing_delay),
+            failure_rate: env_var("SSE_SIMULATOR_FAILURE_RATE")
+                .filter(|rate: &f64| !rate.is_nan())
+                .map(|rate| rate.clamp(0.0, 1.0))
+                .unwrap_or(default.failure_rate),
+        }
+    }
+}
+
+/// Read and parse an environment variable
+pub(crate) fn env_var<T>(name: &str) -> Option<T>
+where
+    T: FromStr,
+    T::Err: Debug,
+{
+    let value = std::env::var(name).ok()?;
+    match value.parse() {
+        Ok(value) => Some(value),
+        Err(err) => {
+            tracing::warn!("ignoring invalid value {value:?} for {name}. {err:?}");
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_required_transitions() {
+        assert!(check_required_transitions(&StateMachine::default(), true).is_ok());
+
+        // Nothing is ever queued, which is fine for workers but not for the simulator
+        let state_machine: StateMachine = serde_json::from_str(
+            r#"{"transitions": {
+                "pending": ["processing"],
+                "queued": ["processing"],
+                "processing": ["completed", "failed"]
+            }}"#,
+        )
+        .unwrap();
+        assert!(check_required_transitions(&state_machine, false).is_ok());
+        let err = check_required_transitions(&state_machine, true).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "the transition from pending to queued is needed by the simulator"
+        );
+    }
+
+    #[test]
+    fn test_watchdog_skips_statuses_that_cant_fail() {
+        let watchdog = WatchdogConfig::default().for_state_machine(&StateMachine::default());
+        assert_eq!(watchdog.timeouts.len(), 2);
+
+        let state_machine: StateMachine = serde_json::from_str(
+            r#"{"transitions": {
+                "pending": ["queued"],
+                "queued": ["processing"],
+                "processing": ["completed", "failed"]
+            }}"#,
+        )
+        .unwrap();
+        let watchdog = WatchdogConfig::default().for_state_machine(&state_machine);
+        let statuses: Vec<_> = watchdog
+            .timeouts
+            .iter()
+            .map(|(status, _)| *status)
+            .collect();
+        assert_eq!(statuses, [ReportStatus::Processing]);
+    }
+}
@@ -1,15 +1,28 @@
-use axum::Router;
-use serde::Deserialize;
+use axum::{Extension, Router};
 use tokio::sync::mpsc::{channel, Receiver, Sender};
 
+mod auth;
+mod config;
+mod recording;
+pub mod sse_parser;
 mod v1;
 mod v2;
 mod v3;
 mod v4;
 
-pub use v4::dynamodb::get_dynamo_db_client;
+pub use auth::issue_token;
+pub use config::{
+    AuthConfig, Config, ConfigError, EventLogConfig, LimitsConfig, MailboxConfig, MessageBus,
+    SimulatorConfig, V1Config, V2Config, V4Config, WatchdogConfig, WebhookConfig, WorkerConfig,
+};
+pub use v4::dynamodb::{ensure_report_table, get_dynamo_db_client, SchemaError};
+pub use v4::memory::{InMemoryDatabase, InMemoryError};
+pub use v4::{
+    BulkStatusUpdateResult, NewReport, Report, ReportList, ReportStatus, ReportStatusError,
+    ReportStatusUpdate, StateMachine, StateMachineError,
+};
 
-pub fn create_app<D>(database: D) -> Router
+pub fn create_app<D>(database: D, config: Config) -> Router
 where
     D: v4::database::Database + Clone + Sync + Send + 'static,
     <D as v4::database::Database>::Error: std::fmt::Debug,
@@ -19,15 +32,38 @@ where
     let (sender_v2, receiver_v2): (Sender<v2::Command>, Receiver<v2::Command>) = channel(100);
     let (sender_v3, receiver_v3): (Sender<v2::Command>, Receiver<v2::Command>) = channel(100);
     let (sender_v4, receiver_v4): (Sender<v4::AppEvent>, Receiver<v4::AppEvent>) = channel(100);
+    let authenticator = auth::Authenticator::new(&config.auth);
 
     Router::new()
-        .nest("/v1", v1::create_app_v1())
-        .nest("/v2", v2::create_app_v2(sender_v2, receiver_v2))
-        .nest("/v3", v3::create_app_v3(sender_v3, receiver_v3))
-        .nest("/v4", v4::create_app_v4(sender_v4, receiver_v4, database))
+        .nest("/v1", v1::create_app_v1(config.v1))
+        .nest(
+            "/v2",
+            v2::create_app_v2(sender_v2, receiver_v2, config.v2.clone()),
+        )
+        .nest("/v3", v3::create_app_v3(sender_v3, receiver_v3, config.v2))
+        .nest(
+            "/v4",
+            v4::create_app_v4(sender_v4, receiver_v4, database, config.v4),
+        )
+        .layer(Extension(authenticator))
 }
 
-#[derive(Debug, Deserialize)]
-pub(crate) struct QueryParams {
-    username: String,
+/// Only the v4 app, nested under `/v4` like in [create_app].
+///
+/// Unlike [create_app] this doesn't start the v1-v3 apps, so nothing connects to Kafka when
+/// `config.v4.message_bus` is [MessageBus::InProcess]
+pub fn create_app_v4<D>(database: D, config: Config) -> Router
+where
+    D: v4::database::Database + Clone + Sync + Send + 'static,
+    <D as v4::database::Database>::Error: std::fmt::Debug,
+{
+    let (sender, receiver): (Sender<v4::AppEvent>, Receiver<v4::AppEvent>) = channel(100);
+    let authenticator = auth::Authenticator::new(&config.auth);
+
+    Router::new()
+        .nest(
+            "/v4",
+            v4::create_app_v4(sender, receiver, database, config.v4),
+        )
+        .layer(Extension(authenticator))
 }
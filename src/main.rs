@@ -1,10 +1,10 @@
 //! Based on the Server-Sent-Event example in the axum crate:
 //! <https://github.com/tokio-rs/axum/blob/main/examples/sse/src/main.rs>
-use tower_http::trace::TraceLayer;
 use tower_http::cors::CorsLayer;
+use tower_http::trace::TraceLayer;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter};
 
-use server_sent_events::{create_app, get_dynamo_db_client};
+use server_sent_events::{create_app, ensure_report_table, get_dynamo_db_client, Config};
 
 #[tokio::main]
 async fn main() {
@@ -19,10 +19,24 @@ async fn main() {
         .with(tracing_subscriber::fmt::layer())
         .init();
 
+    let config = match Config::from_env() {
+        Ok(config) => config,
+        Err(err) => {
+            tracing::error!("{err}");
+            std::process::exit(1);
+        }
+    };
+
     let dynamodb_client = std::sync::Arc::new(get_dynamo_db_client().await);
 
+    // Create the DynamoDB table if it doesn't exist yet. The v1-v3 apps don't need DynamoDB
+    // so we keep running even if DynamoDB isn't available.
+    if let Err(err) = ensure_report_table(&dynamodb_client).await {
+        tracing::error!("The v4 app won't be able to store reports. {err}");
+    }
+
     // build our application and add a middleware layer to enable tracing (logging)
-    let app = create_app(dynamodb_client)
+    let app = create_app(dynamodb_client, config)
         .layer(TraceLayer::new_for_http())
         .layer(CorsLayer::permissive());
 
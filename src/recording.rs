@@ -0,0 +1,316 @@
+//! Recorded event streams.
+//!
+//! A recording is a JSON-lines file with one event per line, e.g.
+//!
+//! ```json
+//! {"offsetMs": 0, "event": "report_status_update", "id": "1", "data": {"id": "...", "status": "queued"}}
+//! {"offsetMs": 1500, "data": "hi!"}
+//! ```
+//!
+//! `offsetMs` is how long after the start of the recording the event was sent. `event`, `id` and
+//! `timestamp` are optional. String `data` is sent as is, anything else is sent as JSON.
+//!
+//! The same format is used for the v1 demo fixtures and for recorded v4 connections.
+use axum::response::sse::Event;
+use chrono::{DateTime, Utc};
+use futures::stream::{self, Stream};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::mpsc::{channel, Receiver, Sender};
+use tokio_stream::StreamExt as _;
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct RecordedEvent {
+    /// Milliseconds since the start of the recording
+    pub(crate) offset_ms: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) event: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) id: Option<String>,
+    /// When the event was recorded
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) timestamp: Option<DateTime<Utc>>,
+    pub(crate) data: serde_json::Value,
+}
+
+impl RecordedEvent {
+    /// The SSE `data:` field
+    pub(crate) fn data(&self) -> String {
+        match &self.data {
+            serde_json::Value::String(data) => data.clone(),
+            data => data.to_string(),
+        }
+    }
+
+    pub(crate) fn into_event(self) -> Event {
+        let mut event = Event::default().data(self.data());
+        if let Some(name) = self.event {
+            event = event.event(name);
+        }
+        if let Some(id) = self.id {
+            event = event.id(id);
+        }
+        event
+    }
+}
+
+#[derive(Debug)]
+pub(crate) enum RecordingError {
+    Io(std::io::Error),
+    /// A line couldn't be parsed. Lines are numbered from 1
+    InvalidEvent {
+        line: usize,
+        err: serde_json::Error,
+    },
+}
+
+impl std::fmt::Display for RecordingError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RecordingError::Io(err) => write!(f, "unable to read the recording: {err}"),
+            RecordingError::InvalidEvent { line, err } => {
+                write!(f, "invalid event on line {line}: {err}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for RecordingError {}
+
+/// Where the recording called `name` is kept in `dir`.
+///
+/// Names can only contain ASCII letters, numbers, `-` and `_`, so they can't point outside of `dir`
+pub(crate) fn recording_path(dir: &Path, name: &str) -> Option<PathBuf> {
+    let valid = !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    valid.then(|| dir.join(format!("{name}.jsonl")))
+}
+
+/// Read every event in the recording. Blank lines are skipped
+pub(crate) async fn read_recording(path: &Path) -> Result<Vec<RecordedEvent>, RecordingError> {
+    let contents = tokio::fs::read_to_string(path)
+        .await
+        .map_err(RecordingError::Io)?;
+    parse_recording(&contents)
+}
+
+fn parse_recording(contents: &str) -> Result<Vec<RecordedEvent>, RecordingError> {
+    contents
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(index, line)| {
+            serde_json::from_str(line).map_err(|err| RecordingError::InvalidEvent {
+                line: index + 1,
+                err,
+            })
+        })
+        .collect()
+}
+
+/// When replayed events are emitted
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Timing {
+    /// Keep the recorded timing, sped up or slowed down. `2.0` replays twice as fast
+    Recorded { speed: f64 },
+    /// Evenly space the events, starting right away
+    Interval(Duration),
+}
+
+pub(crate) fn replay(
+    events: Vec<RecordedEvent>,
+    timing: Timing,
+) -> impl Stream<Item = RecordedEvent> {
+    let start = tokio::time::Instant::now();
+    stream::iter(events.into_iter().enumerate()).then(move |(index, event)| async move {
+        let offset = match timing {
+            Timing::Recorded { speed } => Duration::from_millis(event.offset_ms).div_f64(speed),
+            Timing::Interval(interval) => interval * index as u32,
+        };
+        tokio::time::sleep_until(start + offset).await;
+        event
+    })
+}
+
+/// Writes the events sent on a connection to a recording.
+///
+/// The file is written on its own task, so recording never holds up the connection. Events are
+/// dropped if the writer falls behind.
+pub(crate) struct Recorder {
+    sender: Sender<RecordedEvent>,
+    start: Instant,
+}
+
+impl Recorder {
+    /// Start a new recording. Fails if there's already a recording at `path`
+    pub(crate) async fn create(path: PathBuf) -> std::io::Result<Self> {
+        if let Some(dir) = path.parent() {
+            tokio::fs::create_dir_all(dir).await?;
+        }
+        let file = tokio::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)
+            .await?;
+        let (sender, receiver) = channel(100);
+        tokio::spawn(write_events(file, receiver, path));
+        Ok(Self {
+            sender,
+            start: Instant::now(),
+        })
+    }
+
+    pub(crate) fn record(
+        &self,
+        event: Option<String>,
+        id: Option<String>,
+        data: serde_json::Value,
+    ) {
+        let event = RecordedEvent {
+            offset_ms: self.start.elapsed().as_millis() as u64,
+            event,
+            id,
+            timestamp: Some(Utc::now()),
+            data,
+        };
+        if let Err(TrySendError::Full(event)) = self.sender.try_send(event) {
+            tracing::warn!("the recorder is falling behind. Dropping {event:?}");
+        }
+    }
+}
+
+/// Append events to the file until the [Recorder] is dropped
+async fn write_events(file: tokio::fs::File, mut receiver: Receiver<RecordedEvent>, path: PathBuf) {
+    let mut file = tokio::io::BufWriter::new(file);
+    while let Some(event) = receiver.recv().await {
+        let Ok(mut line) = serde_json::to_vec(&event) else {
+            continue;
+        };
+        line.push(b'\n');
+        // Flushing every event keeps the recording usable while the connection is still open
+        let written = match file.write_all(&line).await {
+            Ok(()) => file.flush().await,
+            Err(err) => Err(err),
+        };
+        if let Err(err) = written {
+            tracing::error!("unable to write to recording {}. {err:?}", path.display());
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_recording() {
+        let contents = concat!(
+            r#"{"offsetMs": 0, "event": "greeting", "id": "1", "data": {"text": "hi"}}"#,
+            "\n\n",
+            r#"{"offsetMs": 1500, "data": "hi!"}"#,
+            "\n",
+        );
+        let events = parse_recording(contents).unwrap();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].event.as_deref(), Some("greeting"));
+        assert_eq!(events[0].data(), r#"{"text":"hi"}"#);
+        assert_eq!(events[1].offset_ms, 1500);
+        assert_eq!(events[1].data(), "hi!");
+
+        assert!(recording_path(Path::new("fixtures"), "report_lifecycle").is_some());
+        assert!(recording_path(Path::new("fixtures"), "../secrets").is_none());
+
+        let invalid = "{\"offsetMs\": 0, \"data\": \"ok\"}\n\nnot json";
+        assert!(matches!(
+            parse_recording(invalid),
+            Err(RecordingError::InvalidEvent { line: 3, .. })
+        ));
+    }
+
+    /// A path in a directory of its own, so tests don't trip over each other's recordings
+    fn temp_recording_path() -> PathBuf {
+        std::env::temp_dir()
+            .join(format!("sse-recording-test-{}", uuid::Uuid::new_v4()))
+            .join("recording.jsonl")
+    }
+
+    /// Wait for the writer task to write `count` events
+    async fn wait_for_events(path: &Path, count: usize) -> Vec<RecordedEvent> {
+        for _ in 0..50 {
+            if let Ok(events) = read_recording(path).await {
+                if events.len() >= count {
+                    return events;
+                }
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        panic!("{} never got {count} events", path.display());
+    }
+
+    #[tokio::test]
+    async fn test_recorder_writes_every_event() {
+        let path = temp_recording_path();
+        let recorder = Recorder::create(path.clone()).await.unwrap();
+        recorder.record(
+            Some("report_status_update".to_owned()),
+            Some("1".to_owned()),
+            serde_json::json!({ "status": "queued" }),
+        );
+        recorder.record(None, None, serde_json::json!("hi!"));
+
+        let events = wait_for_events(&path, 2).await;
+        assert_eq!(events[0].event.as_deref(), Some("report_status_update"));
+        assert_eq!(events[0].id.as_deref(), Some("1"));
+        assert_eq!(events[0].data(), r#"{"status":"queued"}"#);
+        assert!(events[0].timestamp.is_some());
+        assert_eq!(
+            (events[1].event.as_deref(), events[1].id.as_deref()),
+            (None, None)
+        );
+        assert_eq!(events[1].data(), "hi!");
+        assert!(events[0].offset_ms <= events[1].offset_ms);
+
+        // Recordings are never overwritten
+        assert!(Recorder::create(path.clone()).await.is_err());
+        drop(recorder);
+        let _ = tokio::fs::remove_dir_all(path.parent().unwrap()).await;
+    }
+
+    #[tokio::test]
+    async fn test_replay_keeps_the_order_of_events() {
+        let event = |offset_ms, id: &str| RecordedEvent {
+            offset_ms,
+            event: None,
+            id: Some(id.to_owned()),
+            timestamp: None,
+            data: serde_json::json!("hi!"),
+        };
+        let recording = vec![event(0, "1"), event(500, "2"), event(1000, "3")];
+
+        let start = Instant::now();
+        let replayed: Vec<_> = replay(recording.clone(), Timing::Recorded { speed: 100.0 })
+            .collect()
+            .await;
+        assert_eq!(replayed, recording);
+        // A second of events at 100x takes about 10ms
+        assert!(start.elapsed() >= Duration::from_millis(10));
+        assert!(start.elapsed() < Duration::from_millis(500));
+
+        let start = Instant::now();
+        let replayed: Vec<_> = replay(
+            recording.clone(),
+            Timing::Interval(Duration::from_millis(10)),
+        )
+        .collect()
+        .await;
+        assert_eq!(replayed, recording);
+        assert!(start.elapsed() >= Duration::from_millis(20));
+        assert!(start.elapsed() < Duration::from_millis(500));
+    }
+}
@@ -0,0 +1,118 @@
+//! Client side parsing of `text/event-stream` responses.
+//!
+//! Used by `reportctl tail` and by the integration tests to read the events the server sends.
+//! Response bodies arrive in arbitrary chunks, so the parser keeps whatever it hasn't been able to
+//! parse yet and hands back events once they're complete.
+
+/// An event as the client sees it
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct SseEvent {
+    pub event: Option<String>,
+    pub id: Option<String>,
+    /// Events with more than one `data:` line have them joined with `\n`
+    pub data: String,
+}
+
+impl SseEvent {
+    /// The data as JSON, or as a JSON string when it isn't valid JSON
+    pub fn json(&self) -> serde_json::Value {
+        serde_json::from_str(&self.data)
+            .unwrap_or_else(|_| serde_json::Value::String(self.data.clone()))
+    }
+}
+
+/// Incremental parser for the `text/event-stream` format
+#[derive(Debug, Default)]
+pub struct SseParser {
+    /// Bytes of a line that hasn't been terminated yet. Kept as bytes since a chunk can end in
+    /// the middle of a multibyte character
+    buffer: Vec<u8>,
+    current: SseEvent,
+}
+
+impl SseParser {
+    /// Feed the next chunk of the response body and return any events that are now complete
+    pub fn feed(&mut self, chunk: &[u8]) -> Vec<SseEvent> {
+        self.buffer.extend_from_slice(chunk);
+
+        let mut events = vec![];
+        while let Some(newline) = self.buffer.iter().position(|&byte| byte == b'\n') {
+            let line: Vec<u8> = self.buffer.drain(..=newline).collect();
+            let line = String::from_utf8_lossy(&line);
+            let line = line.trim_end_matches(['\n', '\r']);
+
+            if line.is_empty() {
+                // A blank line dispatches the event
+                let event = std::mem::take(&mut self.current);
+                if event.event.is_some() || !event.data.is_empty() {
+                    events.push(event);
+                }
+                continue;
+            }
+
+            let (field, value) = line.split_once(':').unwrap_or((line, ""));
+            let value = value.strip_prefix(' ').unwrap_or(value);
+            match field {
+                // lines starting with `:` are comments, e.g. the keep-alive text
+                "" => {}
+                "event" => self.current.event = Some(value.to_owned()),
+                "id" => self.current.id = Some(value.to_owned()),
+                "data" => {
+                    if !self.current.data.is_empty() {
+                        self.current.data.push('\n');
+                    }
+                    self.current.data.push_str(value);
+                }
+                _ => {}
+            }
+        }
+        events
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_sse_parser_multibyte_character_split_across_chunks() {
+        let body = ":keep-alive-text\n\nevent: report_status_update\nid: 7\ndata: {\"name\": \"caf\u{e9}\"}\n\n";
+        let split = body.find('\u{e9}').unwrap() + 1;
+        assert!(!body.is_char_boundary(split));
+
+        let mut parser = SseParser::default();
+        assert!(parser.feed(&body.as_bytes()[..split]).is_empty());
+        let events = parser.feed(&body.as_bytes()[split..]);
+
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].event.as_deref(), Some("report_status_update"));
+        assert_eq!(events[0].id.as_deref(), Some("7"));
+        assert_eq!(events[0].data, "{\"name\": \"caf\u{e9}\"}");
+        assert_eq!(events[0].json()["name"], "caf\u{e9}");
+    }
+
+    #[test]
+    fn test_sse_parser_multiline_data_and_crlf() {
+        let mut parser = SseParser::default();
+        let events = parser.feed(b"data: first\r\ndata: second\r\n\r\ndata:hi!\n\nevent: ping\n\n");
+
+        assert_eq!(
+            events,
+            [
+                SseEvent {
+                    data: "first\nsecond".to_owned(),
+                    ..Default::default()
+                },
+                SseEvent {
+                    data: "hi!".to_owned(),
+                    ..Default::default()
+                },
+                SseEvent {
+                    event: Some("ping".to_owned()),
+                    ..Default::default()
+                },
+            ]
+        );
+        assert_eq!(events[1].json(), "hi!");
+    }
+}
@@ -1,28 +1,333 @@
-use crate::QueryParams;
-use axum::extract::Query;
+use crate::auth::Username;
+use crate::config::V1Config;
+use crate::recording::{
+    read_recording, recording_path, replay, RecordedEvent, RecordingError, Timing,
+};
+use axum::extract::{Query, State};
+use axum::http::StatusCode;
 use axum::response::sse::{Event, Sse};
 use axum::routing::get;
 use axum::{debug_handler, Router};
-use futures::stream::{repeat_with, Stream};
+use futures::stream::{iter, repeat_with, BoxStream, Stream, StreamExt as _};
+use rand::Rng;
 use std::{convert::Infallible, time::Duration};
-use tokio_stream::StreamExt as _;
+use uuid::Uuid;
 
-pub fn create_app_v1() -> Router {
-    Router::new().route("/sse", get(sse_handler))
+pub fn create_app_v1(config: V1Config) -> Router {
+    Router::new()
+        .route("/sse", get(sse_handler))
+        .with_state(config)
 }
 
+/// Synthetic streams for developing clients without Kafka or DynamoDB
+#[derive(Debug, Default, Clone, Copy, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum DemoStream {
+    /// `hi!` over and over
+    #[default]
+    Hi,
+    /// The current time
+    Clock,
+    /// Counts up from 1. Every event's id is its count
+    Counter,
+    /// `report_status_update` events for made up reports
+    Reports,
+    /// Replay a recording from the fixtures directory
+    Fixture,
+}
+
+/// Shorter intervals would have a single client flooding the server with events
+const MIN_INTERVAL: Duration = Duration::from_millis(10);
+
+#[derive(Debug, serde::Deserialize)]
+struct DemoParams {
+    #[serde(default)]
+    stream: DemoStream,
+    /// Time between events. Must be at least [MIN_INTERVAL]
+    interval_ms: Option<u64>,
+    /// Sent as every event's name instead of the stream's own names
+    event: Option<String>,
+    /// Close the stream after this many events
+    count: Option<usize>,
+    /// Name of the `.jsonl` file to replay, without the extension
+    fixture: Option<String>,
+}
+
+type EventStream = BoxStream<'static, Result<Event, Infallible>>;
+
 #[debug_handler]
 async fn sse_handler(
-    Query(params): Query<QueryParams>,
-) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
-    tracing::info!("`{:?}` connected", params.username);
+    State(config): State<V1Config>,
+    Username(username): Username,
+    Query(params): Query<DemoParams>,
+) -> Result<Sse<EventStream>, (StatusCode, String)> {
+    tracing::info!(
+        "`{:?}` connected to the {:?} stream",
+        username,
+        params.stream
+    );
+
+    if params
+        .event
+        .as_ref()
+        .is_some_and(|event| event.contains(['\n', '\r']))
+    {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "`event` can't contain line breaks".to_owned(),
+        ));
+    }
+
+    let interval = params.interval_ms.map(Duration::from_millis);
+    if interval.is_some_and(|interval| interval < MIN_INTERVAL) {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!(
+                "`interval_ms` must be at least {}",
+                MIN_INTERVAL.as_millis()
+            ),
+        ));
+    }
+    let interval_or = |default| interval.unwrap_or(default);
 
-    // A `Stream` that repeats an event every 15 second
-    let stream = repeat_with(|| Ok(Event::default().data("hi!"))).throttle(Duration::from_secs(15));
+    let events: BoxStream<'static, Event> = match params.stream {
+        // Repeats `hi!` every 15 seconds unless another interval is given
+        DemoStream::Hi => throttle(
+            repeat_with(|| Event::default().data("hi!")),
+            interval_or(Duration::from_secs(15)),
+        ),
+        DemoStream::Clock => throttle(
+            repeat_with(|| Event::default().data(chrono::Utc::now().to_rfc3339())),
+            interval_or(Duration::from_secs(1)),
+        ),
+        DemoStream::Counter => throttle(
+            iter(1_u64..).map(|count| {
+                Event::default()
+                    .id(count.to_string())
+                    .data(count.to_string())
+            }),
+            interval_or(Duration::from_secs(1)),
+        ),
+        DemoStream::Reports => {
+            let mut reports = ReportGenerator::new();
+            throttle(
+                repeat_with(move || reports.next_event()),
+                interval_or(Duration::from_secs(2)),
+            )
+        }
+        // Fixtures keep their recorded timing unless an interval is given
+        DemoStream::Fixture => fixture_stream(&config, &params, interval).await?,
+    };
 
-    Sse::new(stream).keep_alive(
+    let event_name = params.event;
+    let events = events.map(move |event| match &event_name {
+        Some(name) => event.event(name),
+        None => event,
+    });
+    let stream = match params.count {
+        Some(count) => events.take(count).map(Ok).boxed(),
+        None => events.map(Ok).boxed(),
+    };
+
+    Ok(Sse::new(stream).keep_alive(
         axum::response::sse::KeepAlive::new()
             .interval(Duration::from_secs(3))
             .text("keep-alive-text"),
-    )
+    ))
+}
+
+/// Emit at most one event per `interval`, starting right away
+fn throttle(
+    events: impl Stream<Item = Event> + Send + 'static,
+    interval: Duration,
+) -> BoxStream<'static, Event> {
+    tokio_stream::StreamExt::throttle(events, interval).boxed()
+}
+
+async fn fixture_stream(
+    config: &V1Config,
+    params: &DemoParams,
+    interval: Option<Duration>,
+) -> Result<BoxStream<'static, Event>, (StatusCode, String)> {
+    let Some(name) = &params.fixture else {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "missing `fixture` query parameter".to_owned(),
+        ));
+    };
+    let Some(path) = recording_path(&config.fixtures_dir, name) else {
+        return Err((StatusCode::BAD_REQUEST, format!("invalid fixture {name:?}")));
+    };
+
+    let recording = match read_recording(&path).await {
+        Ok(recording) => recording,
+        Err(RecordingError::Io(err)) if err.kind() == std::io::ErrorKind::NotFound => {
+            return Err((StatusCode::NOT_FOUND, format!("unknown fixture {name:?}")));
+        }
+        Err(err) => {
+            tracing::error!("unable to load fixture {}. {err}", path.display());
+            return Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("unable to load fixture {name:?}"),
+            ));
+        }
+    };
+
+    let timing = match interval {
+        Some(interval) => Timing::Interval(interval),
+        None => Timing::Recorded { speed: 1.0 },
+    };
+    let events = replay(recording, timing).map(RecordedEvent::into_event);
+    Ok(events.boxed())
+}
+
+/// Walks made up reports through the default lifecycle, starting a new report once the last
+/// one is `completed` or `failed`
+struct ReportGenerator {
+    report_id: Uuid,
+    status: Option<&'static str>,
+}
+
+impl ReportGenerator {
+    fn new() -> Self {
+        Self {
+            report_id: Uuid::new_v4(),
+            status: None,
+        }
+    }
+
+    fn next_event(&mut self) -> Event {
+        let status = match self.status {
+            None => "pending",
+            Some("pending") => "queued",
+            Some("queued") => "processing",
+            Some("processing") if rand::thread_rng().gen_bool(0.2) => "failed",
+            Some("processing") => "completed",
+            Some(_) => {
+                self.report_id = Uuid::new_v4();
+                "pending"
+            }
+        };
+        self.status = Some(status);
+
+        let data = serde_json::json!({
+            "id": self.report_id,
+            "status": status,
+            "source": "simulator",
+        });
+        Event::default()
+            .event("report_status_update")
+            .data(data.to_string())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use axum::http::Uri;
+    use axum::response::IntoResponse;
+    use std::path::PathBuf;
+
+    fn config() -> V1Config {
+        V1Config {
+            fixtures_dir: PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("fixtures"),
+        }
+    }
+
+    /// Open the stream and return the lines of every event it sent until it closed
+    async fn events(query: &str) -> Result<Vec<Vec<String>>, (StatusCode, String)> {
+        let uri: Uri = format!("/sse?{query}").parse().unwrap();
+        let params = Query::try_from_uri(&uri).unwrap();
+        let sse = sse_handler(State(config()), Username("demo".to_owned()), params).await?;
+        let chunks: Vec<_> = sse
+            .into_response()
+            .into_body()
+            .into_data_stream()
+            .map(Result::unwrap)
+            .collect()
+            .await;
+        let events = String::from_utf8(chunks.concat())
+            .unwrap()
+            .split("\n\n")
+            .filter(|event| !event.is_empty())
+            .map(|event| event.lines().map(str::to_owned).collect())
+            .collect();
+        Ok(events)
+    }
+
+    #[tokio::test]
+    async fn test_clock_stream() {
+        let events = events("stream=clock&interval_ms=10&count=2").await.unwrap();
+        assert_eq!(events.len(), 2);
+        for event in events {
+            let [data] = event.as_slice() else {
+                panic!("expected only data in {event:?}");
+            };
+            let time = data.strip_prefix("data: ").unwrap();
+            assert!(chrono::DateTime::parse_from_rfc3339(time).is_ok(), "{time}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_counter_stream() {
+        let events = events("stream=counter&interval_ms=10&count=3&event=tick")
+            .await
+            .unwrap();
+        assert_eq!(
+            events,
+            [
+                ["id: 1", "data: 1", "event: tick"],
+                ["id: 2", "data: 2", "event: tick"],
+                ["id: 3", "data: 3", "event: tick"],
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_reports_stream() {
+        let events = events("stream=reports&interval_ms=10&count=3")
+            .await
+            .unwrap();
+        let statuses: Vec<_> = events
+            .iter()
+            .map(|event| {
+                assert_eq!(event[0], "event: report_status_update");
+                let data = event[1].strip_prefix("data: ").unwrap();
+                let data: serde_json::Value = serde_json::from_str(data).unwrap();
+                data["status"].as_str().unwrap().to_owned()
+            })
+            .collect();
+        assert_eq!(statuses, ["pending", "queued", "processing"]);
+    }
+
+    #[tokio::test]
+    async fn test_fixture_stream() {
+        // The fixture's recorded timing takes seconds, so an interval is given to replay it quickly
+        let fixture = events("stream=fixture&fixture=report_lifecycle&interval_ms=10")
+            .await
+            .unwrap();
+        assert_eq!(fixture.len(), 6);
+        let ids: Vec<_> = fixture.iter().map(|event| event.last().unwrap()).collect();
+        assert_eq!(ids, ["id: 1", "id: 2", "id: 3", "id: 4", "id: 5", "id: 6"]);
+
+        let (status, _) = events("stream=fixture&fixture=missing").await.unwrap_err();
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let (status, _) = events("stream=fixture&fixture=../secrets")
+            .await
+            .unwrap_err();
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let (status, _) = events("stream=fixture").await.unwrap_err();
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_short_intervals_are_rejected() {
+        for stream in ["hi", "clock", "counter", "reports", "fixture"] {
+            let query = format!("stream={stream}&fixture=report_lifecycle&interval_ms=9");
+            let (status, _) = events(&query).await.unwrap_err();
+            assert_eq!(status, StatusCode::BAD_REQUEST, "{stream}");
+        }
+        let (status, _) = events("interval_ms=0").await.unwrap_err();
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
 }
@@ -1,19 +1,29 @@
-use axum::extract::Query;
-use axum::extract::State;
+use axum::extract::{Json, Path, Query, State};
 use axum::http::StatusCode;
 use axum::response::sse::{Event, Sse};
 use axum::routing::{get, post};
 use axum::Router;
+use chrono::{DateTime, Utc};
 use futures::stream::Stream;
-use std::collections::HashMap;
+use lru::LruCache;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::num::NonZeroUsize;
+use std::time::Instant;
 use std::{convert::Infallible, time::Duration};
 use tokio::sync::mpsc::{channel, Receiver, Sender};
+use tokio::sync::oneshot;
 use tokio_stream::StreamExt as _;
+use uuid::Uuid;
 
-use crate::QueryParams;
+use crate::auth::Username;
+use crate::config::{MailboxConfig, V2Config};
 
-pub fn create_app_v2(sender: Sender<Command>, receiver: Receiver<Command>) -> Router {
-    tokio::spawn(handle_command_messages(receiver));
+pub fn create_app_v2(
+    sender: Sender<Command>,
+    receiver: Receiver<Command>,
+    config: V2Config,
+) -> Router {
+    tokio::spawn(handle_command_messages(receiver, config.mailbox));
 
     let state = V2AppState {
         command_sender: sender,
@@ -22,6 +32,9 @@ pub fn create_app_v2(sender: Sender<Command>, receiver: Receiver<Command>) -> Ro
     Router::new()
         .route("/sse", get(sse_handler))
         .route("/message", post(send_message))
+        .route("/message/:id", get(message_receipt))
+        .route("/message/:id/ack", post(acknowledge_message))
+        .route("/presence", get(presence))
         .with_state(state)
 }
 
@@ -34,53 +47,414 @@ struct V2AppState {
 pub(crate) enum Command {
     Connect {
         username: String,
-        sender: Sender<String>,
+        /// Named channels the user wants to receive messages from
+        channels: Vec<String>,
+        /// Whether the connection wants `user_joined` and `user_left` events
+        presence: bool,
+        sender: Sender<SseMessage>,
     },
     Message {
+        target: Target,
+        message: SseMessage,
+        /// Told whether the message was delivered right away or queued
+        reply: Option<oneshot::Sender<Delivery>>,
+        /// Keep a [Receipt] for the message's id so that clients can acknowledge it. Holds the
+        /// username of the sender, who can look the receipt up
+        receipt: Option<String>,
+    },
+    /// A client received the message
+    Acknowledge {
+        message_id: String,
+        username: String,
+        /// `false` when there's no receipt for the message, or the user didn't receive it
+        reply: oneshot::Sender<bool>,
+    },
+    /// Look up the receipt for a message
+    Receipt {
+        message_id: String,
         username: String,
-        message: String,
+        /// `None` when there's no receipt, or the user didn't send or receive the message
+        reply: oneshot::Sender<Option<Receipt>>,
     },
     Closed {
         username: String,
     },
+    /// List the connected users
+    Presence {
+        reply: oneshot::Sender<Vec<PresentUser>>,
+    },
+}
+
+/// A message sent over a user's SSE connection
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct SseMessage {
+    /// Without an event name clients get the message as a `message` event
+    pub(crate) event: Option<String>,
+    pub(crate) id: Option<String>,
+    pub(crate) data: String,
+}
+
+impl SseMessage {
+    pub(crate) fn message(data: String) -> Self {
+        Self {
+            event: None,
+            id: None,
+            data,
+        }
+    }
+
+    fn presence(event: &str, username: &str) -> Self {
+        Self {
+            event: Some(event.to_owned()),
+            id: None,
+            data: serde_json::json!({ "username": username }).to_string(),
+        }
+    }
+}
+
+/// An open SSE connection
+#[derive(Debug)]
+struct Connection {
+    sender: Sender<SseMessage>,
+    connected_at: DateTime<Utc>,
+    presence: bool,
+    /// Channels joined when the connection was opened. Other connections of the same user
+    /// can be in different channels
+    channels: HashSet<String>,
+}
+
+/// A connected user, as listed by `GET /v2/presence`
+#[derive(Debug, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct PresentUser {
+    username: String,
+    connections: usize,
+    /// When each of the user's connections was opened
+    connected_at: Vec<DateTime<Utc>>,
+}
+
+/// Who a message is sent to
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Target {
+    User(String),
+    /// Everyone who joined the channel
+    Channel(String),
+    /// Every connected user
+    Everyone,
+}
+
+/// What happened to a message
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+#[serde(tag = "status", rename_all = "lowercase")]
+pub(crate) enum Delivery {
+    /// Sent to this many connected users
+    Delivered { recipients: usize },
+    /// The user isn't connected. They'll get the message when they connect
+    Queued,
+    /// The user isn't connected and the mailbox is turned off
+    Dropped,
+}
+
+/// How many receipts are kept before the oldest are forgotten
+const MAX_RECEIPTS: usize = 1000;
+
+/// What happened to a message sent with `receipt=true`
+#[derive(Debug, Clone, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct Receipt {
+    message_id: String,
+    #[serde(flatten)]
+    delivery: Delivery,
+    /// Users whose clients acknowledged the message, in the order they did
+    acknowledged: Vec<Acknowledgement>,
+    #[serde(skip)]
+    sender: String,
+    /// Users the message was sent or queued for. Only they can acknowledge it
+    #[serde(skip)]
+    recipients: HashSet<String>,
+}
+
+impl Receipt {
+    fn is_visible_to(&self, username: &str) -> bool {
+        self.sender == username || self.recipients.contains(username)
+    }
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Acknowledgement {
+    username: String,
+    acknowledged_at: DateTime<Utc>,
+}
+
+/// Messages sent to users while they weren't connected
+struct Mailbox {
+    config: MailboxConfig,
+    messages: HashMap<String, VecDeque<(Instant, SseMessage)>>,
+}
+
+impl Mailbox {
+    fn new(config: MailboxConfig) -> Self {
+        Self {
+            config,
+            messages: HashMap::new(),
+        }
+    }
+
+    fn push(&mut self, username: String, message: SseMessage, now: Instant) -> Delivery {
+        if self.config.max_messages == 0 {
+            return Delivery::Dropped;
+        }
+
+        // Forget users whose messages have all expired, so users who never come back don't
+        // hold onto memory forever
+        let ttl = self.config.ttl;
+        self.messages.retain(|_, messages| {
+            messages
+                .back()
+                .is_some_and(|(queued_at, _)| now.duration_since(*queued_at) < ttl)
+        });
+
+        let messages = self.messages.entry(username).or_default();
+        if messages.len() == self.config.max_messages {
+            messages.pop_front();
+        }
+        messages.push_back((now, message));
+        Delivery::Queued
+    }
+
+    /// Remove up to `limit` of the user's unexpired messages, oldest first. The rest stay queued
+    fn take(&mut self, username: &str, now: Instant, limit: usize) -> Vec<SseMessage> {
+        let Some(messages) = self.messages.get_mut(username) else {
+            return vec![];
+        };
+        let ttl = self.config.ttl;
+        messages.retain(|(queued_at, _)| now.duration_since(*queued_at) < ttl);
+        let taken = messages
+            .drain(..limit.min(messages.len()))
+            .map(|(_, message)| message)
+            .collect();
+        if messages.is_empty() {
+            self.messages.remove(username);
+        }
+        taken
+    }
 }
 
 /// Async task Loop that process all the `Command` messages received on the Receiver
-async fn handle_command_messages(mut receiver: Receiver<Command>) {
-    let mut map = HashMap::new();
+async fn handle_command_messages(mut receiver: Receiver<Command>, mailbox_config: MailboxConfig) {
+    // Users can have more than one connection open, e.g. multiple browser tabs
+    let mut map: HashMap<String, Vec<Connection>> = HashMap::new();
+    let mut mailbox = Mailbox::new(mailbox_config);
+    let mut receipts: LruCache<String, Receipt> =
+        LruCache::new(NonZeroUsize::new(MAX_RECEIPTS).expect("value is > 0"));
 
     while let Some(command) = receiver.recv().await {
         match command {
-            Command::Connect { username, sender } => {
-                tracing::info!("got a connection from {username:?}");
-                map.insert(username, sender);
+            Command::Connect {
+                username,
+                channels,
+                presence,
+                sender,
+            } => {
+                tracing::info!("got a connection from {username:?} for channels {channels:?}");
+                // The client isn't reading yet, so only take what fits in the connection's
+                // channel. Anything else waits for the next connection
+                let queued = mailbox.take(&username, Instant::now(), sender.capacity());
+                if !queued.is_empty() {
+                    tracing::info!("sending {} queued messages to {username:?}", queued.len());
+                }
+                for message in queued {
+                    if sender.try_send(message).is_err() {
+                        tracing::error!("Failed to send queued message to {username:?}");
+                        break;
+                    }
+                }
+                let joined = !map.contains_key(&username);
+                map.entry(username.clone()).or_default().push(Connection {
+                    sender,
+                    connected_at: Utc::now(),
+                    presence,
+                    channels: channels.into_iter().collect(),
+                });
+                if joined {
+                    let joined = SseMessage::presence("user_joined", &username);
+                    send_to_presence_subscribers(&map, joined).await;
+                }
             }
             Command::Closed { ref username } => {
                 tracing::info!("{username:?} closed the connection");
-                let _ = map.remove(username);
+                let Some(connections) = map.get_mut(username) else {
+                    continue;
+                };
+                // Only the connection that was just closed should be removed
+                connections.retain(|connection| !connection.sender.is_closed());
+                if !connections.is_empty() {
+                    continue;
+                }
+
+                map.remove(username);
+                let left = SseMessage::presence("user_left", username);
+                send_to_presence_subscribers(&map, left).await;
             }
-            Command::Message { username, message } => {
-                tracing::info!("sending message to {username:?}");
-                match map.get(&username) {
-                    Some(tx) => {
-                        let _ = tx.send(message).await.map_err(|err| {
-                            tracing::error!("Failed to send message to {username:?}");
-                            err
-                        });
+            Command::Presence { reply } => {
+                let mut users: Vec<_> = map
+                    .iter()
+                    .map(|(username, connections)| PresentUser {
+                        username: username.clone(),
+                        connections: connections.len(),
+                        connected_at: connections.iter().map(|c| c.connected_at).collect(),
+                    })
+                    .collect();
+                users.sort_by(|a, b| a.username.cmp(&b.username));
+                let _ = reply.send(users);
+            }
+            Command::Message {
+                target,
+                message,
+                reply,
+                receipt,
+            } => {
+                // Worked out before sending, since the target is used up by then
+                let receipt =
+                    message.id.clone().zip(receipt).map(|(message_id, sender)| {
+                        (message_id, sender, recipients(&map, &target))
+                    });
+                let delivery = match target {
+                    Target::User(username) => {
+                        tracing::info!("sending message to {username:?}");
+                        send_to_user(&map, &mut mailbox, username, message).await
+                    }
+                    Target::Channel(channel) => {
+                        // Collected up front so the future doesn't hold a closure over `map`
+                        let connections: Vec<&Connection> = map
+                            .values()
+                            .flatten()
+                            .filter(|connection| connection.channels.contains(&channel))
+                            .collect();
+                        let recipients = send_to_all(connections.into_iter(), &message).await;
+                        tracing::info!("sent message to {recipients} connections in {channel:?}");
+                        Delivery::Delivered { recipients }
                     }
-                    None => {
-                        tracing::warn!("Not connected to {username:?}. Cannot send message");
+                    Target::Everyone => {
+                        let recipients = send_to_all(map.values().flatten(), &message).await;
+                        tracing::info!("broadcast message to {recipients} connections");
+                        Delivery::Delivered { recipients }
                     }
+                };
+                if let Some((message_id, sender, recipients)) = receipt {
+                    let receipt = Receipt {
+                        message_id: message_id.clone(),
+                        delivery: delivery.clone(),
+                        acknowledged: vec![],
+                        sender,
+                        recipients,
+                    };
+                    receipts.push(message_id, receipt);
+                }
+                if let Some(reply) = reply {
+                    let _ = reply.send(delivery);
+                }
+            }
+            Command::Acknowledge {
+                message_id,
+                username,
+                reply,
+            } => {
+                let Some(receipt) = receipts
+                    .get_mut(&message_id)
+                    .filter(|receipt| receipt.recipients.contains(&username))
+                else {
+                    let _ = reply.send(false);
+                    continue;
+                };
+                // Every connection a user has open gets the message, so only the first ack counts
+                if !receipt
+                    .acknowledged
+                    .iter()
+                    .any(|ack| ack.username == username)
+                {
+                    tracing::info!("{username:?} acknowledged message {message_id:?}");
+                    receipt.acknowledged.push(Acknowledgement {
+                        username,
+                        acknowledged_at: Utc::now(),
+                    });
                 }
+                let _ = reply.send(true);
+            }
+            Command::Receipt {
+                message_id,
+                username,
+                reply,
+            } => {
+                let receipt = receipts
+                    .get(&message_id)
+                    .filter(|receipt| receipt.is_visible_to(&username))
+                    .cloned();
+                let _ = reply.send(receipt);
             }
         }
     }
 }
 
+/// The users a message sent to the target is meant for
+fn recipients(map: &HashMap<String, Vec<Connection>>, target: &Target) -> HashSet<String> {
+    match target {
+        // Queued messages count, since the user can still acknowledge them once they connect
+        Target::User(username) => HashSet::from([username.clone()]),
+        Target::Channel(channel) => map
+            .iter()
+            .filter(|(_, connections)| connections.iter().any(|c| c.channels.contains(channel)))
+            .map(|(username, _)| username.clone())
+            .collect(),
+        Target::Everyone => map.keys().cloned().collect(),
+    }
+}
+
+/// Send the message to the user, or put it in their mailbox if they aren't connected
+async fn send_to_user(
+    map: &HashMap<String, Vec<Connection>>,
+    mailbox: &mut Mailbox,
+    username: String,
+    message: SseMessage,
+) -> Delivery {
+    // The user could have disconnected without us hearing about it yet
+    let recipients = send_to_all(map.get(&username).into_iter().flatten(), &message).await;
+    if recipients > 0 {
+        return Delivery::Delivered { recipients };
+    }
+
+    tracing::info!("Not connected to {username:?}. Queuing the message");
+    mailbox.push(username, message, Instant::now())
+}
+
+/// Returns how many connections the message was sent to
+async fn send_to_all<'a>(
+    connections: impl Iterator<Item = &'a Connection>,
+    message: &SseMessage,
+) -> usize {
+    let mut recipients = 0;
+    for connection in connections {
+        match connection.sender.send(message.clone()).await {
+            Ok(()) => recipients += 1,
+            Err(_) => tracing::error!("Failed to send message to connection"),
+        }
+    }
+    recipients
+}
+
+/// Tell the connections that opted in that a user connected or disconnected
+async fn send_to_presence_subscribers(map: &HashMap<String, Vec<Connection>>, message: SseMessage) {
+    let subscribers = map.values().flatten().filter(|c| c.presence);
+    for connection in subscribers {
+        let _ = connection.sender.send(message.clone()).await;
+    }
+}
+
 /// Helper task that notifies the main async loop that a user has disconnected
 async fn handle_user_disconnect(
     app_command_sender: Sender<Command>,
-    user_sse_sender: Sender<String>,
+    user_sse_sender: Sender<SseMessage>,
     username: String,
 ) {
     // `closed()` will wait for the receiving end of the stream to be dropped.
@@ -97,6 +471,15 @@ async fn handle_user_disconnect(
     }
 }
 
+#[derive(Debug, serde::Deserialize)]
+struct SseParams {
+    /// Comma separated list of channels to join, e.g. `general,random`
+    channels: Option<String>,
+    /// Send `user_joined` and `user_left` events when users connect and disconnect
+    #[serde(default)]
+    presence: bool,
+}
+
 /// Handles [Server Sent Events]
 ///
 /// Once the connection has been established the server can keep sending data to the client.
@@ -105,12 +488,24 @@ async fn handle_user_disconnect(
 #[axum::debug_handler]
 async fn sse_handler(
     State(state): State<V2AppState>,
-    Query(params): Query<QueryParams>,
+    Username(username): Username,
+    Query(params): Query<SseParams>,
 ) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, String> {
-    let (sse_sender, sse_receiver): (Sender<String>, Receiver<String>) = channel(100);
+    let (sse_sender, sse_receiver): (Sender<SseMessage>, Receiver<SseMessage>) = channel(100);
+
+    let channels = params
+        .channels
+        .iter()
+        .flat_map(|channels| channels.split(','))
+        .map(str::trim)
+        .filter(|channel| !channel.is_empty())
+        .map(str::to_owned)
+        .collect();
 
     let connect = Command::Connect {
-        username: params.username.clone(),
+        username: username.clone(),
+        channels,
+        presence: params.presence,
         sender: sse_sender.clone(),
     };
     let Ok(()) = state.command_sender.send(connect).await else {
@@ -122,12 +517,20 @@ async fn sse_handler(
     tokio::spawn(handle_user_disconnect(
         state.command_sender,
         sse_sender,
-        params.username,
+        username,
     ));
 
     // Create the event stream from the receiving end of the channel
-    let stream = tokio_stream::wrappers::ReceiverStream::new(sse_receiver)
-        .map(|data| Ok(Event::default().data(data)));
+    let stream = tokio_stream::wrappers::ReceiverStream::new(sse_receiver).map(|message| {
+        let mut event = Event::default().data(message.data);
+        if let Some(name) = message.event {
+            event = event.event(name);
+        }
+        if let Some(id) = message.id {
+            event = event.id(id);
+        }
+        Ok(event)
+    });
 
     // Create and return the server sent event response
     let sse = Sse::new(stream).keep_alive(
@@ -138,22 +541,428 @@ async fn sse_handler(
     Ok(sse)
 }
 
+#[derive(Debug, serde::Deserialize)]
+struct MessageParams {
+    /// Send the message to this user. The sender is whoever made the request
+    to: Option<String>,
+    /// Send the message to everyone in this channel
+    channel: Option<String>,
+    /// Send the message to every connected user
+    #[serde(default)]
+    broadcast: bool,
+    /// Give the message an id that clients can acknowledge
+    #[serde(default)]
+    receipt: bool,
+}
+
+impl MessageParams {
+    /// Who the message is for. Exactly one of `to`, `channel` and `broadcast` has to be given
+    fn target(&self) -> Result<Target, &'static str> {
+        match (&self.to, &self.channel, self.broadcast) {
+            (Some(username), None, false) => Ok(Target::User(username.clone())),
+            (None, Some(channel), false) => Ok(Target::Channel(channel.clone())),
+            (None, None, true) => Ok(Target::Everyone),
+            _ => Err("a message needs exactly one of `to`, `channel` or `broadcast=true`"),
+        }
+    }
+}
+
+#[derive(Debug, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+struct SendResponse {
+    #[serde(flatten)]
+    delivery: Delivery,
+    /// Only set when a receipt was asked for
+    #[serde(skip_serializing_if = "Option::is_none")]
+    message_id: Option<String>,
+}
+
 #[axum::debug_handler]
 async fn send_message(
     State(state): State<V2AppState>,
-    Query(params): Query<QueryParams>,
+    Username(username): Username,
+    Query(params): Query<MessageParams>,
     body: String,
-) -> StatusCode {
+) -> (StatusCode, Result<Json<SendResponse>, String>) {
+    let target = match params.target() {
+        Ok(target) => target,
+        Err(err) => return (StatusCode::BAD_REQUEST, Err(err.to_owned())),
+    };
+
+    tracing::info!("sending message to {target:?}");
+    let mut message = SseMessage::message(body);
+    // Clients get the id as the SSE `id:` field, and send it back to acknowledge the message
+    let message_id = params.receipt.then(|| Uuid::new_v4().to_string());
+    message.id.clone_from(&message_id);
+
+    let (reply, delivery) = oneshot::channel();
     let message = Command::Message {
-        username: params.username.clone(),
-        message: body,
+        target,
+        message,
+        reply: Some(reply),
+        receipt: params.receipt.then_some(username),
+    };
+    if let Err(err) = state.command_sender.send(message).await {
+        tracing::error!("failed to send message event. {err:?}");
+        return (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Err("unable to send the message".to_owned()),
+        );
+    }
+
+    match delivery.await {
+        Ok(delivery) => (
+            StatusCode::OK,
+            Ok(Json(SendResponse {
+                delivery,
+                message_id,
+            })),
+        ),
+        Err(err) => {
+            tracing::error!("never heard back about the message. {err:?}");
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Err("unable to send the message".to_owned()),
+            )
+        }
+    }
+}
+
+/// Look up who acknowledged a message sent with `receipt=true`
+#[axum::debug_handler]
+async fn message_receipt(
+    State(state): State<V2AppState>,
+    Username(username): Username,
+    Path(message_id): Path<String>,
+) -> (StatusCode, Result<Json<Receipt>, String>) {
+    let (reply, receipt) = oneshot::channel();
+    let lookup = Command::Receipt {
+        message_id,
+        username,
+        reply,
+    };
+    if let Err(err) = state.command_sender.send(lookup).await {
+        tracing::error!("failed to send receipt event. {err:?}");
+        return (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Err("unable to look up the receipt".to_owned()),
+        );
+    }
+
+    match receipt.await {
+        Ok(Some(receipt)) => (StatusCode::OK, Ok(Json(receipt))),
+        Ok(None) => (StatusCode::NOT_FOUND, Err("unknown message".to_owned())),
+        Err(err) => {
+            tracing::error!("never heard back about the receipt. {err:?}");
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Err("unable to look up the receipt".to_owned()),
+            )
+        }
+    }
+}
+
+/// Clients call this once they've received a message sent with `receipt=true`
+#[axum::debug_handler]
+async fn acknowledge_message(
+    State(state): State<V2AppState>,
+    Username(username): Username,
+    Path(message_id): Path<String>,
+) -> (StatusCode, Result<(), String>) {
+    let (reply, acknowledged) = oneshot::channel();
+    let ack = Command::Acknowledge {
+        message_id,
+        username,
+        reply,
     };
-    match state.command_sender.send(message).await {
-        Ok(()) => StatusCode::NO_CONTENT,
+    if let Err(err) = state.command_sender.send(ack).await {
+        tracing::error!("failed to send acknowledge event. {err:?}");
+        return (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Err("unable to acknowledge the message".to_owned()),
+        );
+    }
+
+    match acknowledged.await {
+        Ok(true) => (StatusCode::NO_CONTENT, Ok(())),
+        Ok(false) => (StatusCode::NOT_FOUND, Err("unknown message".to_owned())),
         Err(err) => {
-            tracing::error!("{err:?}");
-            tracing::error!("failed to send message event to {:?}", params.username);
-            StatusCode::INTERNAL_SERVER_ERROR
+            tracing::error!("never heard back about the acknowledgement. {err:?}");
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Err("unable to acknowledge the message".to_owned()),
+            )
         }
     }
 }
+
+/// List the connected users
+#[axum::debug_handler]
+async fn presence(
+    State(state): State<V2AppState>,
+    Username(_): Username,
+) -> (StatusCode, Result<Json<Vec<PresentUser>>, String>) {
+    let (reply, users) = oneshot::channel();
+    if let Err(err) = state.command_sender.send(Command::Presence { reply }).await {
+        tracing::error!("failed to send presence event. {err:?}");
+        return (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Err("unable to list connected users".to_owned()),
+        );
+    }
+
+    match users.await {
+        Ok(users) => (StatusCode::OK, Ok(Json(users))),
+        Err(err) => {
+            tracing::error!("never heard back about the connected users. {err:?}");
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Err("unable to list connected users".to_owned()),
+            )
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_message_targets() {
+        let target = |query: &str| {
+            let uri: axum::http::Uri = format!("/message?{query}").parse().unwrap();
+            let Query(params) = Query::<MessageParams>::try_from_uri(&uri).unwrap();
+            params.target()
+        };
+
+        assert_eq!(target("to=bob"), Ok(Target::User("bob".to_owned())));
+        assert_eq!(
+            target("channel=general&receipt=true"),
+            Ok(Target::Channel("general".to_owned()))
+        );
+        assert_eq!(target("broadcast=true"), Ok(Target::Everyone));
+        for query in ["", "to=bob&channel=general", "to=bob&broadcast=true"] {
+            assert!(target(query).is_err(), "{query}");
+        }
+    }
+
+    #[test]
+    fn test_mailbox_keeps_the_newest_unexpired_messages() {
+        let mut mailbox = Mailbox::new(MailboxConfig {
+            max_messages: 2,
+            ttl: Duration::from_secs(60),
+        });
+        let now = Instant::now();
+
+        let message = |data: &str| SseMessage::message(data.to_owned());
+
+        for data in ["one", "two", "three"] {
+            let delivery = mailbox.push("demo".to_owned(), message(data), now);
+            assert_eq!(delivery, Delivery::Queued);
+        }
+        assert_eq!(mailbox.take("demo", now, 1), [message("two")]);
+        assert_eq!(mailbox.take("demo", now, 10), [message("three")]);
+        assert!(mailbox.take("demo", now, 10).is_empty());
+
+        mailbox.push("demo".to_owned(), message("stale"), now);
+        assert!(mailbox
+            .take("demo", now + Duration::from_secs(60), 10)
+            .is_empty());
+    }
+
+    /// Start the command loop and return a way to send it commands
+    fn spawn_command_loop() -> Sender<Command> {
+        let (sender, receiver) = channel(100);
+        tokio::spawn(handle_command_messages(
+            receiver,
+            MailboxConfig {
+                max_messages: 10,
+                ttl: Duration::from_secs(60),
+            },
+        ));
+        sender
+    }
+
+    async fn connect(
+        commands: &Sender<Command>,
+        username: &str,
+        channels: &[&str],
+    ) -> Receiver<SseMessage> {
+        let (sender, receiver) = channel(10);
+        let connect = Command::Connect {
+            username: username.to_owned(),
+            channels: channels.iter().map(|c| c.to_string()).collect(),
+            presence: false,
+            sender,
+        };
+        commands.send(connect).await.unwrap();
+        receiver
+    }
+
+    async fn send(commands: &Sender<Command>, target: Target, data: &str) -> Delivery {
+        let (reply, delivery) = oneshot::channel();
+        let message = Command::Message {
+            target,
+            message: SseMessage::message(data.to_owned()),
+            reply: Some(reply),
+            receipt: None,
+        };
+        commands.send(message).await.unwrap();
+        delivery.await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_channel_messages_only_reach_connections_in_the_channel() {
+        let commands = spawn_command_loop();
+        let mut general_tab = connect(&commands, "alice", &["general"]).await;
+        let mut other_tab = connect(&commands, "alice", &[]).await;
+        let mut bob = connect(&commands, "bob", &["general", "random"]).await;
+        let mut carol = connect(&commands, "carol", &["random"]).await;
+
+        let delivery = send(&commands, Target::Channel("general".to_owned()), "hello").await;
+        assert_eq!(delivery, Delivery::Delivered { recipients: 2 });
+        assert_eq!(general_tab.recv().await.unwrap().data, "hello");
+        assert_eq!(bob.recv().await.unwrap().data, "hello");
+        assert!(other_tab.try_recv().is_err());
+        assert!(carol.try_recv().is_err());
+
+        // Bob leaving doesn't take alice out of the channel
+        drop(bob);
+        commands
+            .send(Command::Closed {
+                username: "bob".to_owned(),
+            })
+            .await
+            .unwrap();
+        let delivery = send(&commands, Target::Channel("general".to_owned()), "again").await;
+        assert_eq!(delivery, Delivery::Delivered { recipients: 1 });
+        assert_eq!(general_tab.recv().await.unwrap().data, "again");
+
+        // Closing the tab that joined the channel leaves alice connected but out of the channel
+        drop(general_tab);
+        commands
+            .send(Command::Closed {
+                username: "alice".to_owned(),
+            })
+            .await
+            .unwrap();
+        let delivery = send(&commands, Target::Channel("general".to_owned()), "gone").await;
+        assert_eq!(delivery, Delivery::Delivered { recipients: 0 });
+        assert!(other_tab.try_recv().is_err());
+
+        let delivery = send(&commands, Target::Channel("missing".to_owned()), "nobody").await;
+        assert_eq!(delivery, Delivery::Delivered { recipients: 0 });
+    }
+
+    #[tokio::test]
+    async fn test_broadcasts_reach_every_connection() {
+        let commands = spawn_command_loop();
+        let mut first_tab = connect(&commands, "alice", &["general"]).await;
+        let mut second_tab = connect(&commands, "alice", &[]).await;
+        let mut bob = connect(&commands, "bob", &[]).await;
+
+        let delivery = send(&commands, Target::Everyone, "hi all").await;
+        assert_eq!(delivery, Delivery::Delivered { recipients: 3 });
+        for receiver in [&mut first_tab, &mut second_tab, &mut bob] {
+            assert_eq!(receiver.recv().await.unwrap().data, "hi all");
+        }
+
+        let delivery = send(&commands, Target::User("alice".to_owned()), "just alice").await;
+        assert_eq!(delivery, Delivery::Delivered { recipients: 2 });
+        assert_eq!(first_tab.recv().await.unwrap().data, "just alice");
+        assert_eq!(second_tab.recv().await.unwrap().data, "just alice");
+        assert!(bob.try_recv().is_err());
+    }
+
+    async fn send_with_receipt(
+        commands: &Sender<Command>,
+        sender: &str,
+        target: Target,
+        message_id: &str,
+    ) -> Delivery {
+        let (reply, delivery) = oneshot::channel();
+        let mut message = SseMessage::message("did you get this?".to_owned());
+        message.id = Some(message_id.to_owned());
+        let message = Command::Message {
+            target,
+            message,
+            reply: Some(reply),
+            receipt: Some(sender.to_owned()),
+        };
+        commands.send(message).await.unwrap();
+        delivery.await.unwrap()
+    }
+
+    async fn receipt(
+        commands: &Sender<Command>,
+        message_id: &str,
+        username: &str,
+    ) -> Option<Receipt> {
+        let (reply, receipt) = oneshot::channel();
+        let lookup = Command::Receipt {
+            message_id: message_id.to_owned(),
+            username: username.to_owned(),
+            reply,
+        };
+        commands.send(lookup).await.unwrap();
+        receipt.await.unwrap()
+    }
+
+    async fn acknowledge(commands: &Sender<Command>, message_id: &str, username: &str) -> bool {
+        let (reply, acknowledged) = oneshot::channel();
+        let ack = Command::Acknowledge {
+            message_id: message_id.to_owned(),
+            username: username.to_owned(),
+            reply,
+        };
+        commands.send(ack).await.unwrap();
+        acknowledged.await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_only_recipients_can_acknowledge_messages() {
+        let commands = spawn_command_loop();
+        let _bob = connect(&commands, "bob", &["general"]).await;
+        let _bobs_other_tab = connect(&commands, "bob", &["general"]).await;
+        let _carol = connect(&commands, "carol", &["random"]).await;
+
+        let target = Target::Channel("general".to_owned());
+        let delivery = send_with_receipt(&commands, "alice", target, "message-1").await;
+        assert_eq!(delivery, Delivery::Delivered { recipients: 2 });
+
+        assert!(!acknowledge(&commands, "message-1", "carol").await);
+        assert!(!acknowledge(&commands, "message-1", "alice").await);
+        assert!(!acknowledge(&commands, "unknown", "bob").await);
+        // Both of bob's tabs acknowledge the message, but only the first ack counts
+        assert!(acknowledge(&commands, "message-1", "bob").await);
+        assert!(acknowledge(&commands, "message-1", "bob").await);
+
+        let receipt = receipt(&commands, "message-1", "alice").await.unwrap();
+        assert_eq!(receipt.delivery, Delivery::Delivered { recipients: 2 });
+        let acknowledged: Vec<_> = receipt.acknowledged.iter().map(|a| &a.username).collect();
+        assert_eq!(acknowledged, ["bob"]);
+    }
+
+    #[tokio::test]
+    async fn test_receipts_are_only_visible_to_the_sender_and_recipients() {
+        let commands = spawn_command_loop();
+        let _bob = connect(&commands, "bob", &[]).await;
+
+        let delivery = send_with_receipt(&commands, "alice", Target::Everyone, "message-1").await;
+        assert_eq!(delivery, Delivery::Delivered { recipients: 1 });
+        assert!(receipt(&commands, "message-1", "alice").await.is_some());
+        assert!(receipt(&commands, "message-1", "bob").await.is_some());
+        // Carol connected after the broadcast, so it wasn't sent to her
+        let _carol = connect(&commands, "carol", &[]).await;
+        assert!(receipt(&commands, "message-1", "carol").await.is_none());
+        assert!(!acknowledge(&commands, "message-1", "carol").await);
+        assert!(receipt(&commands, "unknown", "alice").await.is_none());
+
+        // Users who weren't connected can acknowledge once they connect and get the message
+        let target = Target::User("dave".to_owned());
+        let delivery = send_with_receipt(&commands, "dave", target, "message-2").await;
+        assert_eq!(delivery, Delivery::Queued);
+        assert!(acknowledge(&commands, "message-2", "dave").await);
+        assert!(!acknowledge(&commands, "message-2", "bob").await);
+    }
+}
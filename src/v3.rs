@@ -8,14 +8,19 @@ use rdkafka::types::RDKafkaErrorCode;
 use rdkafka::ClientConfig;
 use tokio::sync::mpsc::{Receiver, Sender};
 
+use crate::config::V2Config;
 use crate::v2::create_app_v2;
-use crate::v2::Command;
+use crate::v2::{Command, SseMessage, Target};
 
 /// The v3 router is the exact same as the v2 router except it adds a feature to listen
 /// for messages on a Kafka topic.
-pub fn create_app_v3(sender: Sender<Command>, receiver: Receiver<Command>) -> Router {
+pub fn create_app_v3(
+    sender: Sender<Command>,
+    receiver: Receiver<Command>,
+    config: V2Config,
+) -> Router {
     tokio::spawn(listen_for_kafka_message(sender.clone()));
-    create_app_v2(sender, receiver)
+    create_app_v2(sender, receiver, config)
 }
 
 /// Continuously listen for messages on the `v3_messages` Kafka topic
@@ -50,17 +55,27 @@ async fn listen_for_kafka_message(sender: Sender<Command>) {
                     continue 'consumer_loop;
                 };
 
-                let Some((username, message)) = parse_username_and_message_from_bytes(bytes) else {
-                    tracing::error!(
-                        "Cannot parse `username` and `message` from the kafka message: {:?}",
-                        std::str::from_utf8(bytes)
-                    );
-                    continue 'consumer_loop;
+                let (targets, message) = match parse_kafka_message(bytes) {
+                    Ok(parsed) => parsed,
+                    Err(err) => {
+                        tracing::error!(
+                            "Cannot parse the kafka message {:?}. {err}",
+                            String::from_utf8_lossy(bytes)
+                        );
+                        continue 'consumer_loop;
+                    }
                 };
 
-                let message = Command::Message { username, message };
-                if let Err(err) = sender.send(message).await {
-                    tracing::error!("couldn't send kafka message to {err:?}");
+                for target in targets {
+                    let message = Command::Message {
+                        target,
+                        message: message.clone(),
+                        reply: None,
+                        receipt: None,
+                    };
+                    if let Err(err) = sender.send(message).await {
+                        tracing::error!("couldn't send kafka message to {err:?}");
+                    }
                 }
             }
             Err(err) => {
@@ -83,10 +98,188 @@ async fn listen_for_kafka_message(sender: Sender<Command>) {
     }
 }
 
-fn parse_username_and_message_from_bytes(bytes: &[u8]) -> Option<(String, String)> {
-    // The message is expected to be in the following format `username:message`
+/// The only version of [Envelope] we know how to read
+const ENVELOPE_VERSION: u32 = 1;
+
+/// A structured kafka message, e.g.
+///
+/// ```json
+/// {
+///     "version": 1,
+///     "recipients": [{"type": "user", "name": "a:b"}, {"type": "channel", "name": "general"}],
+///     "event": "greeting",
+///     "body": "hello",
+///     "id": "42"
+/// }
+/// ```
+///
+/// A user who is also in one of the channels gets the message once for each recipient.
+#[derive(Debug, serde::Deserialize)]
+struct Envelope {
+    version: u32,
+    recipients: Vec<Recipient>,
+    /// Sent as the SSE `event:` field
+    event: Option<String>,
+    /// Strings are sent as is. Anything else is sent as JSON
+    body: serde_json::Value,
+    /// Sent as the SSE `id:` field
+    id: Option<String>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+#[serde(tag = "type", content = "name", rename_all = "lowercase")]
+enum Recipient {
+    User(String),
+    Channel(String),
+    Everyone,
+}
+
+impl From<Recipient> for Target {
+    fn from(recipient: Recipient) -> Self {
+        match recipient {
+            Recipient::User(username) => Target::User(username),
+            Recipient::Channel(channel) => Target::Channel(channel),
+            Recipient::Everyone => Target::Everyone,
+        }
+    }
+}
+
+#[derive(Debug)]
+enum ParseError {
+    InvalidEnvelope(serde_json::Error),
+    UnsupportedVersion(u32),
+    NoRecipients,
+    /// SSE fields can't span multiple lines
+    MultilineField(&'static str),
+    /// Not an envelope, and not in the `target:message` format either
+    InvalidFormat,
+}
+
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::InvalidEnvelope(err) => write!(f, "invalid JSON envelope: {err}"),
+            Self::UnsupportedVersion(version) => {
+                write!(f, "unsupported envelope version {version}")
+            }
+            Self::NoRecipients => write!(f, "the envelope doesn't have any recipients"),
+            Self::MultilineField(field) => write!(f, "`{field}` can't contain line breaks"),
+            Self::InvalidFormat => write!(f, "expected a JSON envelope or `target:message`"),
+        }
+    }
+}
+
+/// Messages that start with `{` are read as an [Envelope]. Anything else is read with
+/// [parse_target_and_message_from_bytes], which is how v3 has always worked
+fn parse_kafka_message(bytes: &[u8]) -> Result<(Vec<Target>, SseMessage), ParseError> {
+    if !bytes.trim_ascii_start().starts_with(b"{") {
+        let (target, message) =
+            parse_target_and_message_from_bytes(bytes).ok_or(ParseError::InvalidFormat)?;
+        return Ok((vec![target], SseMessage::message(message)));
+    }
+
+    let envelope: Envelope = serde_json::from_slice(bytes).map_err(ParseError::InvalidEnvelope)?;
+    if envelope.version != ENVELOPE_VERSION {
+        return Err(ParseError::UnsupportedVersion(envelope.version));
+    }
+    if envelope.recipients.is_empty() {
+        return Err(ParseError::NoRecipients);
+    }
+
+    let is_multiline = |field: &Option<String>| {
+        field
+            .as_ref()
+            .is_some_and(|field| field.contains(['\n', '\r']))
+    };
+    if is_multiline(&envelope.event) {
+        return Err(ParseError::MultilineField("event"));
+    }
+    if is_multiline(&envelope.id) {
+        return Err(ParseError::MultilineField("id"));
+    }
+
+    let data = match envelope.body {
+        serde_json::Value::String(body) => body,
+        body => body.to_string(),
+    };
+    let message = SseMessage {
+        event: envelope.event,
+        id: envelope.id,
+        data,
+    };
+    let targets = envelope.recipients.into_iter().map(Target::from).collect();
+    Ok((targets, message))
+}
+
+/// The message is expected to be in one of the following formats:
+///
+/// * `username:message` sends the message to a single user
+/// * `#channel:message` sends the message to everyone in the channel
+/// * `*:message` sends the message to every connected user
+fn parse_target_and_message_from_bytes(bytes: &[u8]) -> Option<(Target, String)> {
     let colon_index = bytes.iter().position(|b| *b == b':')?;
-    let username = String::from_utf8(bytes[..colon_index].to_vec()).ok()?;
+    let target = String::from_utf8(bytes[..colon_index].to_vec()).ok()?;
     let message = String::from_utf8(bytes[colon_index + 1..].to_vec()).ok()?;
-    Some((username, message))
+
+    let target = if target == "*" {
+        Target::Everyone
+    } else if let Some(channel) = target.strip_prefix('#') {
+        Target::Channel(channel.to_owned())
+    } else {
+        Target::User(target)
+    };
+    Some((target, message))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_target_and_message() {
+        let parse = |bytes: &[u8]| parse_target_and_message_from_bytes(bytes);
+
+        assert_eq!(
+            parse(b"demo:hello: world"),
+            Some((Target::User("demo".to_owned()), "hello: world".to_owned()))
+        );
+        assert_eq!(
+            parse(b"#general:hi"),
+            Some((Target::Channel("general".to_owned()), "hi".to_owned()))
+        );
+        assert_eq!(
+            parse(b"*:everyone"),
+            Some((Target::Everyone, "everyone".to_owned()))
+        );
+        assert_eq!(parse(b"no colon"), None);
+    }
+
+    #[test]
+    fn test_parse_envelope() {
+        let envelope = br#"{
+            "version": 1,
+            "recipients": [{"type": "user", "name": "a:b"}, {"type": "everyone"}],
+            "event": "greeting",
+            "body": {"text": "hi"},
+            "id": "42"
+        }"#;
+        let (targets, message) = parse_kafka_message(envelope).unwrap();
+        assert_eq!(targets, [Target::User("a:b".to_owned()), Target::Everyone]);
+        assert_eq!(message.event.as_deref(), Some("greeting"));
+        assert_eq!(message.id.as_deref(), Some("42"));
+        assert_eq!(message.data, r#"{"text":"hi"}"#);
+
+        let (targets, message) = parse_kafka_message(b"demo:hello").unwrap();
+        assert_eq!(targets, [Target::User("demo".to_owned())]);
+        assert_eq!(message, SseMessage::message("hello".to_owned()));
+
+        for invalid in [
+            &br#"{"version": 2, "recipients": [{"type": "everyone"}], "body": "hi"}"#[..],
+            br#"{"version": 1, "recipients": [], "body": "hi"}"#,
+            br#"{"version": 1, "recipients": [{"type": "everyone"}], "body": "hi", "event": "a\nb"}"#,
+            br#"{"version": 1}"#,
+        ] {
+            assert!(parse_kafka_message(invalid).is_err());
+        }
+    }
 }
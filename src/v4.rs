@@ -1,53 +1,157 @@
-use axum::routing::{get, post, put};
+use axum::routing::{delete, get, post, put};
 use axum::Router;
+use std::sync::Arc;
 use tokio::sync::mpsc::{channel, Receiver, Sender};
 
+use crate::config::{MessageBus, V4Config};
+
+mod admin;
 mod app_events;
 pub mod database;
 pub mod dynamodb;
+mod event_log;
 mod kafka_consumer;
 mod kafka_producer;
+mod limits;
+pub mod memory;
+mod recordings;
 mod report_status;
 mod request_handlers;
+mod simulator;
+mod state_machine;
 mod tasks;
+mod watchdog;
+mod webhooks;
+mod websocket;
+mod workers;
 
 pub(super) use app_events::AppEvent;
+pub use app_events::{BulkStatusUpdateResult, NewReport, Report, ReportList, ReportStatusUpdate};
+pub use report_status::{ReportStatus, ReportStatusError};
+pub use state_machine::{StateMachine, StateMachineError};
 
 #[derive(Debug, Clone)]
 struct V4AppState<D> {
     app_event_sender: Sender<AppEvent>,
     report_status_sender: Sender<app_events::ReportStatusUpdate>,
     database: D,
+    config: Arc<V4Config>,
+    webhooks: webhooks::SharedWebhookRegistry,
+    limits: Arc<limits::Limits>,
 }
 
 pub fn create_app_v4<D>(
     sender: Sender<AppEvent>,
     receiver: Receiver<AppEvent>,
     database: D,
+    config: V4Config,
 ) -> Router
 where
     D: database::Database + Clone + Send + Sync + 'static,
     <D as database::Database>::Error: std::fmt::Debug,
 {
-    tokio::spawn(tasks::handle_app_events(receiver, database.clone()));
-    tokio::spawn(kafka_consumer::consume_kafka_messages(sender.clone()));
+    let webhook_registry = webhooks::SharedWebhookRegistry::default();
+    let (webhook_sender, webhook_receiver) = channel(1000);
+    tokio::spawn(webhooks::deliver_webhooks(
+        webhook_receiver,
+        webhook_registry.clone(),
+        config.webhooks.clone(),
+    ));
+
+    tokio::spawn(tasks::handle_app_events(
+        receiver,
+        database.clone(),
+        config.state_machine.clone(),
+        config.event_log.clone(),
+        webhook_sender,
+    ));
+    if config.background_tasks {
+        tokio::spawn(watchdog::watch_for_stuck_reports(
+            sender.clone(),
+            database.clone(),
+            config.watchdog.clone(),
+        ));
+        tokio::spawn(workers::requeue_expired_leases(
+            sender.clone(),
+            database.clone(),
+            config.workers.clone(),
+        ));
+    }
 
     let (report_status_sender, report_status_receiver) = channel(100);
 
-    tokio::spawn(kafka_producer::produce_kafka_messages(
-        report_status_receiver,
-    ));
+    match config.message_bus {
+        MessageBus::Kafka => {
+            tokio::spawn(kafka_consumer::consume_kafka_messages(sender.clone()));
+            tokio::spawn(kafka_producer::produce_kafka_messages(
+                report_status_receiver,
+            ));
+        }
+        MessageBus::InProcess => {
+            tokio::spawn(forward_report_status_updates(
+                report_status_receiver,
+                sender.clone(),
+            ));
+        }
+    }
 
+    let limits = Arc::new(limits::Limits::new(config.limits.clone()));
     let state = V4AppState {
         app_event_sender: sender,
         report_status_sender,
         database,
+        config: Arc::new(config),
+        webhooks: webhook_registry,
+        limits,
     };
 
     Router::new()
         .route("/sse", get(request_handlers::sse_handler_v4))
+        .route("/ws", get(websocket::ws_handler_v4))
+        .route("/poll", get(request_handlers::poll_handler_v4))
+        .route("/presence", get(request_handlers::presence))
         .route("/new/report", post(request_handlers::create_report))
-        .route("/reports", get(request_handlers::list_reports))
+        .route(
+            "/reports",
+            get(request_handlers::list_reports).put(request_handlers::bulk_change_report_status),
+        )
+        .route("/state-machine", get(request_handlers::state_machine))
         .route("/report", put(request_handlers::change_report_status))
+        .route(
+            "/report/progress",
+            put(request_handlers::change_report_progress),
+        )
+        .route(
+            "/report/:id",
+            get(request_handlers::get_report).delete(request_handlers::delete_report),
+        )
+        .route("/report/:id/archive", put(request_handlers::archive_report))
+        .route("/report/:id/sse", get(request_handlers::report_sse_handler))
+        .route("/admin/sse", get(admin::admin_sse_handler))
+        .route(
+            "/webhooks",
+            get(webhooks::list_webhooks).post(webhooks::create_webhook),
+        )
+        .route("/webhooks/:id", delete(webhooks::delete_webhook))
+        .route("/webhooks/:id/deliveries", get(webhooks::list_deliveries))
+        .route("/worker/claim", post(workers::claim_report))
+        .route("/worker/report/:id/heartbeat", post(workers::heartbeat))
+        .route("/worker/report/:id/finish", post(workers::finish_report))
+        .route("/metrics", get(limits::metrics_handler))
+        .route("/recordings/:name/sse", get(recordings::replay_recording))
         .with_state(state)
 }
+
+/// Stands in for the Kafka producer and consumer when the app runs without Kafka
+async fn forward_report_status_updates(
+    mut receiver: Receiver<app_events::ReportStatusUpdate>,
+    sender: Sender<AppEvent>,
+) {
+    while let Some(update) = receiver.recv().await {
+        let message = AppEvent::report_status_update_message(update);
+        if let Err(err) = sender.send(message).await {
+            tracing::error!("couldn't forward report status update. {err:?}");
+            return;
+        }
+    }
+}
@@ -0,0 +1,122 @@
+use axum::extract::{Query, State};
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::response::sse::{Event, Sse};
+use futures::stream::Stream;
+use std::{convert::Infallible, time::Duration};
+use tokio::sync::mpsc::{channel, Receiver, Sender};
+use tokio_stream::StreamExt as _;
+use uuid::Uuid;
+
+use super::app_events::{AdminFilter, AppEvent, ReportTransition};
+use super::report_status::ReportStatus;
+use super::tasks::handle_disconnect;
+use super::V4AppState;
+
+#[derive(Debug, serde::Deserialize)]
+pub(super) struct AdminQueryParams {
+    /// Browsers can't set headers on an `EventSource`, so the token can also be passed here
+    token: Option<String>,
+    /// Comma separated list of statuses, e.g. `failed,canceled`
+    status: Option<String>,
+    user_id: Option<Uuid>,
+}
+
+impl AdminQueryParams {
+    fn filter(&self) -> Result<AdminFilter, String> {
+        let statuses = match &self.status {
+            Some(statuses) => ReportStatus::parse_list(statuses).map_err(|err| err.to_string())?,
+            None => vec![],
+        };
+
+        Ok(AdminFilter {
+            statuses,
+            user_id: self.user_id,
+        })
+    }
+}
+
+/// Check the admin token passed in the `Authorization: Bearer <token>` header or `token` query
+/// parameter.
+pub(super) fn authorize_admin(
+    admin_token: Option<&str>,
+    headers: &HeaderMap,
+    query_token: Option<&str>,
+) -> Result<(), (StatusCode, String)> {
+    let Some(admin_token) = admin_token else {
+        return Err((
+            StatusCode::FORBIDDEN,
+            "the admin endpoints are disabled".to_owned(),
+        ));
+    };
+
+    match bearer_token(headers).or(query_token) {
+        Some(token) if constant_time_eq(token.as_bytes(), admin_token.as_bytes()) => Ok(()),
+        Some(_) => Err((StatusCode::FORBIDDEN, "invalid admin token".to_owned())),
+        None => Err((StatusCode::UNAUTHORIZED, "missing admin token".to_owned())),
+    }
+}
+
+/// The token from an `Authorization: Bearer <token>` header
+pub(super) fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+}
+
+/// Compare two byte strings without bailing out at the first difference.
+/// This prevents leaking how much of the token was correct through response times.
+pub(super) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+/// Stream every report status transition across all users
+pub(super) async fn admin_sse_handler<D>(
+    State(state): State<V4AppState<D>>,
+    headers: HeaderMap,
+    Query(params): Query<AdminQueryParams>,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, (StatusCode, String)> {
+    authorize_admin(
+        state.config.admin_token.as_deref(),
+        &headers,
+        params.token.as_deref(),
+    )?;
+
+    let filter = params
+        .filter()
+        .map_err(|err| (StatusCode::BAD_REQUEST, err))?;
+
+    let (sse_sender, sse_receiver): (Sender<ReportTransition>, Receiver<ReportTransition>) =
+        channel(100);
+
+    let connect = AppEvent::AdminConnected {
+        filter,
+        sender: sse_sender.clone(),
+    };
+
+    let Ok(()) = state.app_event_sender.send(connect).await else {
+        return Err((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Error Connecting".to_owned(),
+        ));
+    };
+
+    tokio::spawn(handle_disconnect(
+        state.app_event_sender,
+        sse_sender,
+        AppEvent::AdminDisconnected,
+    ));
+
+    let stream =
+        tokio_stream::wrappers::ReceiverStream::new(sse_receiver).filter_map(|transition| {
+            let data = serde_json::to_string(&transition).ok()?;
+            Some(Ok(Event::default().event("report_transition").data(data)))
+        });
+
+    let sse = Sse::new(stream).keep_alive(
+        axum::response::sse::KeepAlive::new()
+            .interval(Duration::from_secs(30))
+            .text("keep-alive-text"),
+    );
+    Ok(sse)
+}
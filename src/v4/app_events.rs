@@ -1,17 +1,75 @@
+use super::event_log::LoggedEvent;
 use super::report_status::ReportStatus;
+use chrono::{DateTime, Utc};
 use tokio::sync::mpsc::Sender;
+use tokio::sync::oneshot;
 use uuid::Uuid;
 
 pub(crate) enum AppEvent {
     UserConnected {
         user_id: Uuid,
-        sender: Sender<ServerSentEventMessage>,
+        sender: Sender<LoggedEvent>,
+        /// Replay the events logged after this one before sending new events
+        last_event_id: Option<u64>,
     },
     UserMessage(ServerSentEventMessage),
     UserDisconnected {
         user_id: Uuid,
     },
+    /// Wait for the user's next event, unless there are already events logged after `since`
+    Poll {
+        user_id: Uuid,
+        since: Option<u64>,
+        reply: oneshot::Sender<Vec<LoggedEvent>>,
+    },
+    /// List the connected users
+    Presence {
+        reply: oneshot::Sender<Vec<PresentUser>>,
+    },
+    /// Hear about users connecting and disconnecting
+    PresenceSubscribed {
+        sender: Sender<PresenceEvent>,
+    },
     CacheReports(Vec<Report>),
+    /// Store a new report. Whether it was stored is sent back, so the report can be used as
+    /// soon as it's been handed to the user
+    NewReport {
+        report: Report,
+        reply: oneshot::Sender<bool>,
+    },
+    ReportDeleted {
+        user_id: Uuid,
+        report_id: Uuid,
+    },
+    /// Subscribe to status changes for a single report.
+    /// The report's current status is sent first, so no change can slip in between
+    ReportSubscribed {
+        report_id: Uuid,
+        sender: Sender<ServerSentEventMessage>,
+    },
+    ReportUnsubscribed {
+        report_id: Uuid,
+    },
+    /// Subscribe to every report transition across all users
+    AdminConnected {
+        filter: AdminFilter,
+        sender: Sender<ReportTransition>,
+    },
+    AdminDisconnected,
+    /// A status change that was already written to the database, e.g. by a worker claiming a
+    /// report. It only needs to be cached and sent to the report's subscribers. The event can
+    /// arrive after later changes, so it's only cached if the cache still has `old_status`
+    ReportStatusChanged {
+        user_id: Uuid,
+        old_status: ReportStatus,
+        update: ReportStatusUpdate,
+    },
+    /// Validate and apply many status updates at once. The outcome of each update is sent back
+    /// in the same order
+    BulkStatusUpdate {
+        updates: Vec<ReportStatusUpdate>,
+        reply: oneshot::Sender<Vec<BulkStatusUpdateResult>>,
+    },
 }
 
 impl AppEvent {
@@ -19,58 +77,326 @@ impl AppEvent {
         AppEvent::UserMessage(ServerSentEventMessage::ReportStatusUpdate(report_stats))
     }
 
-    pub(super) fn new_report(new_report: Report) -> Self {
-        AppEvent::UserMessage(ServerSentEventMessage::NewReport(new_report))
+    pub(super) fn report_progress_message(report_progress: ReportProgress) -> Self {
+        AppEvent::UserMessage(ServerSentEventMessage::ReportProgress(report_progress))
     }
 
     pub(super) fn cache_reports(reports: Vec<Report>) -> Self {
         AppEvent::CacheReports(reports)
     }
+
+    pub(super) fn report_deleted(report: &Report) -> Self {
+        AppEvent::ReportDeleted {
+            user_id: report.user_id,
+            report_id: report.report_id,
+        }
+    }
 }
 
-#[derive(Debug, serde::Serialize, serde::Deserialize)]
+/// Where a status update came from
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UpdateSource {
+    /// Published directly to the Kafka topic by another service
+    #[default]
+    Kafka,
+    /// Sent to one of the app's HTTP endpoints
+    Api,
+    /// The report was stuck in the same status for too long
+    Watchdog,
+    /// A worker claimed or finished the report, or its lease expired
+    Worker,
+    /// The built-in simulator is driving the report
+    Simulator,
+    /// Sent by a client over its WebSocket connection
+    WebSocket,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub struct ReportStatusUpdate {
     pub(super) id: Uuid,
     pub(super) status: ReportStatus,
+    #[serde(default)]
+    pub(super) source: UpdateSource,
+    /// Why the status changed, e.g. the report timed out
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(super) reason: Option<String>,
+    /// Only apply the update while the report has this status, e.g. so the watchdog doesn't
+    /// fail a report that was picked up after it was found to be stuck
+    #[serde(skip)]
+    pub(super) expected_status: Option<ReportStatus>,
 }
 
 impl ReportStatusUpdate {
-    pub(crate) fn into_report(self, user_id: Uuid) -> Report {
-        Report {
+    pub fn new(id: Uuid, status: ReportStatus) -> Self {
+        Self {
+            id,
+            status,
+            source: UpdateSource::default(),
+            reason: None,
+            expected_status: None,
+        }
+    }
+
+    pub fn id(&self) -> Uuid {
+        self.id
+    }
+
+    pub fn status(&self) -> ReportStatus {
+        self.status
+    }
+}
+
+/// Outcome of a single update in a bulk status update
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BulkStatusUpdateResult {
+    pub(super) id: Uuid,
+    pub(super) status: ReportStatus,
+    pub(super) updated: bool,
+    /// Why the update was rejected
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(super) error: Option<String>,
+}
+
+impl BulkStatusUpdateResult {
+    pub(super) fn applied(update: &ReportStatusUpdate) -> Self {
+        Self {
+            id: update.id,
+            status: update.status,
+            updated: true,
+            error: None,
+        }
+    }
+
+    pub(super) fn rejected(update: &ReportStatusUpdate, error: impl ToString) -> Self {
+        Self {
+            id: update.id,
+            status: update.status,
+            updated: false,
+            error: Some(error.to_string()),
+        }
+    }
+
+    pub fn id(&self) -> Uuid {
+        self.id
+    }
+
+    pub fn updated(&self) -> bool {
+        self.updated
+    }
+
+    pub fn error(&self) -> Option<&str> {
+        self.error.as_deref()
+    }
+}
+
+/// A connected user, as listed by `GET /v4/presence`
+#[derive(Debug, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct PresentUser {
+    pub(super) user_id: Uuid,
+    pub(super) connections: usize,
+    /// When each of the user's connections was opened
+    pub(super) connected_at: Vec<DateTime<Utc>>,
+}
+
+/// Sent when a user opens their first connection or closes their last one
+#[derive(Debug, Clone, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct PresenceEvent {
+    #[serde(skip)]
+    pub(super) event: &'static str,
+    pub(super) user_id: Uuid,
+}
+
+impl PresenceEvent {
+    pub(super) fn joined(user_id: Uuid) -> Self {
+        Self {
+            event: "user_joined",
+            user_id,
+        }
+    }
+
+    pub(super) fn left(user_id: Uuid) -> Self {
+        Self {
+            event: "user_left",
             user_id,
-            report_id: self.id,
-            report_status: self.status,
         }
     }
 }
 
+/// A successful status change, sent to admins and webhooks
+#[derive(Debug, Clone, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct ReportTransition {
+    pub(super) user_id: Uuid,
+    pub(super) report_id: Uuid,
+    pub(super) old_status: ReportStatus,
+    pub(super) new_status: ReportStatus,
+    pub(super) source: UpdateSource,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(super) reason: Option<String>,
+}
+
+/// Limit which transitions are sent to an admin connection
+#[derive(Debug, Default)]
+pub(crate) struct AdminFilter {
+    /// Only send transitions into one of these statuses. All statuses are sent when empty
+    pub(super) statuses: Vec<ReportStatus>,
+    pub(super) user_id: Option<Uuid>,
+}
+
+impl AdminFilter {
+    pub(super) fn matches(&self, transition: &ReportTransition) -> bool {
+        (self.statuses.is_empty() || self.statuses.contains(&transition.new_status))
+            && self
+                .user_id
+                .is_none_or(|user_id| user_id == transition.user_id)
+    }
+}
+
+/// Progress made on a report while it's `processing`
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct ReportProgress {
+    pub(super) id: Uuid,
+    /// Percent complete. Between 0 and 100
+    pub(super) progress: u8,
+}
+
+/// Request body used to create a new report
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct NewReport {
+    pub(super) name: String,
+    #[serde(default)]
+    pub(super) parameters: serde_json::Map<String, serde_json::Value>,
+}
+
+impl NewReport {
+    pub fn new(name: String, parameters: serde_json::Map<String, serde_json::Value>) -> Self {
+        Self { name, parameters }
+    }
+}
+
 #[derive(Debug, serde::Serialize, serde::Deserialize, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct Report {
     pub(crate) user_id: Uuid,
     pub(crate) report_id: Uuid,
     pub(crate) report_status: ReportStatus,
+    pub(crate) name: String,
+    #[serde(default)]
+    pub(crate) parameters: serde_json::Map<String, serde_json::Value>,
+    pub(crate) created_at: DateTime<Utc>,
+    pub(crate) updated_at: DateTime<Utc>,
+    /// When the report last changed status. Unlike `updated_at` this isn't changed by progress
+    /// updates
+    #[serde(default)]
+    pub(crate) status_changed_at: DateTime<Utc>,
+    /// Why the report ended up in its current status, e.g. it timed out
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) status_reason: Option<String>,
+    /// Percent complete. Only reported while the report is `processing`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) progress: Option<u8>,
+    /// Archived reports are hidden when listing reports unless they're explicitly requested
+    #[serde(default)]
+    pub(crate) archived: bool,
 }
 
 impl Report {
-    pub(crate) fn new(user_id: Uuid) -> Self {
-        Self::with_all_details(user_id, Uuid::new_v4(), ReportStatus::Pending)
+    pub fn user_id(&self) -> Uuid {
+        self.user_id
     }
-    pub(crate) fn with_all_details(
-        user_id: Uuid,
-        report_id: Uuid,
-        report_status: ReportStatus,
-    ) -> Self {
+
+    pub fn report_id(&self) -> Uuid {
+        self.report_id
+    }
+
+    pub fn report_status(&self) -> ReportStatus {
+        self.report_status
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn created_at(&self) -> DateTime<Utc> {
+        self.created_at
+    }
+
+    pub fn updated_at(&self) -> DateTime<Utc> {
+        self.updated_at
+    }
+
+    pub fn status_changed_at(&self) -> DateTime<Utc> {
+        self.status_changed_at
+    }
+
+    pub fn status_reason(&self) -> Option<&str> {
+        self.status_reason.as_deref()
+    }
+
+    pub fn progress(&self) -> Option<u8> {
+        self.progress
+    }
+
+    pub fn archived(&self) -> bool {
+        self.archived
+    }
+
+    pub(crate) fn new(user_id: Uuid, new_report: NewReport) -> Self {
+        let now = Utc::now();
         Self {
             user_id,
-            report_id,
-            report_status,
+            report_id: Uuid::new_v4(),
+            report_status: ReportStatus::Pending,
+            name: new_report.name,
+            parameters: new_report.parameters,
+            created_at: now,
+            updated_at: now,
+            status_changed_at: now,
+            status_reason: None,
+            progress: None,
+            archived: false,
         }
     }
 }
 
-#[derive(Debug)]
+/// A single page of a user's reports
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ReportList {
+    pub(super) reports: Vec<Report>,
+    /// Opaque token used to fetch the next page. `None` on the last page
+    pub(super) next_cursor: Option<String>,
+}
+
+impl ReportList {
+    pub fn reports(&self) -> &[Report] {
+        &self.reports
+    }
+
+    pub fn into_reports(self) -> Vec<Report> {
+        self.reports
+    }
+
+    pub fn next_cursor(&self) -> Option<&str> {
+        self.next_cursor.as_deref()
+    }
+}
+
+/// Sent to a user when one of their reports is deleted
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct ReportDeleted {
+    pub(super) id: Uuid,
+}
+
+// Every message is about a report
+#[allow(clippy::enum_variant_names)]
+#[derive(Debug, Clone)]
 pub(crate) enum ServerSentEventMessage {
     ReportStatusUpdate(ReportStatusUpdate),
-    NewReport(Report),
+    ReportProgress(ReportProgress),
+    ReportDeleted(ReportDeleted),
 }
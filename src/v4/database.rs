@@ -1,24 +1,157 @@
+use std::collections::BTreeMap;
 use std::ops::Deref;
 
 use async_trait::async_trait;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine as _;
+use chrono::{DateTime, Utc};
 use uuid::Uuid;
 
 use super::{
-    app_events::{Report, ReportStatusUpdate},
+    app_events::{Report, ReportProgress, ReportStatusUpdate},
     report_status::ReportStatus,
 };
 
+/// Default number of reports returned by [Database::list_reports]
+pub const DEFAULT_PAGE_SIZE: usize = 50;
+/// Upper bound on the number of reports returned by [Database::list_reports]
+pub const MAX_PAGE_SIZE: usize = 100;
+
+/// Largest number of updates passed to [Database::update_report_statuses] at once
+pub const MAX_TRANSACTION_SIZE: usize = 25;
+
+/// Which of a user's reports should be listed
+#[derive(Debug, Clone)]
+pub struct ReportQuery {
+    /// Maximum number of reports to return
+    pub page_size: usize,
+    /// Continue listing from where a previous page left off
+    pub cursor: Option<PageCursor>,
+    /// Only list reports with one of these statuses. All statuses are listed when empty
+    pub statuses: Vec<ReportStatus>,
+    pub include_archived: bool,
+}
+
+impl Default for ReportQuery {
+    fn default() -> Self {
+        Self {
+            page_size: DEFAULT_PAGE_SIZE,
+            cursor: None,
+            statuses: vec![],
+            include_archived: false,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ReportPage {
+    pub reports: Vec<Report>,
+    /// Pass this back in [ReportQuery::cursor] to get the next page.
+    /// `None` when there are no more reports to list
+    pub next_cursor: Option<PageCursor>,
+}
+
+/// Opaque pagination token.
+///
+/// Backends store whatever key they need to resume listing, e.g. DynamoDB's `LastEvaluatedKey`.
+/// Clients only ever see the encoded token.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PageCursor(pub(crate) BTreeMap<String, String>);
+
+impl PageCursor {
+    pub fn encode(&self) -> String {
+        let json = serde_json::to_vec(&self.0).expect("a map of strings serializes to json");
+        URL_SAFE_NO_PAD.encode(json)
+    }
+
+    pub fn decode(token: &str) -> Option<Self> {
+        let json = URL_SAFE_NO_PAD.decode(token).ok()?;
+        serde_json::from_slice(&json).ok().map(PageCursor)
+    }
+
+    /// Decode a token handed out while listing `user_id`'s reports.
+    ///
+    /// Reports are listed by `user_id` and paged by `report_id`, so those are the only keys a
+    /// cursor can have. Tampered tokens and tokens from another user's listing return `None`
+    pub fn decode_for_user(token: &str, user_id: Uuid) -> Option<Self> {
+        let cursor = Self::decode(token)?;
+        let keys_match = cursor.0.len() == 2
+            && cursor.0.get("user_id")?.parse::<Uuid>().ok()? == user_id
+            && cursor.0.get("report_id")?.parse::<Uuid>().is_ok();
+        keys_match.then_some(cursor)
+    }
+}
+
+/// Gives a worker exclusive ownership of a `processing` report until it expires
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Lease {
+    pub token: Uuid,
+    pub expires_at: DateTime<Utc>,
+}
+
 #[async_trait]
 pub trait Database {
     type Error;
-    async fn list_reports(&self, use_id: Uuid) -> Result<Vec<Report>, Self::Error>;
+    async fn list_reports(
+        &self,
+        user_id: Uuid,
+        query: &ReportQuery,
+    ) -> Result<ReportPage, Self::Error>;
     async fn insert_report(&self, report: Report) -> Result<(), Self::Error>;
+    /// Only writes the update if the report still has `current_status`, since workers change
+    /// statuses outside of the event loop.
+    ///
+    /// Returns the report's owner, or `None` if the report doesn't exist or its status changed
     async fn update_report_status(
         &self,
+        current_status: ReportStatus,
         update: &ReportStatusUpdate,
     ) -> Result<Option<Uuid>, Self::Error>;
+    /// Write every update or none of them.
+    ///
+    /// Each update is paired with the status the report is expected to have. Nothing is written if
+    /// any report was changed in the meantime. At most [MAX_TRANSACTION_SIZE] updates are passed
+    async fn update_report_statuses(
+        &self,
+        updates: &[(ReportStatus, ReportStatusUpdate)],
+    ) -> Result<(), Self::Error>;
+    /// Returns the report's owner, or `None` if the report doesn't exist
+    async fn update_report_progress(
+        &self,
+        progress: &ReportProgress,
+    ) -> Result<Option<Uuid>, Self::Error>;
     async fn get_report_status(&self, report_id: Uuid)
         -> Result<Option<ReportStatus>, Self::Error>;
+    /// Reports that have been in `status` since before `changed_before`.
+    /// Reports held by an unexpired [Lease] aren't stale, no matter how long they've been processing
+    async fn list_stale_reports(
+        &self,
+        status: ReportStatus,
+        changed_before: DateTime<Utc>,
+    ) -> Result<Vec<Report>, Self::Error>;
+    /// Atomically move the oldest `queued` report to `processing` and hand it out under `lease`.
+    /// Returns `None` when there's nothing queued
+    async fn claim_report(&self, lease: &Lease) -> Result<Option<Report>, Self::Error>;
+    /// Extend the lease on a report. Returns `false` if `lease.token` no longer holds the report
+    async fn renew_lease(&self, report_id: Uuid, lease: &Lease) -> Result<bool, Self::Error>;
+    /// Apply a status update to a leased report and drop the lease.
+    /// Returns the report's owner, or `None` if `lease_token` no longer holds the report
+    async fn release_lease(
+        &self,
+        lease_token: Uuid,
+        update: &ReportStatusUpdate,
+    ) -> Result<Option<Uuid>, Self::Error>;
+    /// Ids and leases of `processing` reports whose lease expired before `expired_before`
+    async fn list_expired_leases(
+        &self,
+        expired_before: DateTime<Utc>,
+    ) -> Result<Vec<(Uuid, Lease)>, Self::Error>;
+    async fn get_report(&self, report_id: Uuid) -> Result<Option<Report>, Self::Error>;
+    async fn delete_report(&self, report_id: Uuid) -> Result<(), Self::Error>;
+    /// Does nothing if the report doesn't exist
+    async fn set_report_archived(&self, report_id: Uuid, archived: bool)
+        -> Result<(), Self::Error>;
 }
 
 #[async_trait]
@@ -28,8 +161,12 @@ where
 {
     type Error = T::Error;
 
-    async fn list_reports(&self, user_id: Uuid) -> Result<Vec<Report>, Self::Error> {
-        self.deref().list_reports(user_id).await
+    async fn list_reports(
+        &self,
+        user_id: Uuid,
+        query: &ReportQuery,
+    ) -> Result<ReportPage, Self::Error> {
+        self.deref().list_reports(user_id, query).await
     }
 
     async fn insert_report(&self, report: Report) -> Result<(), Self::Error> {
@@ -38,9 +175,26 @@ where
 
     async fn update_report_status(
         &self,
+        current_status: ReportStatus,
         update: &ReportStatusUpdate,
     ) -> Result<Option<Uuid>, Self::Error> {
-        self.deref().update_report_status(update).await
+        self.deref()
+            .update_report_status(current_status, update)
+            .await
+    }
+
+    async fn update_report_statuses(
+        &self,
+        updates: &[(ReportStatus, ReportStatusUpdate)],
+    ) -> Result<(), Self::Error> {
+        self.deref().update_report_statuses(updates).await
+    }
+
+    async fn update_report_progress(
+        &self,
+        progress: &ReportProgress,
+    ) -> Result<Option<Uuid>, Self::Error> {
+        self.deref().update_report_progress(progress).await
     }
 
     async fn get_report_status(
@@ -49,4 +203,101 @@ where
     ) -> Result<Option<ReportStatus>, Self::Error> {
         self.deref().get_report_status(report_id).await
     }
+
+    async fn list_stale_reports(
+        &self,
+        status: ReportStatus,
+        changed_before: DateTime<Utc>,
+    ) -> Result<Vec<Report>, Self::Error> {
+        self.deref()
+            .list_stale_reports(status, changed_before)
+            .await
+    }
+
+    async fn claim_report(&self, lease: &Lease) -> Result<Option<Report>, Self::Error> {
+        self.deref().claim_report(lease).await
+    }
+
+    async fn renew_lease(&self, report_id: Uuid, lease: &Lease) -> Result<bool, Self::Error> {
+        self.deref().renew_lease(report_id, lease).await
+    }
+
+    async fn release_lease(
+        &self,
+        lease_token: Uuid,
+        update: &ReportStatusUpdate,
+    ) -> Result<Option<Uuid>, Self::Error> {
+        self.deref().release_lease(lease_token, update).await
+    }
+
+    async fn list_expired_leases(
+        &self,
+        expired_before: DateTime<Utc>,
+    ) -> Result<Vec<(Uuid, Lease)>, Self::Error> {
+        self.deref().list_expired_leases(expired_before).await
+    }
+
+    async fn get_report(&self, report_id: Uuid) -> Result<Option<Report>, Self::Error> {
+        self.deref().get_report(report_id).await
+    }
+
+    async fn delete_report(&self, report_id: Uuid) -> Result<(), Self::Error> {
+        self.deref().delete_report(report_id).await
+    }
+
+    async fn set_report_archived(
+        &self,
+        report_id: Uuid,
+        archived: bool,
+    ) -> Result<(), Self::Error> {
+        self.deref().set_report_archived(report_id, archived).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_page_cursor_round_trip() {
+        let cursor = PageCursor(BTreeMap::from([
+            ("report_id".to_owned(), Uuid::new_v4().to_string()),
+            ("user_id".to_owned(), Uuid::new_v4().to_string()),
+        ]));
+        assert_eq!(PageCursor::decode(&cursor.encode()), Some(cursor));
+    }
+
+    #[test]
+    fn test_invalid_page_cursor() {
+        assert_eq!(PageCursor::decode("not a cursor!"), None);
+        assert_eq!(PageCursor::decode(&URL_SAFE_NO_PAD.encode("[1, 2]")), None);
+    }
+
+    #[test]
+    fn test_page_cursor_for_user() {
+        let user_id = Uuid::new_v4();
+        let cursor = |keys: &[(&str, String)]| {
+            let keys = keys.iter().map(|(k, v)| (k.to_string(), v.clone()));
+            PageCursor(keys.collect()).encode()
+        };
+        let report_id = ("report_id", Uuid::new_v4().to_string());
+
+        let valid = cursor(&[report_id.clone(), ("user_id", user_id.to_string())]);
+        assert!(PageCursor::decode_for_user(&valid, user_id).is_some());
+        assert!(PageCursor::decode_for_user(&valid, Uuid::new_v4()).is_none());
+
+        let missing_user = cursor(std::slice::from_ref(&report_id));
+        assert!(PageCursor::decode_for_user(&missing_user, user_id).is_none());
+        let extra_key = cursor(&[
+            report_id.clone(),
+            ("user_id", user_id.to_string()),
+            ("status", "queued".to_owned()),
+        ]);
+        assert!(PageCursor::decode_for_user(&extra_key, user_id).is_none());
+        let not_a_report = cursor(&[
+            ("report_id", "x".to_owned()),
+            ("user_id", user_id.to_string()),
+        ]);
+        assert!(PageCursor::decode_for_user(&not_a_report, user_id).is_none());
+    }
 }
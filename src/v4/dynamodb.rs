@@ -1,14 +1,24 @@
+use std::collections::HashMap;
 use std::str::FromStr;
+use std::time::Duration;
 
 use async_trait::async_trait;
 use aws_config::{self, BehaviorVersion, SdkConfig};
-use aws_sdk_dynamodb::operation::query::QueryOutput;
-use aws_sdk_dynamodb::types::{AttributeValue, ReturnValue, Select};
+use aws_sdk_dynamodb::error::SdkError;
+use aws_sdk_dynamodb::operation::update_item::builders::UpdateItemFluentBuilder;
+use aws_sdk_dynamodb::operation::update_item::UpdateItemOutput;
+use aws_sdk_dynamodb::types::{
+    AttributeDefinition, AttributeValue, CreateGlobalSecondaryIndexAction, GlobalSecondaryIndex,
+    GlobalSecondaryIndexUpdate, IndexStatus, KeySchemaElement, KeyType, Projection, ProjectionType,
+    ProvisionedThroughput, ReturnValue, ScalarAttributeType, Select, TableDescription, TableStatus,
+    TransactWriteItem, Update,
+};
 use aws_sdk_dynamodb::Client as DynamoDB;
+use chrono::{DateTime, SecondsFormat, Utc};
 use uuid::Uuid;
 
-use super::app_events::{Report, ReportStatusUpdate};
-use super::database::Database;
+use super::app_events::{Report, ReportProgress, ReportStatusUpdate};
+use super::database::{Database, Lease, PageCursor, ReportPage, ReportQuery};
 use super::report_status::ReportStatus;
 
 async fn get_aws_config() -> SdkConfig {
@@ -25,6 +35,318 @@ pub async fn get_dynamo_db_client() -> DynamoDB {
 }
 
 const TABLE_NAME: &str = "report_status";
+/// Global secondary index used to lookup all of a user's reports
+const USER_ID_INDEX: &str = "UserIdIndex";
+/// Global secondary index used to find reports by status, oldest status change first
+const STATUS_INDEX: &str = "StatusIndex";
+
+/// How many times we'll check if a newly created table is ready before giving up
+const TABLE_ACTIVE_POLL_ATTEMPTS: usize = 60;
+const TABLE_ACTIVE_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+#[derive(Debug)]
+pub enum SchemaError {
+    /// Error returned by DynamoDB
+    DynamoDB(Box<aws_sdk_dynamodb::Error>),
+    /// The table exists, but it isn't laid out the way the app expects
+    SchemaMismatch(String),
+    /// The table was created, but never became active
+    TableNotActive,
+}
+
+impl std::fmt::Display for SchemaError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SchemaError::DynamoDB(err) => write!(f, "DynamoDB error: {err}"),
+            SchemaError::SchemaMismatch(reason) => {
+                write!(f, "table {TABLE_NAME} has an unexpected schema: {reason}")
+            }
+            SchemaError::TableNotActive => write!(f, "table {TABLE_NAME} never became active"),
+        }
+    }
+}
+
+impl std::error::Error for SchemaError {}
+
+impl<E, R> From<SdkError<E, R>> for SchemaError
+where
+    aws_sdk_dynamodb::Error: From<SdkError<E, R>>,
+{
+    fn from(value: SdkError<E, R>) -> Self {
+        SchemaError::DynamoDB(Box::new(value.into()))
+    }
+}
+
+/// Make sure the `report_status` table and its `UserIdIndex` and `StatusIndex` exist.
+///
+/// The table is only created when it's missing, so existing reports are never deleted.
+/// If the table already exists we check that its key schema matches what the app expects.
+/// Tables created before the `StatusIndex` was added get the index.
+pub async fn ensure_report_table(client: &DynamoDB) -> Result<(), SchemaError> {
+    let description = match describe_report_table(client).await? {
+        Some(description) if !has_index(&description, STATUS_INDEX) => {
+            tracing::info!("table {TABLE_NAME} is missing the {STATUS_INDEX}. Adding it");
+            create_status_index(client).await?;
+            let description = wait_for_report_table(client).await?;
+            backfill_status_changed_at(client).await?;
+            description
+        }
+        Some(description) => {
+            tracing::info!("table {TABLE_NAME} already exists");
+            description
+        }
+        None => {
+            tracing::info!("table {TABLE_NAME} does not exist. Creating it");
+            create_report_table(client).await?;
+            wait_for_report_table(client).await?
+        }
+    };
+
+    verify_key_schema(&description)
+}
+
+async fn describe_report_table(client: &DynamoDB) -> Result<Option<TableDescription>, SchemaError> {
+    match client.describe_table().table_name(TABLE_NAME).send().await {
+        Ok(output) => Ok(output.table),
+        Err(err) => {
+            let err = err.into_service_error();
+            if err.is_resource_not_found_exception() {
+                Ok(None)
+            } else {
+                Err(SchemaError::DynamoDB(Box::new(err.into())))
+            }
+        }
+    }
+}
+
+fn key(attribute_name: &str, key_type: KeyType) -> KeySchemaElement {
+    KeySchemaElement::builder()
+        .attribute_name(attribute_name)
+        .key_type(key_type)
+        .build()
+        .expect("attribute_name and key_type are set")
+}
+
+fn string_attribute(attribute_name: &str) -> AttributeDefinition {
+    AttributeDefinition::builder()
+        .attribute_name(attribute_name)
+        .attribute_type(ScalarAttributeType::S)
+        .build()
+        .expect("attribute_name and attribute_type are set")
+}
+
+fn throughput(read_capacity: i64, write_capacity: i64) -> ProvisionedThroughput {
+    ProvisionedThroughput::builder()
+        .read_capacity_units(read_capacity)
+        .write_capacity_units(write_capacity)
+        .build()
+        .expect("read and write capacity are set")
+}
+
+fn has_index(description: &TableDescription, index_name: &str) -> bool {
+    description
+        .global_secondary_indexes()
+        .iter()
+        .any(|index| index.index_name() == Some(index_name))
+}
+
+fn all_attributes() -> Projection {
+    Projection::builder()
+        .projection_type(ProjectionType::All)
+        .build()
+}
+
+/// Key schema of the `StatusIndex`. Timestamps are stored as strings that sort chronologically
+fn status_index_key_schema() -> [KeySchemaElement; 2] {
+    [
+        key("status", KeyType::Hash),
+        key("status_changed_at", KeyType::Range),
+    ]
+}
+
+/// Create the report_status table with a primary key consisting of report_id (partition key).
+/// We add a secondary index on the `user_id` column so that we can lookup a users reports, and
+/// one on the `status` column so that the watchdog and workers can find reports by status.
+///
+/// Succeeds if the table already exists, e.g. when another process created it in the meantime
+async fn create_report_table(client: &DynamoDB) -> Result<(), SchemaError> {
+    let user_id_index = GlobalSecondaryIndex::builder()
+        .index_name(USER_ID_INDEX)
+        .key_schema(key("user_id", KeyType::Hash))
+        .projection(all_attributes())
+        .provisioned_throughput(throughput(10, 5))
+        .build()
+        .expect("index_name and key_schema are set");
+
+    let status_index = GlobalSecondaryIndex::builder()
+        .index_name(STATUS_INDEX)
+        .set_key_schema(Some(status_index_key_schema().to_vec()))
+        .projection(all_attributes())
+        .provisioned_throughput(throughput(10, 5))
+        .build()
+        .expect("index_name and key_schema are set");
+
+    let request = client
+        .create_table()
+        .table_name(TABLE_NAME)
+        .attribute_definitions(string_attribute("report_id"))
+        .attribute_definitions(string_attribute("user_id"))
+        .attribute_definitions(string_attribute("status"))
+        .attribute_definitions(string_attribute("status_changed_at"))
+        .key_schema(key("report_id", KeyType::Hash))
+        .provisioned_throughput(throughput(10, 10))
+        .global_secondary_indexes(user_id_index)
+        .global_secondary_indexes(status_index);
+
+    match request.send().await {
+        Ok(_) => Ok(()),
+        Err(err) => {
+            let err = err.into_service_error();
+            if err.is_resource_in_use_exception() {
+                // Another process started at the same time and created the table first.
+                // The caller waits for the table to become active either way
+                tracing::info!("table {TABLE_NAME} was created by another process");
+                Ok(())
+            } else {
+                Err(SchemaError::DynamoDB(Box::new(err.into())))
+            }
+        }
+    }
+}
+
+/// Add the `StatusIndex` to a table created before the index existed
+async fn create_status_index(client: &DynamoDB) -> Result<(), SchemaError> {
+    let status_index = CreateGlobalSecondaryIndexAction::builder()
+        .index_name(STATUS_INDEX)
+        .set_key_schema(Some(status_index_key_schema().to_vec()))
+        .projection(all_attributes())
+        .provisioned_throughput(throughput(10, 5))
+        .build()
+        .expect("index_name and key_schema are set");
+
+    client
+        .update_table()
+        .table_name(TABLE_NAME)
+        .attribute_definitions(string_attribute("status"))
+        .attribute_definitions(string_attribute("status_changed_at"))
+        .global_secondary_index_updates(
+            GlobalSecondaryIndexUpdate::builder()
+                .create(status_index)
+                .build(),
+        )
+        .send()
+        .await?;
+    Ok(())
+}
+
+/// Reports saved before we tracked when their status changed aren't in the `StatusIndex`, so the
+/// watchdog would never see them. Start their clock from when they were last updated, or from now
+/// if they're older than that too.
+///
+/// This scans the whole table, so it only runs once, when the index is added
+async fn backfill_status_changed_at(client: &DynamoDB) -> Result<(), SchemaError> {
+    let mut pages = client
+        .scan()
+        .table_name(TABLE_NAME)
+        .filter_expression("attribute_not_exists(status_changed_at)")
+        .projection_expression("report_id")
+        .into_paginator()
+        .page_size(100)
+        .send();
+
+    let mut backfilled = 0;
+    while let Some(page) = pages.next().await {
+        for item in page?.items() {
+            let Some(report_id) = item.get("report_id") else {
+                continue;
+            };
+            let request = client
+                .update_item()
+                .table_name(TABLE_NAME)
+                .key("report_id", report_id.clone())
+                .update_expression("set status_changed_at = if_not_exists(updated_at, :now)")
+                .condition_expression("attribute_exists(report_id)")
+                .expression_attribute_values(":now", timestamp_attribute(Utc::now()));
+            if send_unless_condition_failed(request)
+                .await
+                .map_err(|err| SchemaError::DynamoDB(Box::new(err)))?
+                .is_some()
+            {
+                backfilled += 1;
+            }
+        }
+    }
+
+    tracing::info!("set status_changed_at on {backfilled} older reports");
+    Ok(())
+}
+
+/// Poll the table until both it and all of its indexes are active
+async fn wait_for_report_table(client: &DynamoDB) -> Result<TableDescription, SchemaError> {
+    for _ in 0..TABLE_ACTIVE_POLL_ATTEMPTS {
+        if let Some(description) = describe_report_table(client).await? {
+            let table_active = description.table_status() == Some(&TableStatus::Active);
+            let indexes_active = description
+                .global_secondary_indexes()
+                .iter()
+                .all(|index| index.index_status() == Some(&IndexStatus::Active));
+
+            if table_active && indexes_active {
+                tracing::info!("table {TABLE_NAME} is active");
+                return Ok(description);
+            }
+        }
+        tokio::time::sleep(TABLE_ACTIVE_POLL_INTERVAL).await;
+    }
+
+    Err(SchemaError::TableNotActive)
+}
+
+fn verify_key_schema(description: &TableDescription) -> Result<(), SchemaError> {
+    if description.key_schema() != [key("report_id", KeyType::Hash)] {
+        return Err(SchemaError::SchemaMismatch(format!(
+            "expected `report_id` as the only (HASH) key, found {:?}",
+            description.key_schema()
+        )));
+    }
+
+    let Some(user_id_index) = description
+        .global_secondary_indexes()
+        .iter()
+        .find(|index| index.index_name() == Some(USER_ID_INDEX))
+    else {
+        return Err(SchemaError::SchemaMismatch(format!(
+            "missing the {USER_ID_INDEX} global secondary index"
+        )));
+    };
+
+    if user_id_index.key_schema() != [key("user_id", KeyType::Hash)] {
+        return Err(SchemaError::SchemaMismatch(format!(
+            "expected `user_id` as the only (HASH) key of {USER_ID_INDEX}, found {:?}",
+            user_id_index.key_schema()
+        )));
+    }
+
+    let Some(status_index) = description
+        .global_secondary_indexes()
+        .iter()
+        .find(|index| index.index_name() == Some(STATUS_INDEX))
+    else {
+        return Err(SchemaError::SchemaMismatch(format!(
+            "missing the {STATUS_INDEX} global secondary index"
+        )));
+    };
+
+    if status_index.key_schema() != status_index_key_schema() {
+        return Err(SchemaError::SchemaMismatch(format!(
+            "expected `status` (HASH) and `status_changed_at` (RANGE) as the keys of \
+            {STATUS_INDEX}, found {:?}",
+            status_index.key_schema()
+        )));
+    }
+
+    Ok(())
+}
 
 #[async_trait]
 impl Database for DynamoDB {
@@ -39,51 +361,84 @@ impl Database for DynamoDB {
         let request = self
             .put_item()
             .table_name(TABLE_NAME.to_owned())
-            .item("report_id", AttributeValue::S(report.report_id.to_string()))
-            .item("user_id", AttributeValue::S(report.user_id.to_string()))
-            .item(
-                "status",
-                AttributeValue::S(report.report_status.as_str().to_owned()),
-            );
+            .set_item(Some(report_to_item(&report)));
 
         let _ = request.send().await?;
 
         Ok(())
     }
 
-    async fn list_reports(&self, user_id: uuid::Uuid) -> Result<Vec<Report>, Self::Error> {
+    async fn list_reports(
+        &self,
+        user_id: Uuid,
+        query: &ReportQuery,
+    ) -> Result<ReportPage, Self::Error> {
         tracing::info!("listing reports for user {}", user_id);
 
-        let request = self
+        let mut request = self
             .query()
             .table_name(TABLE_NAME)
-            .index_name("UserIdIndex")
-            .select(Select::SpecificAttributes)
+            .index_name(USER_ID_INDEX)
+            .select(Select::AllProjectedAttributes)
             .key_condition_expression("#user_id = :user_id")
             .expression_attribute_names("#user_id", "user_id")
-            .expression_attribute_names("#s", "status")
-            .expression_attribute_values(":user_id", AttributeValue::S(user_id.to_string()))
-            .projection_expression("report_id, #s");
+            .expression_attribute_values(":user_id", AttributeValue::S(user_id.to_string()));
 
-        let mut paginator = request.into_paginator().page_size(100).send();
+        let mut filters = vec![];
 
-        let mut output = vec![];
-        while let Some(value) = paginator.next().await {
-            let query_output = value?;
+        if !query.include_archived {
+            filters.push("(attribute_not_exists(archived) OR archived = :false)".to_owned());
+            request = request.expression_attribute_values(":false", AttributeValue::Bool(false));
+        }
 
-            if query_output.items().is_empty() {
-                break;
+        if !query.statuses.is_empty() {
+            let mut placeholders = vec![];
+            for (index, status) in query.statuses.iter().enumerate() {
+                let placeholder = format!(":status{index}");
+                request = request.expression_attribute_values(
+                    &placeholder,
+                    AttributeValue::S(status.as_str().to_owned()),
+                );
+                placeholders.push(placeholder);
             }
+            filters.push(format!("#s IN ({})", placeholders.join(", ")));
+            request = request.expression_attribute_names("#s", "status");
+        }
+
+        if !filters.is_empty() {
+            request = request.filter_expression(filters.join(" AND "));
+        }
 
-            if let Ok(partial_reports) = PartialReports::try_from(query_output) {
-                output.extend(partial_reports.into_reports(user_id))
+        // DynamoDB applies the `Limit` before the filter expression, so a single query can return
+        // fewer reports than requested. Keep querying until the page is full or we run out.
+        let mut reports = vec![];
+        let mut start_key = query.cursor.as_ref().map(cursor_to_key);
+        loop {
+            let remaining = query.page_size - reports.len();
+            let response = request
+                .clone()
+                .set_exclusive_start_key(start_key)
+                .limit(remaining as i32)
+                .send()
+                .await?;
+
+            reports.extend(response.items().iter().filter_map(report_from_item));
+            start_key = response.last_evaluated_key;
+
+            if start_key.is_none() || reports.len() >= query.page_size {
+                break;
             }
         }
-        Ok(output)
+
+        Ok(ReportPage {
+            reports,
+            next_cursor: start_key.map(key_to_cursor),
+        })
     }
 
     async fn update_report_status(
         &self,
+        current_status: ReportStatus,
         update: &ReportStatusUpdate,
     ) -> Result<Option<Uuid>, Self::Error> {
         tracing::info!(
@@ -92,34 +447,72 @@ impl Database for DynamoDB {
             update.status,
         );
 
+        let request = status_update_request(self, current_status, update);
+
+        let response = send_unless_condition_failed(request).await?;
+        Ok(response.and_then(|response| user_id_from_old_values(response.attributes)))
+    }
+
+    async fn update_report_statuses(
+        &self,
+        updates: &[(ReportStatus, ReportStatusUpdate)],
+    ) -> Result<(), Self::Error> {
+        tracing::info!(
+            "Changing the status of {} reports in DynamoDB",
+            updates.len()
+        );
+
+        let mut request = self.transact_write_items();
+        for (current_status, update) in updates {
+            let (expression, mut values) = status_update_expression(update);
+            values.insert(
+                ":current_status".to_owned(),
+                status_attribute(*current_status),
+            );
+
+            let update = Update::builder()
+                .table_name(TABLE_NAME)
+                .key("report_id", AttributeValue::S(update.id.to_string()))
+                .update_expression(expression)
+                .condition_expression("#s = :current_status")
+                .expression_attribute_names("#s", "status")
+                .set_expression_attribute_values(Some(values))
+                .build()
+                .expect("table_name, key and update_expression are set");
+
+            request = request.transact_items(TransactWriteItem::builder().update(update).build());
+        }
+
+        let _ = request.send().await?;
+        Ok(())
+    }
+
+    async fn update_report_progress(
+        &self,
+        progress: &ReportProgress,
+    ) -> Result<Option<Uuid>, Self::Error> {
+        tracing::info!(
+            "Changing the progress of report {:?} in DynamoDB to {}%",
+            progress.id,
+            progress.progress,
+        );
+
         let request = self
             .update_item()
             .table_name(TABLE_NAME.to_owned())
-            .key("report_id", AttributeValue::S(update.id.to_string()))
+            .key("report_id", AttributeValue::S(progress.id.to_string()))
             .expression_attribute_values(
-                ":report_status",
-                AttributeValue::S(update.status.as_str().to_owned()),
+                ":progress",
+                AttributeValue::N(progress.progress.to_string()),
             )
-            .expression_attribute_names("#s", "status")
-            .update_expression("set #s = :report_status")
+            .expression_attribute_values(":updated_at", timestamp_attribute(Utc::now()))
+            .update_expression("set progress = :progress, updated_at = :updated_at")
+            // Without the condition an update for an unknown report would create a new item
+            .condition_expression("attribute_exists(report_id)")
             .return_values(ReturnValue::AllOld);
 
-        let response = request.send().await?;
-        let value = response
-            .attributes
-            .expect("we asked for values to be returned");
-
-        // TODO(ytmimi) we should have better error handling here
-        // instead of calling .expect
-        let user_id = value
-            .get("user_id")
-            .expect("table has a user_id column")
-            .as_s()
-            .expect("username stored as a string");
-
-        Ok(Some(
-            Uuid::from_str(user_id).expect("user_id is a valid uuid"),
-        ))
+        let response = send_unless_condition_failed(request).await?;
+        Ok(response.and_then(|response| user_id_from_old_values(response.attributes)))
     }
 
     async fn get_report_status(
@@ -145,35 +538,379 @@ impl Database for DynamoDB {
 
         Ok(ReportStatus::from_str(status).ok())
     }
+
+    async fn list_stale_reports(
+        &self,
+        status: ReportStatus,
+        changed_before: DateTime<Utc>,
+    ) -> Result<Vec<Report>, Self::Error> {
+        let request = self
+            .query()
+            .table_name(TABLE_NAME)
+            .index_name(STATUS_INDEX)
+            .key_condition_expression("#s = :status AND status_changed_at < :changed_before")
+            .filter_expression("attribute_not_exists(lease_expires_at) OR lease_expires_at < :now")
+            .expression_attribute_names("#s", "status")
+            .expression_attribute_values(":status", AttributeValue::S(status.as_str().to_owned()))
+            .expression_attribute_values(":changed_before", timestamp_attribute(changed_before))
+            .expression_attribute_values(":now", timestamp_attribute(Utc::now()));
+
+        let mut paginator = request.into_paginator().page_size(100).send();
+
+        let mut reports = vec![];
+        while let Some(page) = paginator.next().await {
+            reports.extend(page?.items().iter().filter_map(report_from_item));
+        }
+        Ok(reports)
+    }
+
+    async fn claim_report(&self, lease: &Lease) -> Result<Option<Report>, Self::Error> {
+        // The status index is sorted by when the status changed, so the oldest queued reports
+        // come first. Only fetch a few at a time, since the first one is usually claimed
+        let request = self
+            .query()
+            .table_name(TABLE_NAME)
+            .index_name(STATUS_INDEX)
+            .key_condition_expression("#s = :queued")
+            .expression_attribute_names("#s", "status")
+            .expression_attribute_values(":queued", status_attribute(ReportStatus::Queued));
+
+        let mut pages = request.into_paginator().page_size(10).send();
+        while let Some(page) = pages.next().await {
+            for report in page?.items().iter().filter_map(report_from_item) {
+                if let Some(claimed) = claim_queued_report(self, &report, lease).await? {
+                    return Ok(Some(claimed));
+                }
+            }
+        }
+
+        Ok(None)
+    }
+
+    async fn renew_lease(&self, report_id: Uuid, lease: &Lease) -> Result<bool, Self::Error> {
+        let request = self
+            .update_item()
+            .table_name(TABLE_NAME)
+            .key("report_id", AttributeValue::S(report_id.to_string()))
+            .condition_expression("#s = :processing AND lease_token = :token")
+            .update_expression("set lease_expires_at = :expires_at")
+            .expression_attribute_names("#s", "status")
+            .expression_attribute_values(":processing", status_attribute(ReportStatus::Processing))
+            .expression_attribute_values(":token", AttributeValue::S(lease.token.to_string()))
+            .expression_attribute_values(":expires_at", timestamp_attribute(lease.expires_at));
+
+        Ok(send_unless_condition_failed(request).await?.is_some())
+    }
+
+    async fn release_lease(
+        &self,
+        lease_token: Uuid,
+        update: &ReportStatusUpdate,
+    ) -> Result<Option<Uuid>, Self::Error> {
+        tracing::info!(
+            "releasing the lease on report {:?} in DynamoDB with status {:?}",
+            update.id,
+            update.status,
+        );
+
+        // Extends the condition from `status_update_request`
+        let request = status_update_request(self, ReportStatus::Processing, update)
+            .condition_expression("#s = :current_status AND lease_token = :token")
+            .expression_attribute_values(":token", AttributeValue::S(lease_token.to_string()));
+
+        let response = send_unless_condition_failed(request).await?;
+        Ok(response.and_then(|response| user_id_from_old_values(response.attributes)))
+    }
+
+    async fn list_expired_leases(
+        &self,
+        expired_before: DateTime<Utc>,
+    ) -> Result<Vec<(Uuid, Lease)>, Self::Error> {
+        let request = self
+            .query()
+            .table_name(TABLE_NAME)
+            .index_name(STATUS_INDEX)
+            .key_condition_expression("#s = :processing")
+            .filter_expression("lease_expires_at < :expired_before")
+            .expression_attribute_names("#s", "status")
+            .expression_attribute_values(":processing", status_attribute(ReportStatus::Processing))
+            .expression_attribute_values(":expired_before", timestamp_attribute(expired_before))
+            .projection_expression("report_id, lease_token, lease_expires_at");
+
+        let mut paginator = request.into_paginator().page_size(100).send();
+
+        let mut leases = vec![];
+        while let Some(page) = paginator.next().await {
+            leases.extend(page?.items().iter().filter_map(|item| {
+                let string = |name: &str| item.get(name).and_then(|value| value.as_s().ok());
+                let report_id = Uuid::from_str(string("report_id")?).ok()?;
+                let lease = Lease {
+                    token: Uuid::from_str(string("lease_token")?).ok()?,
+                    expires_at: DateTime::parse_from_rfc3339(string("lease_expires_at")?)
+                        .ok()?
+                        .with_timezone(&Utc),
+                };
+                Some((report_id, lease))
+            }));
+        }
+        Ok(leases)
+    }
+
+    async fn get_report(&self, report_id: Uuid) -> Result<Option<Report>, Self::Error> {
+        let request = self
+            .get_item()
+            .table_name(TABLE_NAME.to_owned())
+            .key("report_id", AttributeValue::S(report_id.to_string()));
+
+        let response = request.send().await?;
+        Ok(response.item().and_then(report_from_item))
+    }
+
+    async fn delete_report(&self, report_id: Uuid) -> Result<(), Self::Error> {
+        tracing::info!("deleting report {report_id} from DynamoDB");
+
+        let request = self
+            .delete_item()
+            .table_name(TABLE_NAME.to_owned())
+            .key("report_id", AttributeValue::S(report_id.to_string()));
+
+        let _ = request.send().await?;
+        Ok(())
+    }
+
+    async fn set_report_archived(
+        &self,
+        report_id: Uuid,
+        archived: bool,
+    ) -> Result<(), Self::Error> {
+        tracing::info!("setting archived={archived} for report {report_id} in DynamoDB");
+
+        let request = self
+            .update_item()
+            .table_name(TABLE_NAME.to_owned())
+            .key("report_id", AttributeValue::S(report_id.to_string()))
+            .expression_attribute_values(":archived", AttributeValue::Bool(archived))
+            .expression_attribute_values(":updated_at", timestamp_attribute(Utc::now()))
+            .update_expression("set archived = :archived, updated_at = :updated_at")
+            // The report might have been deleted since the caller looked it up
+            .condition_expression("attribute_exists(report_id)");
+
+        let _ = send_unless_condition_failed(request).await?;
+        Ok(())
+    }
+}
+
+type Item = HashMap<String, AttributeValue>;
+
+/// Move a single report from `queued` to `processing` under `lease`.
+/// Other workers are claiming reports at the same time, so this returns `None` if we lost the race
+async fn claim_queued_report(
+    client: &DynamoDB,
+    report: &Report,
+    lease: &Lease,
+) -> Result<Option<Report>, aws_sdk_dynamodb::Error> {
+    let request = client
+        .update_item()
+        .table_name(TABLE_NAME)
+        .key("report_id", AttributeValue::S(report.report_id.to_string()))
+        .condition_expression("#s = :queued")
+        .update_expression(
+            "set #s = :processing, updated_at = :now, status_changed_at = :now, \
+            lease_token = :token, lease_expires_at = :expires_at \
+            remove status_reason",
+        )
+        .expression_attribute_names("#s", "status")
+        .expression_attribute_values(":queued", status_attribute(ReportStatus::Queued))
+        .expression_attribute_values(":processing", status_attribute(ReportStatus::Processing))
+        .expression_attribute_values(":now", timestamp_attribute(Utc::now()))
+        .expression_attribute_values(":token", AttributeValue::S(lease.token.to_string()))
+        .expression_attribute_values(":expires_at", timestamp_attribute(lease.expires_at))
+        .return_values(ReturnValue::AllNew);
+
+    let Some(response) = send_unless_condition_failed(request).await? else {
+        return Ok(None);
+    };
+    tracing::info!("claimed report {} in DynamoDB", report.report_id);
+    Ok(response.attributes.as_ref().and_then(report_from_item))
+}
+
+/// The update expression and values that write a status update.
+/// Leases only last as long as the `processing` status they were handed out with, so any lease on
+/// the report is dropped. The expression refers to the `status` attribute as `#s`
+fn status_update_expression(update: &ReportStatusUpdate) -> (String, Item) {
+    let mut values = HashMap::from([
+        (":report_status".to_owned(), status_attribute(update.status)),
+        (":now".to_owned(), timestamp_attribute(Utc::now())),
+    ]);
+
+    let set_status = "set #s = :report_status, updated_at = :now, status_changed_at = :now";
+    let remove_lease = "lease_token, lease_expires_at";
+    let expression = match &update.reason {
+        Some(reason) => {
+            values.insert(":reason".to_owned(), AttributeValue::S(reason.clone()));
+            format!("{set_status}, status_reason = :reason remove {remove_lease}")
+        }
+        // The reason only applies to the status it was given with
+        None => format!("{set_status} remove status_reason, {remove_lease}"),
+    };
+
+    (expression, values)
 }
 
-struct PartialReports(Vec<ReportStatusUpdate>);
+/// Build the request that writes a status update.
+/// The update only applies if the report still has `current_status`. Deleted reports don't have
+/// a status, so a late update can't bring one back
+fn status_update_request(
+    client: &DynamoDB,
+    current_status: ReportStatus,
+    update: &ReportStatusUpdate,
+) -> UpdateItemFluentBuilder {
+    let (expression, mut values) = status_update_expression(update);
+    values.insert(
+        ":current_status".to_owned(),
+        status_attribute(current_status),
+    );
+    client
+        .update_item()
+        .table_name(TABLE_NAME.to_owned())
+        .key("report_id", AttributeValue::S(update.id.to_string()))
+        .update_expression(expression)
+        .condition_expression("#s = :current_status")
+        .set_expression_attribute_values(Some(values))
+        .expression_attribute_names("#s", "status")
+        .return_values(ReturnValue::AllOld)
+}
 
-impl PartialReports {
-    fn into_reports(self, user_id: uuid::Uuid) -> Vec<Report> {
-        self.0
-            .into_iter()
-            .map(|report_status| report_status.into_report(user_id))
-            .collect()
+/// Send a conditional update, treating a failed condition as an expected outcome rather than an error
+async fn send_unless_condition_failed(
+    request: UpdateItemFluentBuilder,
+) -> Result<Option<UpdateItemOutput>, aws_sdk_dynamodb::Error> {
+    match request.send().await {
+        Ok(output) => Ok(Some(output)),
+        Err(SdkError::ServiceError(err)) if err.err().is_conditional_check_failed_exception() => {
+            Ok(None)
+        }
+        Err(err) => Err(err.into()),
     }
 }
 
-impl TryFrom<QueryOutput> for PartialReports {
-    type Error = String;
-    fn try_from(value: QueryOutput) -> Result<Self, Self::Error> {
-        let reports = value.items.unwrap_or_default();
-        let reports = reports
-            .into_iter()
-            .filter_map(|output| {
-                let report_id = output.get("report_id")?.as_s().ok()?;
-                let status = output.get("status")?.as_s().ok()?;
-                Some(ReportStatusUpdate {
-                    id: uuid::Uuid::from_str(report_id).ok()?,
-                    status: ReportStatus::from_str(status).ok()?,
-                })
-            })
-            .collect::<Vec<_>>();
+fn status_attribute(status: ReportStatus) -> AttributeValue {
+    AttributeValue::S(status.as_str().to_owned())
+}
 
-        Ok(PartialReports(reports))
+/// Timestamps are always stored with the same precision and timezone
+/// so that they can be compared as strings in filter expressions
+fn timestamp_attribute(timestamp: DateTime<Utc>) -> AttributeValue {
+    AttributeValue::S(timestamp.to_rfc3339_opts(SecondsFormat::Millis, true))
+}
+
+fn report_to_item(report: &Report) -> Item {
+    let mut item = HashMap::from([
+        (
+            "report_id".to_owned(),
+            AttributeValue::S(report.report_id.to_string()),
+        ),
+        (
+            "user_id".to_owned(),
+            AttributeValue::S(report.user_id.to_string()),
+        ),
+        (
+            "status".to_owned(),
+            AttributeValue::S(report.report_status.as_str().to_owned()),
+        ),
+        ("name".to_owned(), AttributeValue::S(report.name.clone())),
+        (
+            "parameters".to_owned(),
+            AttributeValue::S(serde_json::Value::Object(report.parameters.clone()).to_string()),
+        ),
+        (
+            "created_at".to_owned(),
+            timestamp_attribute(report.created_at),
+        ),
+        (
+            "updated_at".to_owned(),
+            timestamp_attribute(report.updated_at),
+        ),
+        (
+            "status_changed_at".to_owned(),
+            timestamp_attribute(report.status_changed_at),
+        ),
+    ]);
+
+    if let Some(reason) = &report.status_reason {
+        item.insert(
+            "status_reason".to_owned(),
+            AttributeValue::S(reason.clone()),
+        );
     }
+
+    if let Some(progress) = report.progress {
+        item.insert(
+            "progress".to_owned(),
+            AttributeValue::N(progress.to_string()),
+        );
+    }
+
+    item
+}
+
+fn report_from_item(item: &Item) -> Option<Report> {
+    let string = |name: &str| item.get(name).and_then(|value| value.as_s().ok());
+    let timestamp = |name: &str| {
+        string(name)
+            .and_then(|value| DateTime::parse_from_rfc3339(value).ok())
+            .map(|value| value.with_timezone(&Utc))
+    };
+    // Reports created before we started tracking timestamps don't have them
+    let created_at = timestamp("created_at").unwrap_or(DateTime::<Utc>::UNIX_EPOCH);
+    let updated_at = timestamp("updated_at").unwrap_or(created_at);
+
+    Some(Report {
+        user_id: Uuid::from_str(string("user_id")?).ok()?,
+        report_id: Uuid::from_str(string("report_id")?).ok()?,
+        report_status: ReportStatus::from_str(string("status")?).ok()?,
+        name: string("name").cloned().unwrap_or_default(),
+        parameters: string("parameters")
+            .and_then(|value| serde_json::from_str(value).ok())
+            .unwrap_or_default(),
+        created_at,
+        updated_at,
+        status_changed_at: timestamp("status_changed_at").unwrap_or(updated_at),
+        status_reason: string("status_reason").cloned(),
+        progress: item
+            .get("progress")
+            .and_then(|value| value.as_n().ok())
+            .and_then(|value| value.parse().ok()),
+        archived: item
+            .get("archived")
+            .and_then(|value| value.as_bool().ok())
+            .copied()
+            .unwrap_or(false),
+    })
+}
+
+/// The cursor must come from [PageCursor::decode_for_user], so that it only holds the keys of the
+/// user id index
+fn cursor_to_key(cursor: &PageCursor) -> Item {
+    cursor
+        .0
+        .iter()
+        .map(|(name, value)| (name.clone(), AttributeValue::S(value.clone())))
+        .collect()
+}
+
+/// All of the keys on our table and indexes are strings, so that's all we need to store
+fn key_to_cursor(key: Item) -> PageCursor {
+    let key = key
+        .into_iter()
+        .filter_map(|(name, value)| Some((name, value.as_s().ok()?.clone())))
+        .collect();
+    PageCursor(key)
+}
+
+/// Get the `user_id` from the `ReturnValue::AllOld` values returned by an update
+fn user_id_from_old_values(values: Option<Item>) -> Option<Uuid> {
+    let values = values?;
+    let user_id = values.get("user_id")?.as_s().ok()?;
+    Uuid::from_str(user_id).ok()
 }
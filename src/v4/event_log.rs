@@ -0,0 +1,145 @@
+//! Recent events sent to each user.
+//!
+//! Every message sent to a user's connections is logged with an id first. Clients that lost
+//! their connection can pass the last id they saw to catch up on what they missed, either with
+//! the SSE `Last-Event-ID` header or through the long-polling endpoint.
+use lru::LruCache;
+use std::collections::VecDeque;
+use std::num::NonZeroUsize;
+use tokio::sync::oneshot;
+use uuid::Uuid;
+
+use super::app_events::ServerSentEventMessage;
+
+/// How many users' logs are kept in memory
+const MAX_USERS: usize = 1000;
+
+/// A message along with the id it was logged under
+#[derive(Debug, Clone)]
+pub(crate) struct LoggedEvent {
+    pub(super) id: u64,
+    pub(super) message: ServerSentEventMessage,
+}
+
+#[derive(Debug, Default)]
+struct UserLog {
+    events: VecDeque<LoggedEvent>,
+    /// Long-polling requests waiting for the user's next event
+    waiters: Vec<oneshot::Sender<Vec<LoggedEvent>>>,
+}
+
+pub(super) struct EventLog {
+    users: LruCache<Uuid, UserLog>,
+    max_events: usize,
+    next_id: u64,
+}
+
+impl EventLog {
+    pub(super) fn new(max_events: usize) -> Self {
+        Self {
+            users: LruCache::new(NonZeroUsize::new(MAX_USERS).expect("value is > 0")),
+            max_events,
+            // Ids start at the current time so that they keep increasing across restarts.
+            // Otherwise clients reconnecting with an id from before the restart would miss events
+            next_id: chrono::Utc::now().timestamp_millis().max(0) as u64,
+        }
+    }
+
+    /// Log a message for the user and hand it to any long-polling requests
+    pub(super) fn push(&mut self, user_id: Uuid, message: ServerSentEventMessage) -> LoggedEvent {
+        self.next_id += 1;
+        let event = LoggedEvent {
+            id: self.next_id,
+            message,
+        };
+
+        let log = self.users.get_or_insert_mut(user_id, UserLog::default);
+        if log.events.len() == self.max_events {
+            log.events.pop_front();
+        }
+        log.events.push_back(event.clone());
+
+        for waiter in log.waiters.drain(..) {
+            let _ = waiter.send(vec![event.clone()]);
+        }
+
+        event
+    }
+
+    /// Events logged for the user after `since`, or every logged event when `since` isn't given
+    pub(super) fn events_since(&mut self, user_id: Uuid, since: Option<u64>) -> Vec<LoggedEvent> {
+        let Some(log) = self.users.get(&user_id) else {
+            return vec![];
+        };
+        log.events
+            .iter()
+            .filter(|event| since.is_none_or(|since| event.id > since))
+            .cloned()
+            .collect()
+    }
+
+    /// Reply with the events logged after `since`, or wait for the user's next event if there
+    /// aren't any
+    pub(super) fn poll(
+        &mut self,
+        user_id: Uuid,
+        since: Option<u64>,
+        reply: oneshot::Sender<Vec<LoggedEvent>>,
+    ) {
+        let events = self.events_since(user_id, since);
+        if !events.is_empty() {
+            let _ = reply.send(events);
+            return;
+        }
+
+        let log = self.users.get_or_insert_mut(user_id, UserLog::default);
+        // Forget requests that already timed out
+        log.waiters.retain(|waiter| !waiter.is_closed());
+        log.waiters.push(reply);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::v4::app_events::ReportDeleted;
+
+    fn deleted(id: Uuid) -> ServerSentEventMessage {
+        ServerSentEventMessage::ReportDeleted(ReportDeleted { id })
+    }
+
+    #[test]
+    fn test_events_since() {
+        let mut log = EventLog::new(2);
+        let user_id = Uuid::new_v4();
+
+        let first = log.push(user_id, deleted(Uuid::new_v4()));
+        let second = log.push(user_id, deleted(Uuid::new_v4()));
+        log.push(Uuid::new_v4(), deleted(Uuid::new_v4()));
+        assert!(second.id > first.id);
+
+        let ids = |events: Vec<LoggedEvent>| events.iter().map(|e| e.id).collect::<Vec<_>>();
+        assert_eq!(ids(log.events_since(user_id, None)), [first.id, second.id]);
+        assert_eq!(ids(log.events_since(user_id, Some(first.id))), [second.id]);
+
+        // The oldest event is dropped once the log is full
+        let third = log.push(user_id, deleted(Uuid::new_v4()));
+        assert_eq!(ids(log.events_since(user_id, None)), [second.id, third.id]);
+    }
+
+    #[tokio::test]
+    async fn test_poll_waits_for_the_next_event() {
+        let mut log = EventLog::new(10);
+        let user_id = Uuid::new_v4();
+        let seen = log.push(user_id, deleted(Uuid::new_v4()));
+
+        let (reply, mut events) = oneshot::channel();
+        log.poll(user_id, Some(seen.id), reply);
+        assert!(events.try_recv().is_err());
+
+        let next = log.push(user_id, deleted(Uuid::new_v4()));
+        let events = events.await.unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].id, next.id);
+    }
+}
@@ -0,0 +1,413 @@
+//! Connection caps and rate limits, so that a single client can't overwhelm the app.
+//!
+//! Streaming connections and long polls hold a [ConnectionGuard] for as long as they're open. The
+//! endpoints that change reports use the [RateLimited] extractor, which takes a token from the
+//! user's bucket or rejects the request with `429 Too Many Requests`.
+use axum::async_trait;
+use axum::extract::{FromRequestParts, State};
+use axum::http::request::Parts;
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+use super::admin::authorize_admin;
+use super::V4AppState;
+use crate::auth::UserId;
+use crate::config::LimitsConfig;
+
+/// Forget the buckets of users who haven't made a request in a while once there are this many
+const MAX_IDLE_BUCKETS: usize = 10_000;
+
+#[derive(Debug, Default)]
+struct Counters {
+    connection_rejected_user: AtomicU64,
+    connection_rejected_global: AtomicU64,
+    rate_limited: AtomicU64,
+}
+
+#[derive(Debug, Default)]
+struct Connections {
+    total: usize,
+    per_user: HashMap<Uuid, usize>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+#[derive(Debug)]
+pub(super) struct Limits {
+    config: LimitsConfig,
+    connections: Mutex<Connections>,
+    buckets: Mutex<HashMap<Uuid, Bucket>>,
+    counters: Counters,
+}
+
+#[derive(Debug)]
+pub(super) enum LimitError {
+    TooManyUserConnections(usize),
+    TooManyConnections,
+    RateLimited { retry_after: Duration },
+}
+
+impl LimitError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            LimitError::TooManyUserConnections(_) | LimitError::RateLimited { .. } => {
+                StatusCode::TOO_MANY_REQUESTS
+            }
+            LimitError::TooManyConnections => StatusCode::SERVICE_UNAVAILABLE,
+        }
+    }
+}
+
+impl std::fmt::Display for LimitError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LimitError::TooManyUserConnections(limit) => {
+                write!(f, "at most {limit} connections can be open at once")
+            }
+            LimitError::TooManyConnections => {
+                f.write_str("the server can't accept any more connections")
+            }
+            LimitError::RateLimited { .. } => f.write_str("too many requests"),
+        }
+    }
+}
+
+impl IntoResponse for LimitError {
+    fn into_response(self) -> Response {
+        let mut response = (self.status_code(), self.to_string()).into_response();
+        if let LimitError::RateLimited { retry_after } = self {
+            // Round up so that clients don't retry a moment too early
+            let seconds = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+            response
+                .headers_mut()
+                .insert(header::RETRY_AFTER, seconds.into());
+        }
+        response
+    }
+}
+
+/// Lets handlers that fail with a `(StatusCode, String)` use `?`
+impl From<LimitError> for (StatusCode, String) {
+    fn from(err: LimitError) -> Self {
+        (err.status_code(), err.to_string())
+    }
+}
+
+/// Keeps a connection counted until it's dropped
+pub(super) struct ConnectionGuard {
+    limits: Arc<Limits>,
+    user_id: Uuid,
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        let mut connections = lock(&self.limits.connections);
+        connections.total -= 1;
+        if let Some(count) = connections.per_user.get_mut(&self.user_id) {
+            *count -= 1;
+            if *count == 0 {
+                connections.per_user.remove(&self.user_id);
+            }
+        }
+    }
+}
+
+/// A poisoned lock only means another request panicked while counting, so keep going
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+impl Limits {
+    pub(super) fn new(config: LimitsConfig) -> Self {
+        Self {
+            config,
+            connections: Mutex::default(),
+            buckets: Mutex::default(),
+            counters: Counters::default(),
+        }
+    }
+
+    /// Count a new streaming connection for the user, unless they or the app are at the limit
+    pub(super) fn open_connection(
+        self: &Arc<Self>,
+        user_id: Uuid,
+    ) -> Result<ConnectionGuard, LimitError> {
+        let mut connections = lock(&self.connections);
+
+        if self
+            .config
+            .max_connections
+            .is_some_and(|max| connections.total >= max)
+        {
+            self.counters
+                .connection_rejected_global
+                .fetch_add(1, Ordering::Relaxed);
+            return Err(LimitError::TooManyConnections);
+        }
+
+        let user_connections = connections.per_user.get(&user_id).copied().unwrap_or(0);
+        if let Some(max) = self
+            .config
+            .max_connections_per_user
+            .filter(|max| user_connections >= *max)
+        {
+            self.counters
+                .connection_rejected_user
+                .fetch_add(1, Ordering::Relaxed);
+            return Err(LimitError::TooManyUserConnections(max));
+        }
+
+        connections.total += 1;
+        *connections.per_user.entry(user_id).or_default() += 1;
+        Ok(ConnectionGuard {
+            limits: self.clone(),
+            user_id,
+        })
+    }
+
+    /// Take a token from the user's bucket
+    pub(super) fn check_rate(&self, user_id: Uuid) -> Result<(), LimitError> {
+        self.check_rate_at(user_id, Instant::now())
+    }
+
+    fn check_rate_at(&self, user_id: Uuid, now: Instant) -> Result<(), LimitError> {
+        let Some(requests_per_minute) = self.config.requests_per_minute else {
+            return Ok(());
+        };
+        let burst = f64::from(self.config.burst);
+        let tokens_per_second = f64::from(requests_per_minute) / 60.0;
+
+        let mut buckets = lock(&self.buckets);
+        if buckets.len() >= MAX_IDLE_BUCKETS {
+            // Full buckets belong to users who haven't been limited recently. Starting them
+            // over with a new bucket is the same thing
+            buckets.retain(|_, bucket| {
+                let elapsed = now.saturating_duration_since(bucket.refilled_at);
+                bucket.tokens + elapsed.as_secs_f64() * tokens_per_second < burst
+            });
+        }
+
+        let bucket = buckets.entry(user_id).or_insert(Bucket {
+            tokens: burst,
+            refilled_at: now,
+        });
+        let elapsed = now.saturating_duration_since(bucket.refilled_at);
+        bucket.tokens = (bucket.tokens + elapsed.as_secs_f64() * tokens_per_second).min(burst);
+        bucket.refilled_at = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return Ok(());
+        }
+
+        self.counters.rate_limited.fetch_add(1, Ordering::Relaxed);
+        let retry_after = (1.0 - bucket.tokens) / tokens_per_second;
+        Err(LimitError::RateLimited {
+            retry_after: Duration::from_secs_f64(retry_after),
+        })
+    }
+
+    /// Limits and rejections in the Prometheus text format
+    pub(super) fn metrics(&self) -> String {
+        let connections = {
+            let connections = lock(&self.connections);
+            (connections.total, connections.per_user.len())
+        };
+        let counter = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        let limit = |limit: Option<usize>| limit.map_or(-1, |limit| limit as i64);
+
+        let mut metrics = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, samples: &[(&str, i64)]| {
+            let _ = writeln!(metrics, "# HELP {name} {help}");
+            let _ = writeln!(metrics, "# TYPE {name} {kind}");
+            for (labels, value) in samples {
+                let _ = writeln!(metrics, "{name}{labels} {value}");
+            }
+        };
+
+        metric(
+            "sse_open_connections",
+            "gauge",
+            "Open SSE and WebSocket connections",
+            &[("", connections.0 as i64)],
+        );
+        metric(
+            "sse_connected_users",
+            "gauge",
+            "Users with at least one open connection",
+            &[("", connections.1 as i64)],
+        );
+        metric(
+            "sse_connection_limit",
+            "gauge",
+            "Most connections that can be open at once. -1 when unlimited",
+            &[
+                (r#"{scope="global"}"#, limit(self.config.max_connections)),
+                (
+                    r#"{scope="user"}"#,
+                    limit(self.config.max_connections_per_user),
+                ),
+            ],
+        );
+        metric(
+            "sse_connections_rejected_total",
+            "counter",
+            "Connections rejected because a limit was reached",
+            &[
+                (
+                    r#"{scope="global"}"#,
+                    counter(&self.counters.connection_rejected_global) as i64,
+                ),
+                (
+                    r#"{scope="user"}"#,
+                    counter(&self.counters.connection_rejected_user) as i64,
+                ),
+            ],
+        );
+        metric(
+            "sse_rate_limit_requests_per_minute",
+            "gauge",
+            "Steady rate of requests each user can make. -1 when unlimited",
+            &[(
+                "",
+                self.config
+                    .requests_per_minute
+                    .map_or(-1, |rate| rate.into()),
+            )],
+        );
+        metric(
+            "sse_rate_limit_burst",
+            "gauge",
+            "Requests each user can make at once",
+            &[("", self.config.burst.into())],
+        );
+        metric(
+            "sse_rate_limited_total",
+            "counter",
+            "Requests rejected by the rate limit",
+            &[("", counter(&self.counters.rate_limited) as i64)],
+        );
+
+        metrics
+    }
+}
+
+/// The user making a request to an endpoint that changes reports, after checking that they
+/// haven't made too many requests
+#[derive(Debug)]
+pub(super) struct RateLimited(pub(super) Uuid);
+
+#[async_trait]
+impl<D: Send + Sync> FromRequestParts<V4AppState<D>> for RateLimited {
+    type Rejection = Response;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &V4AppState<D>,
+    ) -> Result<Self, Self::Rejection> {
+        let UserId(user_id) = UserId::from_request_parts(parts, state)
+            .await
+            .map_err(IntoResponse::into_response)?;
+        state
+            .limits
+            .check_rate(user_id)
+            .map_err(IntoResponse::into_response)?;
+        Ok(RateLimited(user_id))
+    }
+}
+
+/// Limits and rejections in the Prometheus text format. Requires the admin token
+pub(super) async fn metrics_handler<D>(
+    State(state): State<V4AppState<D>>,
+    headers: HeaderMap,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    authorize_admin(state.config.admin_token.as_deref(), &headers, None)?;
+    Ok((
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        state.limits.metrics(),
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn limits(config: LimitsConfig) -> Arc<Limits> {
+        Arc::new(Limits::new(config))
+    }
+
+    #[test]
+    fn test_connection_limits() {
+        let limits = limits(LimitsConfig {
+            max_connections_per_user: Some(2),
+            max_connections: Some(3),
+            ..Default::default()
+        });
+        let (alice, bob) = (Uuid::new_v4(), Uuid::new_v4());
+
+        let first = limits.open_connection(alice).unwrap();
+        let _second = limits.open_connection(alice).unwrap();
+        assert!(matches!(
+            limits.open_connection(alice),
+            Err(LimitError::TooManyUserConnections(2))
+        ));
+
+        let _third = limits.open_connection(bob).unwrap();
+        assert!(matches!(
+            limits.open_connection(bob),
+            Err(LimitError::TooManyConnections)
+        ));
+
+        // Closing a connection makes room for another one
+        drop(first);
+        let _fourth = limits.open_connection(alice).unwrap();
+
+        let metrics = limits.metrics();
+        assert!(metrics.contains("sse_open_connections 3\n"), "{metrics}");
+        assert!(
+            metrics.contains("sse_connections_rejected_total{scope=\"user\"} 1\n"),
+            "{metrics}"
+        );
+    }
+
+    #[test]
+    fn test_rate_limit_refills() {
+        let limits = limits(LimitsConfig {
+            requests_per_minute: Some(60),
+            burst: 2,
+            ..Default::default()
+        });
+        let user = Uuid::new_v4();
+        let now = Instant::now();
+
+        assert!(limits.check_rate_at(user, now).is_ok());
+        assert!(limits.check_rate_at(user, now).is_ok());
+        let Err(LimitError::RateLimited { retry_after }) = limits.check_rate_at(user, now) else {
+            panic!("the burst should be used up");
+        };
+        assert_eq!(retry_after, Duration::from_secs(1));
+
+        // One token comes back every second
+        assert!(limits
+            .check_rate_at(user, now + Duration::from_secs(1))
+            .is_ok());
+        assert!(limits
+            .check_rate_at(user, now + Duration::from_secs(1))
+            .is_err());
+
+        // Other users have their own bucket
+        assert!(limits.check_rate_at(Uuid::new_v4(), now).is_ok());
+    }
+}
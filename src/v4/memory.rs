@@ -0,0 +1,533 @@
+//! A [Database] that keeps everything in memory.
+//!
+//! Behaves like the DynamoDB backend, so the v4 app can run without DynamoDB, e.g. in tests or
+//! for frontend development. Nothing survives a restart.
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use uuid::Uuid;
+
+use super::app_events::{Report, ReportProgress, ReportStatusUpdate};
+use super::database::{Database, Lease, PageCursor, ReportPage, ReportQuery};
+use super::report_status::ReportStatus;
+
+#[derive(Debug, Clone, Default)]
+pub struct InMemoryDatabase {
+    reports: Arc<Mutex<HashMap<Uuid, StoredReport>>>,
+}
+
+#[derive(Debug)]
+struct StoredReport {
+    report: Report,
+    lease: Option<Lease>,
+}
+
+#[derive(Debug)]
+pub enum InMemoryError {
+    /// A report in a transaction didn't have the expected status, so nothing was written
+    ConditionFailed(Uuid),
+}
+
+impl std::fmt::Display for InMemoryError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            InMemoryError::ConditionFailed(report_id) => {
+                write!(f, "report {report_id} doesn't have the expected status")
+            }
+        }
+    }
+}
+
+impl std::error::Error for InMemoryError {}
+
+impl InMemoryDatabase {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn reports(&self) -> MutexGuard<'_, HashMap<Uuid, StoredReport>> {
+        // A panic while holding the lock can't leave a report half written
+        self.reports
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+/// Write a status update and drop any lease, like the DynamoDB backend does
+fn apply_status_update(stored: &mut StoredReport, update: &ReportStatusUpdate) {
+    let now = Utc::now();
+    stored.report.report_status = update.status;
+    stored.report.updated_at = now;
+    stored.report.status_changed_at = now;
+    stored.report.status_reason = update.reason.clone();
+    stored.lease = None;
+}
+
+#[async_trait]
+impl Database for InMemoryDatabase {
+    type Error = InMemoryError;
+
+    async fn list_reports(
+        &self,
+        user_id: Uuid,
+        query: &ReportQuery,
+    ) -> Result<ReportPage, Self::Error> {
+        let after = query
+            .cursor
+            .as_ref()
+            .and_then(|cursor| cursor.0.get("report_id"))
+            .and_then(|report_id| report_id.parse::<Uuid>().ok());
+
+        let mut reports: Vec<Report> = self
+            .reports()
+            .values()
+            .map(|stored| &stored.report)
+            .filter(|report| report.user_id == user_id)
+            .filter(|report| query.include_archived || !report.archived)
+            .filter(|report| {
+                query.statuses.is_empty() || query.statuses.contains(&report.report_status)
+            })
+            .filter(|report| after.is_none_or(|after| report.report_id > after))
+            .cloned()
+            .collect();
+        reports.sort_by_key(|report| report.report_id);
+
+        // Same keys as DynamoDB's `LastEvaluatedKey` for the user id index
+        let next_cursor = (reports.len() > query.page_size).then(|| {
+            let last = reports[query.page_size - 1].report_id;
+            PageCursor(BTreeMap::from([
+                ("report_id".to_owned(), last.to_string()),
+                ("user_id".to_owned(), user_id.to_string()),
+            ]))
+        });
+        reports.truncate(query.page_size);
+
+        Ok(ReportPage {
+            reports,
+            next_cursor,
+        })
+    }
+
+    async fn insert_report(&self, report: Report) -> Result<(), Self::Error> {
+        let stored = StoredReport {
+            report,
+            lease: None,
+        };
+        self.reports().insert(stored.report.report_id, stored);
+        Ok(())
+    }
+
+    async fn update_report_status(
+        &self,
+        current_status: ReportStatus,
+        update: &ReportStatusUpdate,
+    ) -> Result<Option<Uuid>, Self::Error> {
+        let mut reports = self.reports();
+        let Some(stored) = reports.get_mut(&update.id) else {
+            return Ok(None);
+        };
+        if stored.report.report_status != current_status {
+            return Ok(None);
+        }
+        apply_status_update(stored, update);
+        Ok(Some(stored.report.user_id))
+    }
+
+    async fn update_report_statuses(
+        &self,
+        updates: &[(ReportStatus, ReportStatusUpdate)],
+    ) -> Result<(), Self::Error> {
+        let mut reports = self.reports();
+        for (current_status, update) in updates {
+            let matches = reports
+                .get(&update.id)
+                .is_some_and(|stored| stored.report.report_status == *current_status);
+            if !matches {
+                return Err(InMemoryError::ConditionFailed(update.id));
+            }
+        }
+
+        for (_, update) in updates {
+            if let Some(stored) = reports.get_mut(&update.id) {
+                apply_status_update(stored, update);
+            }
+        }
+        Ok(())
+    }
+
+    async fn update_report_progress(
+        &self,
+        progress: &ReportProgress,
+    ) -> Result<Option<Uuid>, Self::Error> {
+        let mut reports = self.reports();
+        let Some(stored) = reports.get_mut(&progress.id) else {
+            return Ok(None);
+        };
+        stored.report.progress = Some(progress.progress);
+        stored.report.updated_at = Utc::now();
+        Ok(Some(stored.report.user_id))
+    }
+
+    async fn get_report_status(
+        &self,
+        report_id: Uuid,
+    ) -> Result<Option<ReportStatus>, Self::Error> {
+        Ok(self
+            .reports()
+            .get(&report_id)
+            .map(|stored| stored.report.report_status))
+    }
+
+    async fn list_stale_reports(
+        &self,
+        status: ReportStatus,
+        changed_before: DateTime<Utc>,
+    ) -> Result<Vec<Report>, Self::Error> {
+        let now = Utc::now();
+        Ok(self
+            .reports()
+            .values()
+            .filter(|stored| stored.report.report_status == status)
+            .filter(|stored| stored.report.status_changed_at < changed_before)
+            .filter(|stored| {
+                stored
+                    .lease
+                    .as_ref()
+                    .is_none_or(|lease| lease.expires_at < now)
+            })
+            .map(|stored| stored.report.clone())
+            .collect())
+    }
+
+    async fn claim_report(&self, lease: &Lease) -> Result<Option<Report>, Self::Error> {
+        let mut reports = self.reports();
+        let oldest = reports
+            .values_mut()
+            .filter(|stored| stored.report.report_status == ReportStatus::Queued)
+            .min_by_key(|stored| (stored.report.status_changed_at, stored.report.created_at));
+        let Some(stored) = oldest else {
+            return Ok(None);
+        };
+
+        let now = Utc::now();
+        stored.report.report_status = ReportStatus::Processing;
+        stored.report.updated_at = now;
+        stored.report.status_changed_at = now;
+        stored.report.status_reason = None;
+        stored.lease = Some(lease.clone());
+        Ok(Some(stored.report.clone()))
+    }
+
+    async fn renew_lease(&self, report_id: Uuid, lease: &Lease) -> Result<bool, Self::Error> {
+        let mut reports = self.reports();
+        let Some(stored) = reports.get_mut(&report_id) else {
+            return Ok(false);
+        };
+        let held = stored.report.report_status == ReportStatus::Processing
+            && stored
+                .lease
+                .as_ref()
+                .is_some_and(|current| current.token == lease.token);
+        if held {
+            stored.lease = Some(lease.clone());
+        }
+        Ok(held)
+    }
+
+    async fn release_lease(
+        &self,
+        lease_token: Uuid,
+        update: &ReportStatusUpdate,
+    ) -> Result<Option<Uuid>, Self::Error> {
+        let mut reports = self.reports();
+        let Some(stored) = reports.get_mut(&update.id) else {
+            return Ok(None);
+        };
+        let held = stored.report.report_status == ReportStatus::Processing
+            && stored
+                .lease
+                .as_ref()
+                .is_some_and(|lease| lease.token == lease_token);
+        if !held {
+            return Ok(None);
+        }
+        apply_status_update(stored, update);
+        Ok(Some(stored.report.user_id))
+    }
+
+    async fn list_expired_leases(
+        &self,
+        expired_before: DateTime<Utc>,
+    ) -> Result<Vec<(Uuid, Lease)>, Self::Error> {
+        Ok(self
+            .reports()
+            .values()
+            .filter(|stored| stored.report.report_status == ReportStatus::Processing)
+            .filter_map(|stored| {
+                let lease = stored.lease.as_ref()?;
+                (lease.expires_at < expired_before)
+                    .then(|| (stored.report.report_id, lease.clone()))
+            })
+            .collect())
+    }
+
+    async fn get_report(&self, report_id: Uuid) -> Result<Option<Report>, Self::Error> {
+        Ok(self
+            .reports()
+            .get(&report_id)
+            .map(|stored| stored.report.clone()))
+    }
+
+    async fn delete_report(&self, report_id: Uuid) -> Result<(), Self::Error> {
+        self.reports().remove(&report_id);
+        Ok(())
+    }
+
+    async fn set_report_archived(
+        &self,
+        report_id: Uuid,
+        archived: bool,
+    ) -> Result<(), Self::Error> {
+        if let Some(stored) = self.reports().get_mut(&report_id) {
+            stored.report.archived = archived;
+            stored.report.updated_at = Utc::now();
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::Duration;
+
+    use super::*;
+    use crate::v4::app_events::NewReport;
+
+    async fn insert_report(
+        database: &InMemoryDatabase,
+        status: ReportStatus,
+        age: Duration,
+        lease: Option<Lease>,
+    ) -> Report {
+        let mut report = Report::new(
+            Uuid::new_v4(),
+            NewReport::new("stored".to_owned(), Default::default()),
+        );
+        report.report_status = status;
+        report.status_changed_at = Utc::now() - age;
+        database.insert_report(report.clone()).await.unwrap();
+        database.reports().get_mut(&report.report_id).unwrap().lease = lease;
+        report
+    }
+
+    fn lease(expires_in: Duration) -> Lease {
+        Lease {
+            token: Uuid::new_v4(),
+            expires_at: Utc::now() + expires_in,
+        }
+    }
+
+    fn status(database: &InMemoryDatabase, report_id: Uuid) -> (ReportStatus, Option<Lease>) {
+        let reports = database.reports();
+        let stored = &reports[&report_id];
+        (stored.report.report_status, stored.lease.clone())
+    }
+
+    #[tokio::test]
+    async fn test_claim_renew_and_release_leases() {
+        let database = InMemoryDatabase::new();
+        let newer =
+            insert_report(&database, ReportStatus::Queued, Duration::minutes(1), None).await;
+        let older = insert_report(&database, ReportStatus::Queued, Duration::hours(1), None).await;
+        insert_report(&database, ReportStatus::Pending, Duration::days(1), None).await;
+
+        // The report that has been queued the longest is claimed first
+        let first_lease = lease(Duration::minutes(1));
+        let claimed = database.claim_report(&first_lease).await.unwrap().unwrap();
+        assert_eq!(claimed.report_id, older.report_id);
+        assert_eq!(claimed.report_status, ReportStatus::Processing);
+        assert_eq!(
+            status(&database, older.report_id),
+            (ReportStatus::Processing, Some(first_lease.clone()))
+        );
+        let second_lease = lease(Duration::minutes(1));
+        let claimed = database.claim_report(&second_lease).await.unwrap().unwrap();
+        assert_eq!(claimed.report_id, newer.report_id);
+        assert!(database
+            .claim_report(&lease(Duration::minutes(1)))
+            .await
+            .unwrap()
+            .is_none());
+
+        // Only the lease holder can renew or release the lease
+        let stolen = lease(Duration::hours(1));
+        assert!(!database
+            .renew_lease(older.report_id, &stolen)
+            .await
+            .unwrap());
+        assert!(!database.renew_lease(Uuid::new_v4(), &stolen).await.unwrap());
+        let renewed = Lease {
+            token: first_lease.token,
+            expires_at: Utc::now() + Duration::hours(1),
+        };
+        assert!(database
+            .renew_lease(older.report_id, &renewed)
+            .await
+            .unwrap());
+        assert_eq!(status(&database, older.report_id).1, Some(renewed));
+
+        let finished = ReportStatusUpdate::new(older.report_id, ReportStatus::Completed);
+        let released = database.release_lease(second_lease.token, &finished).await;
+        assert_eq!(released.unwrap(), None);
+        let released = database.release_lease(first_lease.token, &finished).await;
+        assert_eq!(released.unwrap(), Some(older.user_id));
+        assert_eq!(
+            status(&database, older.report_id),
+            (ReportStatus::Completed, None)
+        );
+
+        // A released lease can't be used again
+        assert!(!database
+            .renew_lease(older.report_id, &first_lease)
+            .await
+            .unwrap());
+        let released = database.release_lease(first_lease.token, &finished).await;
+        assert_eq!(released.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_list_expired_leases() {
+        let database = InMemoryDatabase::new();
+        let expired = lease(-Duration::minutes(1));
+        let processing = ReportStatus::Processing;
+        let hour = Duration::hours(1);
+        let report = insert_report(&database, processing, hour, Some(expired.clone())).await;
+        insert_report(
+            &database,
+            processing,
+            hour,
+            Some(lease(Duration::minutes(1))),
+        )
+        .await;
+        insert_report(&database, processing, hour, None).await;
+        // Leases on reports that aren't `processing` anymore don't count
+        insert_report(&database, ReportStatus::Queued, hour, Some(expired.clone())).await;
+
+        let leases = database.list_expired_leases(Utc::now()).await.unwrap();
+        assert_eq!(leases, [(report.report_id, expired)]);
+    }
+
+    #[tokio::test]
+    async fn test_stale_reports_skip_live_leases() {
+        let database = InMemoryDatabase::new();
+        let processing = ReportStatus::Processing;
+        let hour = Duration::hours(1);
+        let abandoned = insert_report(&database, processing, hour, None).await;
+        let expired = lease(-Duration::minutes(1));
+        let timed_out = insert_report(&database, processing, hour, Some(expired)).await;
+        // A worker is still heartbeating, so the report isn't stuck however long it takes
+        insert_report(
+            &database,
+            processing,
+            hour,
+            Some(lease(Duration::minutes(1))),
+        )
+        .await;
+        insert_report(&database, processing, Duration::zero(), None).await;
+        insert_report(&database, ReportStatus::Queued, hour, None).await;
+
+        let changed_before = Utc::now() - Duration::minutes(30);
+        let mut stale: Vec<_> = database
+            .list_stale_reports(processing, changed_before)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|report| report.report_id)
+            .collect();
+        stale.sort();
+        let mut expected = vec![abandoned.report_id, timed_out.report_id];
+        expected.sort();
+        assert_eq!(stale, expected);
+    }
+
+    #[tokio::test]
+    async fn test_status_updates_need_the_expected_status() {
+        let database = InMemoryDatabase::new();
+        let leased = lease(Duration::minutes(1));
+        let processing = ReportStatus::Processing;
+        let report = insert_report(&database, processing, Duration::zero(), Some(leased)).await;
+
+        let cancel = ReportStatusUpdate::new(report.report_id, ReportStatus::Canceled);
+        let written = database
+            .update_report_status(ReportStatus::Queued, &cancel)
+            .await;
+        assert_eq!(written.unwrap(), None);
+        assert_eq!(status(&database, report.report_id).0, processing);
+
+        let written = database.update_report_status(processing, &cancel).await;
+        assert_eq!(written.unwrap(), Some(report.user_id));
+        assert_eq!(
+            status(&database, report.report_id),
+            (ReportStatus::Canceled, None)
+        );
+
+        let missing = ReportStatusUpdate::new(Uuid::new_v4(), ReportStatus::Canceled);
+        let written = database.update_report_status(processing, &missing).await;
+        assert_eq!(written.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_bulk_updates_are_all_or_nothing() {
+        let database = InMemoryDatabase::new();
+        let hour = Duration::hours(1);
+        let queued = insert_report(&database, ReportStatus::Queued, hour, None).await;
+        let processing = ReportStatus::Processing;
+        let leased = lease(Duration::minutes(1));
+        let working = insert_report(&database, processing, hour, Some(leased.clone())).await;
+
+        let cancel =
+            |report: &Report| ReportStatusUpdate::new(report.report_id, ReportStatus::Canceled);
+        // The second report isn't `queued`, so neither report is changed
+        let updates = [
+            (ReportStatus::Queued, cancel(&queued)),
+            (ReportStatus::Queued, cancel(&working)),
+        ];
+        let result = database.update_report_statuses(&updates).await;
+        assert!(
+            matches!(result, Err(InMemoryError::ConditionFailed(id)) if id == working.report_id)
+        );
+        assert_eq!(
+            status(&database, queued.report_id),
+            (ReportStatus::Queued, None)
+        );
+        assert_eq!(
+            status(&database, working.report_id),
+            (processing, Some(leased))
+        );
+
+        let missing = ReportStatusUpdate::new(Uuid::new_v4(), ReportStatus::Canceled);
+        let updates = [
+            (ReportStatus::Queued, cancel(&queued)),
+            (ReportStatus::Queued, missing),
+        ];
+        assert!(database.update_report_statuses(&updates).await.is_err());
+        assert_eq!(status(&database, queued.report_id).0, ReportStatus::Queued);
+
+        let updates = [
+            (ReportStatus::Queued, cancel(&queued)),
+            (processing, cancel(&working)),
+        ];
+        database.update_report_statuses(&updates).await.unwrap();
+        assert_eq!(
+            status(&database, queued.report_id),
+            (ReportStatus::Canceled, None)
+        );
+        // Leases go with the status change
+        assert_eq!(
+            status(&database, working.report_id),
+            (ReportStatus::Canceled, None)
+        );
+    }
+}
@@ -0,0 +1,72 @@
+//! Replay connections recorded with `/v4/sse?record=true`.
+//!
+//! Recordings are served as a regular SSE stream with their original timing, or sped up or
+//! slowed down with `speed`, which makes it easy to reproduce exactly what a client saw.
+use axum::extract::{Path, Query, State};
+use axum::http::StatusCode;
+use axum::response::sse::{Event, Sse};
+use futures::stream::Stream;
+use std::convert::Infallible;
+use tokio_stream::StreamExt as _;
+
+use super::V4AppState;
+use crate::auth::UserId;
+use crate::recording::{read_recording, recording_path, replay, RecordingError, Timing};
+
+/// Replaying long recordings much slower than this would overflow the replay's timers
+const MIN_SPEED: f64 = 0.01;
+const MAX_SPEED: f64 = 100.0;
+
+#[derive(Debug, serde::Deserialize)]
+pub(super) struct ReplayParams {
+    /// `2.0` replays twice as fast, `0.5` at half speed. Must be between [MIN_SPEED] and
+    /// [MAX_SPEED]
+    speed: Option<f64>,
+}
+
+pub(super) async fn replay_recording<D>(
+    State(state): State<V4AppState<D>>,
+    Path(name): Path<String>,
+    UserId(user_id): UserId,
+    Query(params): Query<ReplayParams>,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, (StatusCode, String)> {
+    let Some(dir) = &state.config.recordings_dir else {
+        return Err((
+            StatusCode::NOT_FOUND,
+            "recording isn't enabled on this server".to_owned(),
+        ));
+    };
+
+    // Recordings start with the id of the user who was recorded
+    let path = recording_path(dir, &name)
+        .filter(|_| name.starts_with(&user_id.to_string()))
+        .ok_or_else(|| (StatusCode::NOT_FOUND, format!("unknown recording {name:?}")))?;
+
+    let speed = params.speed.unwrap_or(1.0);
+    // `NaN` isn't in any range, so it's rejected too
+    if !(MIN_SPEED..=MAX_SPEED).contains(&speed) {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("`speed` must be between {MIN_SPEED} and {MAX_SPEED}"),
+        ));
+    }
+
+    let recording = match read_recording(&path).await {
+        Ok(recording) => recording,
+        Err(RecordingError::Io(err)) if err.kind() == std::io::ErrorKind::NotFound => {
+            return Err((StatusCode::NOT_FOUND, format!("unknown recording {name:?}")));
+        }
+        Err(err) => {
+            tracing::error!("unable to load recording {}. {err}", path.display());
+            return Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("unable to load recording {name:?}"),
+            ));
+        }
+    };
+
+    tracing::info!("replaying {name} for user {user_id} at {speed}x");
+    let stream =
+        replay(recording, Timing::Recorded { speed }).map(|recorded| Ok(recorded.into_event()));
+    Ok(Sse::new(stream))
+}
@@ -1,12 +1,18 @@
 use std::str::FromStr;
 use uuid::Uuid;
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+use super::state_machine::StateMachine;
+
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, serde::Serialize, serde::Deserialize,
+)]
 #[serde(rename_all = "lowercase")]
 pub enum ReportStatus {
     Pending,
     Queued,
     Processing,
+    /// Not part of the default lifecycle. Custom state machines can use it to pause processing
+    Paused,
     Canceled,
     Failed,
     Completed,
@@ -25,6 +31,13 @@ pub enum ReportStatusError {
         current: ReportStatus,
         next_status: ReportStatus,
     },
+    /// Progress can only be reported while a report is `processing`
+    ProgressWhileNotProcessing(Uuid, ReportStatus),
+    /// The update was meant for a report in another status
+    UnexpectedStatus {
+        expected: ReportStatus,
+        current: ReportStatus,
+    },
 }
 
 impl FromStr for ReportStatus {
@@ -34,6 +47,7 @@ impl FromStr for ReportStatus {
             "pending" | "PENDING" => ReportStatus::Pending,
             "queued" | "QUEUED" => ReportStatus::Queued,
             "processing" | "PROCESSING" => ReportStatus::Processing,
+            "paused" | "PAUSED" => ReportStatus::Paused,
             "canceled" | "CANCELED" => ReportStatus::Canceled,
             "failed" | "FAILED" => ReportStatus::Failed,
             "completed" | "COMPLETED" => ReportStatus::Completed,
@@ -44,43 +58,83 @@ impl FromStr for ReportStatus {
     }
 }
 
+impl std::fmt::Display for ReportStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.pad(self.as_str())
+    }
+}
+
+impl std::fmt::Display for ReportStatusError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ReportStatusError::DatabaseUpdateFailed => {
+                f.write_str("failed to write the new status to the database")
+            }
+            ReportStatusError::ReportNotFound(report_id, _) => {
+                write!(f, "report {report_id} not found")
+            }
+            ReportStatusError::InvalidStatus(status) => write!(f, "invalid status {status:?}"),
+            ReportStatusError::InvalidStatusTransition {
+                current,
+                next_status,
+            } => write!(f, "cannot transition from {current} to {next_status}"),
+            ReportStatusError::ProgressWhileNotProcessing(report_id, current) => write!(
+                f,
+                "can't report progress for report {report_id} while it's {current}"
+            ),
+            ReportStatusError::UnexpectedStatus { expected, current } => {
+                write!(
+                    f,
+                    "expected the report to be {expected}, but it's {current}"
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for ReportStatusError {}
+
 impl ReportStatus {
+    pub const ALL: [ReportStatus; 7] = [
+        ReportStatus::Pending,
+        ReportStatus::Queued,
+        ReportStatus::Processing,
+        ReportStatus::Paused,
+        ReportStatus::Canceled,
+        ReportStatus::Failed,
+        ReportStatus::Completed,
+    ];
+
     pub(crate) fn as_str(&self) -> &'static str {
         match self {
             ReportStatus::Pending => "pending",
             ReportStatus::Queued => "queued",
             ReportStatus::Processing => "processing",
+            ReportStatus::Paused => "paused",
             ReportStatus::Canceled => "canceled",
             ReportStatus::Failed => "failed",
             ReportStatus::Completed => "completed",
         }
     }
-    /// Define the state machine that outlines valid report status transitions
-    pub(super) fn transition(self, next_status: ReportStatus) -> Result<Self, ReportStatusError> {
-        let next_status = match (self, next_status) {
-            // When a report is pending it hasn't been created yet.
-            // It can either be queued to be worked on or canceled by the user
-            (Self::Pending, Self::Queued) => Self::Queued,
-            (Self::Pending, Self::Canceled) => Self::Canceled,
-            // When a report is queued it will be picked up by a worker.
-            // Processing can start or a user can cancel the report before processing begins
-            (Self::Queued, Self::Processing) => Self::Processing,
-            (Self::Queued, Self::Canceled) => Self::Canceled,
-            // Once the report has started it can either fail or succeed
-            (Self::Processing, Self::Failed) => Self::Failed,
-            (Self::Processing, Self::Completed) => Self::Completed,
-            // Allow uers to retry reports that have failed or been canceled
-            (Self::Canceled, Self::Pending) => Self::Pending,
-            (Self::Failed, Self::Pending) => Self::Pending,
-            _ => {
-                return Err(ReportStatusError::InvalidStatusTransition {
-                    current: self,
-                    next_status,
-                })
-            }
-        };
+    /// Check a status change against the default [StateMachine].
+    ///
+    /// The app itself uses the state machine from its configuration, which may allow different
+    /// transitions
+    pub fn transition(self, next_status: ReportStatus) -> Result<Self, ReportStatusError> {
+        StateMachine::builtin().transition(self, next_status)
+    }
+
+    /// Parse a comma separated list of statuses, e.g. `queued,processing`
+    pub(crate) fn parse_list(statuses: &str) -> Result<Vec<Self>, ReportStatusError> {
+        statuses
+            .split(',')
+            .map(|status| ReportStatus::from_str(status.trim()))
+            .collect()
+    }
 
-        Ok(next_status)
+    /// Terminal statuses can't transition to any other status in the default [StateMachine]
+    pub fn is_terminal(self) -> bool {
+        StateMachine::builtin().is_terminal(self)
     }
 }
 
@@ -124,7 +178,7 @@ mod test {
         invalid_status_transition!(ReportStatus::Queued, ReportStatus::Queued);
         valid_status_transition!(ReportStatus::Queued, ReportStatus::Processing);
         valid_status_transition!(ReportStatus::Queued, ReportStatus::Canceled);
-        invalid_status_transition!(ReportStatus::Queued, ReportStatus::Failed);
+        valid_status_transition!(ReportStatus::Queued, ReportStatus::Failed);
         invalid_status_transition!(ReportStatus::Queued, ReportStatus::Completed);
     }
 
@@ -168,4 +222,13 @@ mod test {
         invalid_status_transition!(ReportStatus::Completed, ReportStatus::Failed);
         invalid_status_transition!(ReportStatus::Completed, ReportStatus::Completed);
     }
+
+    #[test]
+    fn test_terminal_statuses() {
+        let terminal = ReportStatus::ALL
+            .into_iter()
+            .filter(|status| status.is_terminal())
+            .collect::<Vec<_>>();
+        assert_eq!(terminal, vec![ReportStatus::Completed]);
+    }
 }
@@ -1,70 +1,376 @@
-use axum::extract::{Json, Query, State};
-use axum::http::StatusCode;
+use axum::extract::{Json, Path, Query, State};
+use axum::http::{HeaderMap, HeaderName, StatusCode};
 use axum::response::sse::{Event, Sse};
+use axum::response::{AppendHeaders, IntoResponse};
 use futures::stream::Stream;
 use std::fmt::Debug;
 use std::{convert::Infallible, time::Duration};
 use tokio::sync::mpsc::{channel, Receiver, Sender};
+use tokio::sync::oneshot;
 use tokio_stream::StreamExt as _;
 use uuid::Uuid;
 
-use super::app_events::{AppEvent, Report, ReportStatusUpdate, ServerSentEventMessage};
-use super::tasks::handle_user_disconnect;
+use super::app_events::{
+    AppEvent, BulkStatusUpdateResult, NewReport, PresenceEvent, PresentUser, Report, ReportList,
+    ReportProgress, ReportStatusUpdate, ServerSentEventMessage, UpdateSource,
+};
+use super::database::{PageCursor, ReportQuery, DEFAULT_PAGE_SIZE, MAX_PAGE_SIZE};
+use super::event_log::LoggedEvent;
+use super::limits::RateLimited;
+use super::report_status::ReportStatus;
+use super::simulator::simulate_report;
+use super::state_machine::StateMachine;
+use super::tasks::handle_disconnect;
 use super::V4AppState;
+use crate::auth::UserId;
+use crate::recording::{recording_path, Recorder};
 
-#[derive(Debug, serde::Deserialize)]
-pub(super) struct QueryParams {
-    user_id: Uuid,
-}
+/// Response header with the name of the connection's recording
+static RECORDING_HEADER: HeaderName = HeaderName::from_static("x-recording");
 
 /// Handles [Server Sent Events]
 ///
 /// Once the connection has been established the server can keep sending data to the client.
+/// Every event has an id, so clients that reconnect with the `Last-Event-ID` header are sent the
+/// events they missed first.
+///
+/// With `?presence=true` the stream also gets `user_joined` and `user_left` events.
+///
+/// With `?record=true` every event sent on the connection is written to a recording, which can
+/// be replayed from `/v4/recordings/:name/sse`. The recording's name is in the `x-recording`
+/// response header.
 ///
 /// [Server Sent Events]: https://developer.mozilla.org/en-US/docs/Web/API/Server-sent_events/Using_server-sent_events
 pub(super) async fn sse_handler_v4<D>(
     State(state): State<V4AppState<D>>,
-    Query(params): Query<QueryParams>,
-) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, String> {
-    let (sse_sender, sse_receiver): (
-        Sender<ServerSentEventMessage>,
-        Receiver<ServerSentEventMessage>,
-    ) = channel(100);
+    headers: HeaderMap,
+    UserId(user_id): UserId,
+    Query(params): Query<SseParams>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let connection = state.limits.open_connection(user_id)?;
+    let recording = match params.record {
+        true => Some(start_recording(&state, user_id).await?),
+        false => None,
+    };
+    let recording_header = recording
+        .as_ref()
+        .map(|(name, _)| (RECORDING_HEADER.clone(), name.clone()));
+    let recorder = recording.map(|(_, recorder)| recorder);
+
+    let (sse_sender, sse_receiver): (Sender<LoggedEvent>, Receiver<LoggedEvent>) = channel(100);
+
+    let last_event_id = headers
+        .get("last-event-id")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse().ok());
 
     let connect = AppEvent::UserConnected {
-        user_id: params.user_id,
+        user_id,
         sender: sse_sender.clone(),
+        last_event_id,
     };
 
     let Ok(()) = state.app_event_sender.send(connect).await else {
-        return Err(format!("Error Connecting {}", params.user_id));
+        return Err((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Error Connecting {user_id}"),
+        ));
     };
 
+    // When the client didn't opt in the sender is dropped right away, which ends its stream
+    let (presence_sender, presence_receiver) = channel(100);
+    if params.presence {
+        let subscribe = AppEvent::PresenceSubscribed {
+            sender: presence_sender,
+        };
+        if let Err(err) = state.app_event_sender.send(subscribe).await {
+            tracing::error!("Unable to subscribe {user_id} to presence events. {err:?}");
+        }
+    }
+
     // Register a task that will help clean up the connection when the stream is closed
     // Inspired by https://github.com/tokio-rs/axum/discussions/1060#discussioncomment-7457290
-    tokio::spawn(handle_user_disconnect(
+    tokio::spawn(handle_disconnect(
         state.app_event_sender,
         sse_sender,
-        params.user_id,
+        AppEvent::UserDisconnected { user_id },
     ));
 
-    // Create the event stream from the receiving end of the channel
-    let stream =
-        tokio_stream::wrappers::ReceiverStream::new(sse_receiver).filter_map(|data| match data {
-            ServerSentEventMessage::ReportStatusUpdate(report_status) => {
-                let data = serde_json::to_string(&report_status).ok()?;
-                let mut event = Event::default();
-                event = event.event("report_status_update").data(data);
-                Some(Ok(event))
+    // Presence events aren't logged, so they don't have an id
+    let presence_events = tokio_stream::wrappers::ReceiverStream::new(presence_receiver)
+        .filter_map(|presence: PresenceEvent| {
+            let data = serde_json::to_value(&presence).ok()?;
+            Some((presence.event, None, data))
+        });
+
+    // Create the event stream from the receiving end of the channel.
+    // The stream holds onto the connection so that it's counted until the client goes away
+    let stream = tokio_stream::wrappers::ReceiverStream::new(sse_receiver)
+        .filter_map(|logged_event| {
+            let (event_name, data) = event_payload(&logged_event.message)?;
+            Some((event_name, Some(logged_event.id.to_string()), data))
+        })
+        .merge(presence_events)
+        .map(move |(event_name, id, data)| {
+            let _connection = &connection;
+            if let Some(recorder) = &recorder {
+                recorder.record(Some(event_name.to_owned()), id.clone(), data.clone());
             }
-            ServerSentEventMessage::NewReport { .. } => {
-                // We shouldn't get these kinds of messages, but even if we do we don't want to
-                // send these messages to the user since they know they just created a report.
-                None
+            let mut event = Event::default().event(event_name).data(data.to_string());
+            if let Some(id) = id {
+                event = event.id(id);
             }
+            Ok::<_, Infallible>(event)
         });
 
     // Create and return the server sent event response
+    let sse = Sse::new(stream).keep_alive(
+        axum::response::sse::KeepAlive::new()
+            .interval(Duration::from_secs(30))
+            .text("keep-alive-text"),
+    );
+    Ok((AppendHeaders(recording_header), sse))
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub(super) struct SseParams {
+    /// Send `user_joined` and `user_left` events when users connect and disconnect
+    #[serde(default)]
+    presence: bool,
+    /// Record every event sent on the connection
+    #[serde(default)]
+    record: bool,
+}
+
+/// Recordings are named after the user so that users can only replay their own connections
+async fn start_recording<D>(
+    state: &V4AppState<D>,
+    user_id: Uuid,
+) -> Result<(String, Recorder), (StatusCode, String)> {
+    let Some(dir) = &state.config.recordings_dir else {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "recording isn't enabled on this server".to_owned(),
+        ));
+    };
+
+    // The random suffix keeps connections opened by the user in the same millisecond apart
+    let name = format!(
+        "{user_id}-{}-{:08x}",
+        chrono::Utc::now().timestamp_millis(),
+        rand::random::<u32>()
+    );
+    let path = recording_path(dir, &name).expect("uuids and hex numbers are valid names");
+    match Recorder::create(path).await {
+        Ok(recorder) => {
+            tracing::info!("recording connection for user {user_id} as {name}");
+            Ok((name, recorder))
+        }
+        Err(err) => {
+            tracing::error!("unable to start recording {name}. {err:?}");
+            Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "unable to record the connection".to_owned(),
+            ))
+        }
+    }
+}
+
+/// List the users that are connected over SSE or WebSockets
+pub(super) async fn presence<D>(
+    State(state): State<V4AppState<D>>,
+    UserId(_): UserId,
+) -> Result<Json<Vec<PresentUser>>, (StatusCode, String)> {
+    let (reply, users) = oneshot::channel();
+    if let Err(err) = state
+        .app_event_sender
+        .send(AppEvent::Presence { reply })
+        .await
+    {
+        tracing::error!("Unable to send presence message. {err:?}");
+        return Err((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Error listing connected users".to_owned(),
+        ));
+    }
+
+    let mut users = users.await.map_err(|err| {
+        tracing::error!("Never heard back about the connected users. {err:?}");
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Error listing connected users".to_owned(),
+        )
+    })?;
+    users.sort_by_key(|user| user.user_id);
+    Ok(Json(users))
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub(super) struct PollParams {
+    /// Id of the last event the client has seen
+    since: Option<u64>,
+}
+
+#[derive(Debug, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(super) struct PollResponse {
+    events: Vec<PolledEvent>,
+    /// Pass this as `since` on the next request
+    last_event_id: Option<u64>,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub(super) struct PolledEvent {
+    id: u64,
+    event: &'static str,
+    data: serde_json::Value,
+}
+
+/// Long-polling fallback for clients behind proxies that break streaming responses.
+///
+/// Responds right away with the events logged after `since`. When there aren't any, the request
+/// is held until the user's next event or until the long-poll timeout runs out.
+pub(super) async fn poll_handler_v4<D>(
+    State(state): State<V4AppState<D>>,
+    UserId(user_id): UserId,
+    Query(params): Query<PollParams>,
+) -> Result<Json<PollResponse>, (StatusCode, String)> {
+    // A long poll holds a connection open just like a stream does
+    let _connection = state.limits.open_connection(user_id)?;
+
+    let (reply, events) = oneshot::channel();
+    let poll = AppEvent::Poll {
+        user_id,
+        since: params.since,
+        reply,
+    };
+
+    if let Err(err) = state.app_event_sender.send(poll).await {
+        tracing::error!("Unable to send poll message for user {}. {err:?}", user_id);
+        return Err((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Error polling for {}", user_id),
+        ));
+    }
+
+    // The reply is dropped without an answer if the user's log is evicted while we wait
+    let timeout = state.config.event_log.long_poll_timeout;
+    let events = match tokio::time::timeout(timeout, events).await {
+        Ok(Ok(events)) => events,
+        Ok(Err(_)) | Err(_) => vec![],
+    };
+
+    let last_event_id = events.last().map(|event| event.id).or(params.since);
+    let events = events
+        .into_iter()
+        .filter_map(|logged_event| {
+            let (event, data) = event_payload(&logged_event.message)?;
+            Some(PolledEvent {
+                id: logged_event.id,
+                event,
+                data,
+            })
+        })
+        .collect();
+
+    Ok(Json(PollResponse {
+        events,
+        last_event_id,
+    }))
+}
+
+/// Convert a message into the [Event] sent to the client
+fn server_sent_event(message: ServerSentEventMessage) -> Option<Event> {
+    let (event_name, data) = event_payload(&message)?;
+    Some(Event::default().event(event_name).data(data.to_string()))
+}
+
+/// The event name and JSON payload sent to the client for a message.
+///
+/// Shared by every transport so that clients get the same payloads no matter how they connect
+pub(super) fn event_payload(
+    message: &ServerSentEventMessage,
+) -> Option<(&'static str, serde_json::Value)> {
+    let (event_name, data) = match message {
+        ServerSentEventMessage::ReportStatusUpdate(report_status) => (
+            "report_status_update",
+            serde_json::to_value(report_status).ok()?,
+        ),
+        ServerSentEventMessage::ReportProgress(report_progress) => (
+            "report_progress",
+            serde_json::to_value(report_progress).ok()?,
+        ),
+        ServerSentEventMessage::ReportDeleted(report_deleted) => {
+            ("report_deleted", serde_json::to_value(report_deleted).ok()?)
+        }
+    };
+    Some((event_name, data))
+}
+
+/// Handles [Server Sent Events] for a single report
+///
+/// Only status changes for the given report are streamed. The stream starts with the report's
+/// current status and closes once the report reaches a terminal status or is deleted.
+///
+/// [Server Sent Events]: https://developer.mozilla.org/en-US/docs/Web/API/Server-sent_events/Using_server-sent_events
+pub(super) async fn report_sse_handler<D>(
+    State(state): State<V4AppState<D>>,
+    Path(report_id): Path<Uuid>,
+    UserId(user_id): UserId,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, (StatusCode, String)>
+where
+    D: super::database::Database + Clone + Sync + Send,
+    D::Error: Debug,
+{
+    get_owned_report(&state.database, user_id, report_id).await?;
+    let connection = state.limits.open_connection(user_id)?;
+
+    let (sse_sender, sse_receiver): (
+        Sender<ServerSentEventMessage>,
+        Receiver<ServerSentEventMessage>,
+    ) = channel(100);
+
+    // The event loop sends the report's current status before any changes
+    let subscribe = AppEvent::ReportSubscribed {
+        report_id,
+        sender: sse_sender.clone(),
+    };
+    let Ok(()) = state.app_event_sender.send(subscribe).await else {
+        return Err((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Error subscribing to {report_id}"),
+        ));
+    };
+
+    tokio::spawn(handle_disconnect(
+        state.app_event_sender,
+        sse_sender,
+        AppEvent::ReportUnsubscribed { report_id },
+    ));
+
+    let config = state.config.clone();
+    // The stream ends after the first message for a terminal status or deleted report.
+    // We can't rely on the channel closing since `handle_disconnect` holds onto a sender.
+    let stream = futures::stream::unfold((sse_receiver, false), move |(mut receiver, finished)| {
+        let _connection = &connection;
+        let config = config.clone();
+        async move {
+            if finished {
+                return None;
+            }
+            let message = receiver.recv().await?;
+            let finished = match &message {
+                ServerSentEventMessage::ReportStatusUpdate(update) => {
+                    config.state_machine.is_terminal(update.status)
+                }
+                ServerSentEventMessage::ReportDeleted(_) => true,
+                _ => false,
+            };
+            Some((message, (receiver, finished)))
+        }
+    })
+    .filter_map(|message| Some(Ok(server_sent_event(message)?)));
+
     let sse = Sse::new(stream).keep_alive(
         axum::response::sse::KeepAlive::new()
             .interval(Duration::from_secs(30))
@@ -75,82 +381,399 @@ pub(super) async fn sse_handler_v4<D>(
 
 pub(super) async fn create_report<D>(
     State(state): State<V4AppState<D>>,
-    Query(params): Query<QueryParams>,
+    RateLimited(user_id): RateLimited,
+    Json(new_report): Json<NewReport>,
 ) -> (StatusCode, Result<Json<Report>, String>) {
-    let new_report = Report::new(params.user_id);
-    let new_report_message = AppEvent::new_report(new_report.clone());
-    match state.app_event_sender.send(new_report_message).await {
-        Err(err) => {
-            tracing::error!(
-                "unable to send `new_report_message` for user {}. {err:?}",
-                params.user_id
-            );
-            (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                Err("Unable to create a new report".to_owned()),
-            )
-        }
-        Ok(()) => {
+    if new_report.name.trim().is_empty() {
+        return (
+            StatusCode::BAD_REQUEST,
+            Err("The report name can't be empty".to_owned()),
+        );
+    }
+
+    let new_report = Report::new(user_id, new_report);
+    let (reply, stored) = oneshot::channel();
+    let new_report_message = AppEvent::NewReport {
+        report: new_report.clone(),
+        reply,
+    };
+    if let Err(err) = state.app_event_sender.send(new_report_message).await {
+        tracing::error!(
+            "unable to send `new_report_message` for user {}. {err:?}",
+            user_id
+        );
+        return (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Err("Unable to create a new report".to_owned()),
+        );
+    }
+
+    // Only hand out the report once it's stored, so it can be updated right away
+    match stored.await {
+        Ok(true) => {
             tracing::info!("created new report {new_report:?}");
+            if let Some(simulator) = &state.config.simulator {
+                tokio::spawn(simulate_report(
+                    new_report.report_id,
+                    state.report_status_sender.clone(),
+                    simulator.clone(),
+                ));
+            }
             (StatusCode::CREATED, Ok(Json(new_report)))
         }
+        Ok(false) | Err(_) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Err("Unable to create a new report".to_owned()),
+        ),
+    }
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub(super) struct ListReportsParams {
+    /// Archived reports are only listed when this is `true`
+    #[serde(default)]
+    include_archived: bool,
+    page_size: Option<usize>,
+    /// The `nextCursor` returned with the previous page
+    cursor: Option<String>,
+    /// Comma separated list of statuses, e.g. `queued,processing`
+    status: Option<String>,
+}
+
+impl ListReportsParams {
+    fn report_query(&self, user_id: Uuid) -> Result<ReportQuery, String> {
+        let cursor = match &self.cursor {
+            Some(token) => {
+                Some(PageCursor::decode_for_user(token, user_id).ok_or("invalid cursor")?)
+            }
+            None => None,
+        };
+
+        let statuses = match &self.status {
+            Some(statuses) => ReportStatus::parse_list(statuses).map_err(|err| err.to_string())?,
+            None => vec![],
+        };
+
+        Ok(ReportQuery {
+            page_size: self
+                .page_size
+                .unwrap_or(DEFAULT_PAGE_SIZE)
+                .clamp(1, MAX_PAGE_SIZE),
+            cursor,
+            statuses,
+            include_archived: self.include_archived,
+        })
     }
 }
 
 pub(super) async fn list_reports<D>(
     State(state): State<V4AppState<D>>,
-    Query(params): Query<QueryParams>,
-) -> (StatusCode, Result<Json<Vec<Report>>, String>)
+    UserId(user_id): UserId,
+    Query(params): Query<ListReportsParams>,
+) -> (StatusCode, Result<Json<ReportList>, String>)
 where
     D: super::database::Database + Clone + Sync + Send,
     D::Error: Debug,
 {
-    let reports = match state.database.list_reports(params.user_id).await {
+    let query = match params.report_query(user_id) {
+        Ok(query) => query,
+        Err(err) => return (StatusCode::BAD_REQUEST, Err(err)),
+    };
+
+    let page = match state.database.list_reports(user_id, &query).await {
         Err(err) => {
-            tracing::warn!(
-                "Unable to fetch reports for user {}. {err:?}",
-                params.user_id
-            );
+            tracing::warn!("Unable to fetch reports for user {}. {err:?}", user_id);
             return (
                 StatusCode::INTERNAL_SERVER_ERROR,
                 Err("unable to fetch reports".to_string()),
             );
         }
-        Ok(reports) => reports,
+        Ok(page) => page,
+    };
+
+    let report_list = ReportList {
+        reports: page.reports,
+        next_cursor: page.next_cursor.map(|cursor| cursor.encode()),
     };
 
-    if reports.is_empty() {
+    if report_list.reports.is_empty() {
         // exit early. Send the user an empty response
-        return (StatusCode::OK, Ok(Json(reports)));
+        return (StatusCode::OK, Ok(Json(report_list)));
     }
 
-    let message = AppEvent::cache_reports(reports.clone());
+    let message = AppEvent::cache_reports(report_list.reports.clone());
     if let Err(err) = state.app_event_sender.send(message).await {
-        tracing::warn!(
-            "unable to cache reports for user {}. {err:?}",
-            params.user_id
-        );
+        tracing::warn!("unable to cache reports for user {}. {err:?}", user_id);
+    }
+
+    (StatusCode::OK, Ok(Json(report_list)))
+}
+
+pub(super) async fn get_report<D>(
+    State(state): State<V4AppState<D>>,
+    Path(report_id): Path<Uuid>,
+    UserId(user_id): UserId,
+) -> (StatusCode, Result<Json<Report>, String>)
+where
+    D: super::database::Database + Clone + Sync + Send,
+    D::Error: Debug,
+{
+    let report = match get_owned_report(&state.database, user_id, report_id).await {
+        Ok(report) => report,
+        Err((status_code, message)) => return (status_code, Err(message)),
+    };
+
+    let message = AppEvent::cache_reports(vec![report.clone()]);
+    if let Err(err) = state.app_event_sender.send(message).await {
+        tracing::warn!("unable to cache report {report_id}. {err:?}");
     }
 
-    (StatusCode::OK, Ok(Json(reports)))
+    (StatusCode::OK, Ok(Json(report)))
+}
+
+/// The report status transitions the app allows
+pub(super) async fn state_machine<D>(State(state): State<V4AppState<D>>) -> Json<StateMachine> {
+    Json(state.config.state_machine.clone())
 }
 
 pub(super) async fn change_report_status<D>(
     State(state): State<V4AppState<D>>,
-    Query(params): Query<QueryParams>,
-    Json(update): Json<ReportStatusUpdate>,
-) -> StatusCode {
+    RateLimited(user_id): RateLimited,
+    Json(mut update): Json<ReportStatusUpdate>,
+) -> Result<StatusCode, (StatusCode, String)>
+where
+    D: super::database::Database + Clone + Sync + Send,
+    D::Error: Debug,
+{
+    get_owned_report(&state.database, user_id, update.id).await?;
+
+    update.source = UpdateSource::Api;
+    // Only the watchdog and workers explain why a report changed status
+    update.reason = None;
     if let Err(err) = state.report_status_sender.send(update).await {
         tracing::error!(
             "Unable to to send report status update message for user {}. {err:?}",
-            params.user_id
+            user_id
         );
-        StatusCode::INTERNAL_SERVER_ERROR
+        Err((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "unable to update the report status".to_owned(),
+        ))
     } else {
         tracing::info!(
             "Successfully sent report status update message for user {}",
-            params.user_id
+            user_id
         );
-        StatusCode::ACCEPTED
+        Ok(StatusCode::ACCEPTED)
+    }
+}
+
+/// Upper bound on the number of updates accepted by [bulk_change_report_status]
+const MAX_BULK_UPDATES: usize = 500;
+
+/// Change the status of many reports at once.
+///
+/// Unlike [change_report_status] the updates are applied right away instead of going through
+/// Kafka, so that we can tell the caller which of them were applied. Updates to reports the caller
+/// doesn't own are rejected without being sent to the event loop
+pub(super) async fn bulk_change_report_status<D>(
+    State(state): State<V4AppState<D>>,
+    RateLimited(user_id): RateLimited,
+    Json(updates): Json<Vec<ReportStatusUpdate>>,
+) -> (
+    StatusCode,
+    Result<Json<Vec<BulkStatusUpdateResult>>, String>,
+)
+where
+    D: super::database::Database + Clone + Sync + Send,
+    D::Error: Debug,
+{
+    if updates.len() > MAX_BULK_UPDATES {
+        return (
+            StatusCode::BAD_REQUEST,
+            Err(format!(
+                "at most {MAX_BULK_UPDATES} updates can be sent at once"
+            )),
+        );
+    }
+
+    // Rejected updates keep their place, so the results line up with the request
+    let mut results = Vec::with_capacity(updates.len());
+    let mut owned = vec![];
+    for mut update in updates {
+        match get_owned_report(&state.database, user_id, update.id).await {
+            Ok(_) => {
+                update.source = UpdateSource::Api;
+                update.reason = None;
+                owned.push(update);
+                results.push(None);
+            }
+            Err((_, err)) => results.push(Some(BulkStatusUpdateResult::rejected(&update, err))),
+        }
+    }
+
+    let (reply, applied) = oneshot::channel();
+    let message = AppEvent::BulkStatusUpdate {
+        updates: owned,
+        reply,
+    };
+    if let Err(err) = state.app_event_sender.send(message).await {
+        tracing::error!("Unable to send bulk status update message. {err:?}");
+        return (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Err("unable to update the reports".to_owned()),
+        );
+    }
+
+    let mut applied = match applied.await {
+        Ok(applied) => applied.into_iter(),
+        Err(err) => {
+            tracing::error!("Never got the bulk status update results. {err:?}");
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Err("unable to update the reports".to_owned()),
+            );
+        }
+    };
+
+    let results = results
+        .into_iter()
+        .filter_map(|result| result.or_else(|| applied.next()))
+        .collect();
+    (StatusCode::OK, Ok(Json(results)))
+}
+
+pub(super) async fn change_report_progress<D>(
+    State(state): State<V4AppState<D>>,
+    RateLimited(user_id): RateLimited,
+    Json(progress): Json<ReportProgress>,
+) -> (StatusCode, Result<(), String>)
+where
+    D: super::database::Database + Clone + Sync + Send,
+    D::Error: Debug,
+{
+    if progress.progress > 100 {
+        return (
+            StatusCode::BAD_REQUEST,
+            Err("progress must be between 0 and 100".to_owned()),
+        );
+    }
+
+    if let Err((status, err)) = get_owned_report(&state.database, user_id, progress.id).await {
+        return (status, Err(err));
+    }
+
+    let message = AppEvent::report_progress_message(progress);
+    if let Err(err) = state.app_event_sender.send(message).await {
+        tracing::error!(
+            "Unable to to send report progress message for user {}. {err:?}",
+            user_id
+        );
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Err("Unable to update the report progress".to_owned()),
+        )
+    } else {
+        (StatusCode::ACCEPTED, Ok(()))
+    }
+}
+
+/// Lookup a report and make sure that it belongs to the user making the request
+pub(super) async fn get_owned_report<D>(
+    database: &D,
+    user_id: Uuid,
+    report_id: Uuid,
+) -> Result<Report, (StatusCode, String)>
+where
+    D: super::database::Database + Clone + Sync + Send,
+    D::Error: Debug,
+{
+    match database.get_report(report_id).await {
+        Err(err) => {
+            tracing::warn!("Unable to fetch report {report_id}. {err:?}");
+            Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "unable to fetch the report".to_owned(),
+            ))
+        }
+        Ok(None) => Err((
+            StatusCode::NOT_FOUND,
+            format!("report {report_id} not found"),
+        )),
+        Ok(Some(report)) if report.user_id != user_id => {
+            tracing::warn!("user {user_id} does not own report {report_id}");
+            Err((
+                StatusCode::FORBIDDEN,
+                format!("report {report_id} belongs to another user"),
+            ))
+        }
+        Ok(Some(report)) => Ok(report),
+    }
+}
+
+pub(super) async fn delete_report<D>(
+    State(state): State<V4AppState<D>>,
+    Path(report_id): Path<Uuid>,
+    RateLimited(user_id): RateLimited,
+) -> (StatusCode, Result<(), String>)
+where
+    D: super::database::Database + Clone + Sync + Send,
+    D::Error: Debug,
+{
+    let report = match get_owned_report(&state.database, user_id, report_id).await {
+        Ok(report) => report,
+        Err((status_code, message)) => return (status_code, Err(message)),
+    };
+
+    if let Err(err) = state.database.delete_report(report_id).await {
+        tracing::error!("Unable to delete report {report_id}. {err:?}");
+        return (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Err("unable to delete the report".to_owned()),
+        );
+    }
+
+    // Let the event loop evict the report from the cache and notify the user's connections
+    let message = AppEvent::report_deleted(&report);
+    if let Err(err) = state.app_event_sender.send(message).await {
+        tracing::warn!("unable to send report deleted message for {report_id}. {err:?}");
+    }
+
+    (StatusCode::NO_CONTENT, Ok(()))
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub(super) struct ArchiveReport {
+    archived: bool,
+}
+
+pub(super) async fn archive_report<D>(
+    State(state): State<V4AppState<D>>,
+    Path(report_id): Path<Uuid>,
+    RateLimited(user_id): RateLimited,
+    Json(archive): Json<ArchiveReport>,
+) -> (StatusCode, Result<(), String>)
+where
+    D: super::database::Database + Clone + Sync + Send,
+    D::Error: Debug,
+{
+    if let Err((status_code, message)) = get_owned_report(&state.database, user_id, report_id).await
+    {
+        return (status_code, Err(message));
+    }
+
+    match state
+        .database
+        .set_report_archived(report_id, archive.archived)
+        .await
+    {
+        Ok(()) => (StatusCode::NO_CONTENT, Ok(())),
+        Err(err) => {
+            tracing::error!("Unable to archive report {report_id}. {err:?}");
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Err("unable to archive the report".to_owned()),
+            )
+        }
     }
 }
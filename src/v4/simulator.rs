@@ -0,0 +1,53 @@
+//! A fake worker for demos and load tests.
+//!
+//! When enabled, every new report is moved through `queued` and `processing` before it ends up
+//! `completed` or `failed`. Updates are published through the same channel as the
+//! `PUT /v4/report` endpoint, so they take the same path through Kafka as real status changes.
+use std::time::Duration;
+
+use rand::Rng;
+use tokio::sync::mpsc::Sender;
+use uuid::Uuid;
+
+use super::app_events::{ReportStatusUpdate, UpdateSource};
+use super::report_status::ReportStatus;
+use crate::config::SimulatorConfig;
+
+pub(super) async fn simulate_report(
+    report_id: Uuid,
+    report_status_sender: Sender<ReportStatusUpdate>,
+    config: SimulatorConfig,
+) {
+    let failed = rand::thread_rng().gen_bool(config.failure_rate);
+    let final_status = if failed {
+        ReportStatus::Failed
+    } else {
+        ReportStatus::Completed
+    };
+
+    let steps = [
+        (config.queue_delay, ReportStatus::Queued),
+        (config.start_delay, ReportStatus::Processing),
+        (config.processing_delay, final_status),
+    ];
+
+    for (delay, status) in steps {
+        tokio::time::sleep(jitter(delay)).await;
+
+        let mut update = ReportStatusUpdate::new(report_id, status);
+        update.source = UpdateSource::Simulator;
+        if status == ReportStatus::Failed {
+            update.reason = Some("simulated failure".to_owned());
+        }
+
+        if let Err(err) = report_status_sender.send(update).await {
+            tracing::error!("simulator could not update report {report_id}. {err:?}");
+            return;
+        }
+    }
+}
+
+/// Randomize the delay between 50% and 150% of its configured value
+fn jitter(delay: Duration) -> Duration {
+    delay.mul_f64(rand::thread_rng().gen_range(0.5..1.5))
+}
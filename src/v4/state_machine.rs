@@ -0,0 +1,347 @@
+//! The graph of valid report status transitions.
+//!
+//! The default graph models the lifecycle the app has always used, but teams can load their own
+//! from a JSON file, e.g. to add a `paused` state or to stop canceled reports from being retried:
+//!
+//! ```json
+//! {
+//!   "transitions": {
+//!     "pending": ["queued", "canceled"],
+//!     "queued": ["processing", "canceled"],
+//!     "processing": ["paused", "completed", "failed"],
+//!     "paused": ["processing", "canceled"]
+//!   }
+//! }
+//! ```
+use std::collections::{BTreeMap, BTreeSet};
+use std::str::FromStr;
+use std::sync::OnceLock;
+
+use super::report_status::{ReportStatus, ReportStatusError};
+
+/// Every report starts out in this status
+pub const INITIAL_STATUS: ReportStatus = ReportStatus::Pending;
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(try_from = "StateMachineConfig", into = "StateMachineGraph")]
+pub struct StateMachine {
+    transitions: BTreeMap<ReportStatus, BTreeSet<ReportStatus>>,
+}
+
+#[derive(Debug)]
+pub enum StateMachineError {
+    /// The graph mentions a status the app doesn't know about
+    UnknownStatus(String),
+    /// Reports start out `pending`, so it needs at least one transition out of it
+    MissingInitialStatus,
+    /// Reports could never finish
+    NoTerminalStatus,
+    /// A terminal status can't be reached from the initial status
+    UnreachableTerminal(ReportStatus),
+    /// Part of the app, e.g. the workers, relies on a transition the graph doesn't have
+    MissingTransition {
+        current: ReportStatus,
+        next_status: ReportStatus,
+        needed_by: &'static str,
+    },
+}
+
+impl std::fmt::Display for StateMachineError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StateMachineError::UnknownStatus(status) => write!(f, "unknown status {status:?}"),
+            StateMachineError::MissingInitialStatus => {
+                write!(f, "there are no transitions out of {INITIAL_STATUS}")
+            }
+            StateMachineError::NoTerminalStatus => {
+                f.write_str("there are no terminal statuses, so reports can never finish")
+            }
+            StateMachineError::UnreachableTerminal(status) => write!(
+                f,
+                "terminal status {status} can't be reached from {INITIAL_STATUS}"
+            ),
+            StateMachineError::MissingTransition {
+                current,
+                next_status,
+                needed_by,
+            } => write!(
+                f,
+                "the transition from {current} to {next_status} is needed by the {needed_by}"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for StateMachineError {}
+
+/// The state machine as it's written in configuration files
+#[derive(Debug, serde::Deserialize)]
+struct StateMachineConfig {
+    transitions: BTreeMap<String, Vec<String>>,
+}
+
+/// The state machine as it's sent to clients
+#[derive(Debug, serde::Serialize)]
+struct StateMachineGraph {
+    initial: ReportStatus,
+    terminal: Vec<ReportStatus>,
+    transitions: BTreeMap<ReportStatus, BTreeSet<ReportStatus>>,
+}
+
+impl Default for StateMachine {
+    fn default() -> Self {
+        use ReportStatus::*;
+
+        let edges = [
+            // When a report is pending it hasn't been created yet.
+            // It can either be queued to be worked on or canceled by the user
+            (Pending, Queued),
+            (Pending, Canceled),
+            // When a report is queued it will be picked up by a worker.
+            // Processing can start or a user can cancel the report before processing begins
+            (Queued, Processing),
+            (Queued, Canceled),
+            // A queued report fails if no worker picks it up in time
+            (Queued, Failed),
+            // Once the report has started it can either fail or succeed
+            (Processing, Failed),
+            (Processing, Completed),
+            // Allow uers to retry reports that have failed or been canceled
+            (Canceled, Pending),
+            (Failed, Pending),
+        ];
+
+        let mut transitions: BTreeMap<_, BTreeSet<_>> = BTreeMap::new();
+        for (from, to) in edges {
+            transitions.entry(from).or_default().insert(to);
+        }
+        transitions.entry(Completed).or_default();
+
+        Self { transitions }
+    }
+}
+
+impl StateMachine {
+    /// The default state machine, shared so that it's only built once
+    pub fn builtin() -> &'static StateMachine {
+        static BUILTIN: OnceLock<StateMachine> = OnceLock::new();
+        BUILTIN.get_or_init(StateMachine::default)
+    }
+
+    pub fn transition(
+        &self,
+        current: ReportStatus,
+        next_status: ReportStatus,
+    ) -> Result<ReportStatus, ReportStatusError> {
+        let allowed = self
+            .transitions
+            .get(&current)
+            .is_some_and(|next| next.contains(&next_status));
+
+        if allowed {
+            Ok(next_status)
+        } else {
+            Err(ReportStatusError::InvalidStatusTransition {
+                current,
+                next_status,
+            })
+        }
+    }
+
+    /// Terminal statuses are part of the graph, but can't transition to any other status
+    pub fn is_terminal(&self, status: ReportStatus) -> bool {
+        self.transitions
+            .get(&status)
+            .is_some_and(BTreeSet::is_empty)
+    }
+
+    /// Check that the graph has every transition in `transitions`, which `needed_by` relies on
+    pub fn require(
+        &self,
+        needed_by: &'static str,
+        transitions: &[(ReportStatus, ReportStatus)],
+    ) -> Result<(), StateMachineError> {
+        match transitions
+            .iter()
+            .find(|(current, next_status)| self.transition(*current, *next_status).is_err())
+        {
+            Some((current, next_status)) => Err(StateMachineError::MissingTransition {
+                current: *current,
+                next_status: *next_status,
+                needed_by,
+            }),
+            None => Ok(()),
+        }
+    }
+
+    fn terminal_statuses(&self) -> impl Iterator<Item = ReportStatus> + '_ {
+        self.transitions
+            .iter()
+            .filter(|(_, next)| next.is_empty())
+            .map(|(status, _)| *status)
+    }
+
+    fn validate(&self) -> Result<(), StateMachineError> {
+        if self
+            .transitions
+            .get(&INITIAL_STATUS)
+            .is_none_or(BTreeSet::is_empty)
+        {
+            return Err(StateMachineError::MissingInitialStatus);
+        }
+
+        let mut reachable = BTreeSet::from([INITIAL_STATUS]);
+        let mut to_visit = vec![INITIAL_STATUS];
+        while let Some(status) = to_visit.pop() {
+            for next in &self.transitions[&status] {
+                if reachable.insert(*next) {
+                    to_visit.push(*next);
+                }
+            }
+        }
+
+        let mut terminal = self.terminal_statuses().peekable();
+        if terminal.peek().is_none() {
+            return Err(StateMachineError::NoTerminalStatus);
+        }
+
+        match terminal.find(|status| !reachable.contains(status)) {
+            Some(status) => Err(StateMachineError::UnreachableTerminal(status)),
+            None => Ok(()),
+        }
+    }
+}
+
+impl TryFrom<StateMachineConfig> for StateMachine {
+    type Error = StateMachineError;
+
+    fn try_from(config: StateMachineConfig) -> Result<Self, Self::Error> {
+        let parse = |status: &str| {
+            ReportStatus::from_str(status)
+                .map_err(|_| StateMachineError::UnknownStatus(status.to_owned()))
+        };
+
+        let mut transitions: BTreeMap<_, BTreeSet<_>> = BTreeMap::new();
+        for (from, to) in &config.transitions {
+            let from = parse(from)?;
+            transitions.entry(from).or_default();
+            for to in to {
+                let to = parse(to)?;
+                transitions.entry(from).or_default().insert(to);
+                // Statuses that are only ever transitioned into are terminal
+                transitions.entry(to).or_default();
+            }
+        }
+
+        let state_machine = StateMachine { transitions };
+        state_machine.validate()?;
+        Ok(state_machine)
+    }
+}
+
+impl From<StateMachine> for StateMachineGraph {
+    fn from(state_machine: StateMachine) -> Self {
+        StateMachineGraph {
+            initial: INITIAL_STATUS,
+            terminal: state_machine.terminal_statuses().collect(),
+            transitions: state_machine.transitions,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn parse(json: &str) -> Result<StateMachine, String> {
+        serde_json::from_str(json).map_err(|err| err.to_string())
+    }
+
+    #[test]
+    fn test_default_is_valid() {
+        assert!(StateMachine::default().validate().is_ok());
+        assert!(!StateMachine::default().is_terminal(ReportStatus::Paused));
+    }
+
+    #[test]
+    fn test_custom_state_machine() {
+        let state_machine = parse(
+            r#"{"transitions": {
+                "pending": ["queued"],
+                "queued": ["processing"],
+                "processing": ["paused", "completed"],
+                "paused": ["processing", "canceled"]
+            }}"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            state_machine.transition(ReportStatus::Processing, ReportStatus::Paused),
+            Ok(ReportStatus::Paused)
+        );
+        assert!(state_machine
+            .transition(ReportStatus::Canceled, ReportStatus::Pending)
+            .is_err());
+        assert!(state_machine.is_terminal(ReportStatus::Canceled));
+        assert!(state_machine.is_terminal(ReportStatus::Completed));
+    }
+
+    #[test]
+    fn test_invalid_state_machines() {
+        let err = parse(r#"{"transitions": {"pending": ["done"]}}"#).unwrap_err();
+        assert!(err.contains("unknown status \"done\""), "{err}");
+
+        let err = parse(r#"{"transitions": {"queued": ["completed"]}}"#).unwrap_err();
+        assert!(err.contains("no transitions out of pending"), "{err}");
+
+        let err = parse(r#"{"transitions": {"pending": ["queued"], "queued": ["pending"]}}"#)
+            .unwrap_err();
+        assert!(err.contains("no terminal statuses"), "{err}");
+
+        let err = parse(r#"{"transitions": {"pending": ["completed"], "paused": ["canceled"]}}"#)
+            .unwrap_err();
+        assert!(err.contains("canceled can't be reached"), "{err}");
+    }
+
+    #[test]
+    fn test_require_transitions() {
+        let state_machine = parse(
+            r#"{"transitions": {
+                "pending": ["queued"],
+                "queued": ["processing"],
+                "processing": ["completed"]
+            }}"#,
+        )
+        .unwrap();
+
+        assert!(state_machine
+            .require(
+                "workers",
+                &[(ReportStatus::Queued, ReportStatus::Processing)]
+            )
+            .is_ok());
+        let err = state_machine
+            .require(
+                "workers",
+                &[
+                    (ReportStatus::Queued, ReportStatus::Processing),
+                    (ReportStatus::Processing, ReportStatus::Failed),
+                ],
+            )
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "the transition from processing to failed is needed by the workers"
+        );
+    }
+
+    #[test]
+    fn test_serialize_round_trip() {
+        let json = serde_json::to_value(StateMachine::default()).unwrap();
+        assert_eq!(json["initial"], "pending");
+        assert_eq!(json["terminal"], serde_json::json!(["completed"]));
+
+        let state_machine: StateMachine = serde_json::from_value(json).unwrap();
+        assert_eq!(state_machine, StateMachine::default());
+    }
+}
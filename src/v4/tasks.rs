@@ -1,30 +1,209 @@
+use chrono::{DateTime, Utc};
 use lru::LruCache;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::num::NonZeroUsize;
+use tokio::sync::mpsc::error::TrySendError;
 use tokio::sync::mpsc::{Receiver, Sender};
 use uuid::Uuid;
 
-use super::app_events::{AppEvent, ReportStatusUpdate, ServerSentEventMessage};
+use super::app_events::{
+    AdminFilter, AppEvent, BulkStatusUpdateResult, PresenceEvent, PresentUser, ReportDeleted,
+    ReportProgress, ReportStatusUpdate, ReportTransition, ServerSentEventMessage,
+};
+use super::database::MAX_TRANSACTION_SIZE;
+use super::event_log::{EventLog, LoggedEvent};
 use super::report_status::{ReportStatus, ReportStatusError};
+use super::state_machine::StateMachine;
+use crate::config::EventLogConfig;
 
 /// Async task Loop that process all the `Command` messages received on the Receiver
-pub(super) async fn handle_app_events<D>(mut receiver: Receiver<AppEvent>, database: D)
-where
+pub(super) async fn handle_app_events<D>(
+    mut receiver: Receiver<AppEvent>,
+    database: D,
+    state_machine: StateMachine,
+    event_log_config: EventLogConfig,
+    webhook_sender: Sender<ReportTransition>,
+) where
     D: super::database::Database,
     <D as super::database::Database>::Error: std::fmt::Debug,
 {
-    let mut user_connection_map = HashMap::new();
+    let mut subscribers = Subscribers {
+        users: HashMap::new(),
+        reports: HashMap::new(),
+        admins: vec![],
+        presence: vec![],
+        webhooks: webhook_sender,
+        events: EventLog::new(event_log_config.max_events),
+    };
     let mut report_status_cache = LruCache::new(NonZeroUsize::new(200).expect("value is > 0"));
 
     while let Some(event) = receiver.recv().await {
         match event {
-            AppEvent::UserConnected { user_id, sender } => {
+            AppEvent::UserConnected {
+                user_id,
+                sender,
+                last_event_id,
+            } => {
                 tracing::info!("got a connection from user {user_id:?}");
-                user_connection_map.insert(user_id, sender);
+                if last_event_id.is_some() {
+                    // Catch the connection up before it starts getting new events. The client
+                    // isn't reading yet, so don't wait on it if there's more than the channel holds
+                    for event in subscribers.events.events_since(user_id, last_event_id) {
+                        if let Err(TrySendError::Full(_)) = sender.try_send(event) {
+                            tracing::warn!("too many events to replay for user {user_id}");
+                            break;
+                        }
+                    }
+                }
+                let joined = !subscribers.users.contains_key(&user_id);
+                subscribers
+                    .users
+                    .entry(user_id)
+                    .or_default()
+                    .push(UserConnection {
+                        sender,
+                        connected_at: Utc::now(),
+                    });
+                if joined {
+                    send_to_presence_subscribers(
+                        &mut subscribers.presence,
+                        PresenceEvent::joined(user_id),
+                    );
+                }
+            }
+            AppEvent::Poll {
+                user_id,
+                since,
+                reply,
+            } => {
+                subscribers.events.poll(user_id, since, reply);
             }
             AppEvent::UserDisconnected { ref user_id } => {
                 tracing::info!("user {user_id:?} closed the connection");
-                let _ = user_connection_map.remove(user_id);
+                if let Some(connections) = subscribers.users.get_mut(user_id) {
+                    // Only the connection that was just closed should be removed
+                    connections.retain(|connection| !connection.sender.is_closed());
+                    if connections.is_empty() {
+                        subscribers.users.remove(user_id);
+                        send_to_presence_subscribers(
+                            &mut subscribers.presence,
+                            PresenceEvent::left(*user_id),
+                        );
+                    }
+                }
+            }
+            AppEvent::Presence { reply } => {
+                let users = subscribers
+                    .users
+                    .iter()
+                    .map(|(user_id, connections)| PresentUser {
+                        user_id: *user_id,
+                        connections: connections.len(),
+                        connected_at: connections.iter().map(|c| c.connected_at).collect(),
+                    })
+                    .collect();
+                let _ = reply.send(users);
+            }
+            AppEvent::PresenceSubscribed { sender } => {
+                subscribers.presence.push(sender);
+            }
+            AppEvent::ReportDeleted { user_id, report_id } => {
+                tracing::info!("evicting deleted report {report_id} from the cache");
+                report_status_cache.pop(&report_id);
+                let message =
+                    ServerSentEventMessage::ReportDeleted(ReportDeleted { id: report_id });
+                send_to_user(&mut subscribers, user_id, message.clone()).await;
+                // Dropping the subscribers closes their streams
+                if let Some(report_subscribers) = subscribers.reports.remove(&report_id) {
+                    send_to_all(&report_subscribers, message).await;
+                }
+            }
+            AppEvent::ReportSubscribed { report_id, sender } => {
+                tracing::info!("got a subscription for report {report_id:?}");
+                // Updates are only handled in this loop, so nothing can change between reading
+                // the current status and registering the subscriber
+                let current_status =
+                    get_current_report_status(report_id, &mut report_status_cache, &database).await;
+                let message = match current_status {
+                    Some(status) => ServerSentEventMessage::ReportStatusUpdate(
+                        ReportStatusUpdate::new(report_id, status),
+                    ),
+                    None => ServerSentEventMessage::ReportDeleted(ReportDeleted { id: report_id }),
+                };
+                // The subscriber's channel is still empty, so this can't fail for lack of room
+                let _ = sender.try_send(message);
+
+                // The stream ends with terminal statuses and deleted reports
+                if current_status.is_some_and(|status| !state_machine.is_terminal(status)) {
+                    subscribers
+                        .reports
+                        .entry(report_id)
+                        .or_default()
+                        .push(sender);
+                }
+            }
+            AppEvent::ReportUnsubscribed { ref report_id } => {
+                if let Some(report_subscribers) = subscribers.reports.get_mut(report_id) {
+                    report_subscribers.retain(|sender| !sender.is_closed());
+                    if report_subscribers.is_empty() {
+                        subscribers.reports.remove(report_id);
+                    }
+                }
+            }
+            AppEvent::AdminConnected { filter, sender } => {
+                tracing::info!("got an admin connection with filter {filter:?}");
+                subscribers.admins.push((filter, sender));
+            }
+            AppEvent::AdminDisconnected => {
+                tracing::info!("an admin closed the connection");
+                subscribers.admins.retain(|(_, sender)| !sender.is_closed());
+            }
+            AppEvent::ReportStatusChanged {
+                user_id,
+                old_status,
+                update,
+            } => {
+                // The change was written outside of this loop, so the report could have changed
+                // again since. Only a cache that still has the old status is known to be behind
+                match report_status_cache.get(&update.id) {
+                    Some((_, cached)) if *cached == old_status => {
+                        report_status_cache.push(update.id, (user_id, update.status));
+                    }
+                    _ => {
+                        report_status_cache.pop(&update.id);
+                    }
+                }
+                broadcast_status_update(
+                    &state_machine,
+                    &mut subscribers,
+                    user_id,
+                    old_status,
+                    &update,
+                )
+                .await;
+            }
+            AppEvent::BulkStatusUpdate { updates, reply } => {
+                let (results, applied) = update_report_statuses(
+                    updates,
+                    &state_machine,
+                    &mut report_status_cache,
+                    &database,
+                )
+                .await;
+
+                // The caller only waits on the results, so answer before sending any events
+                let _ = reply.send(results);
+
+                for (user_id, old_status, update) in applied {
+                    broadcast_status_update(
+                        &state_machine,
+                        &mut subscribers,
+                        user_id,
+                        old_status,
+                        &update,
+                    )
+                    .await;
+                }
             }
             AppEvent::CacheReports(reports) => {
                 for report in reports {
@@ -32,55 +211,69 @@ where
                         .push(report.report_id, (report.user_id, report.report_status));
                 }
             }
+            AppEvent::NewReport { report, reply } => {
+                tracing::info!(
+                    "Storing report {} in the database for user {}",
+                    report.report_id,
+                    report.user_id
+                );
+                let stored = match database.insert_report(report.clone()).await {
+                    Ok(()) => {
+                        report_status_cache
+                            .push(report.report_id, (report.user_id, report.report_status));
+                        true
+                    }
+                    Err(err) => {
+                        tracing::error!("could not store the report in the database {err:?}");
+                        false
+                    }
+                };
+                let _ = reply.send(stored);
+            }
             AppEvent::UserMessage(event_message) => {
                 match &event_message {
                     ServerSentEventMessage::ReportStatusUpdate(report) => {
                         // Grab the report_status / user_id from the database
-                        match update_report_status(report, &mut report_status_cache, &database)
+                        match update_report_status(
+                            report,
+                            &state_machine,
+                            &mut report_status_cache,
+                            &database,
+                        )
+                        .await
+                        {
+                            Ok((user_id, old_status)) => {
+                                broadcast_status_update(
+                                    &state_machine,
+                                    &mut subscribers,
+                                    user_id,
+                                    old_status,
+                                    report,
+                                )
+                                .await;
+                            }
+                            Err(err) => {
+                                tracing::error!("{err:?}");
+                            }
+                        }
+                    }
+                    ServerSentEventMessage::ReportProgress(progress) => {
+                        match update_report_progress(progress, &mut report_status_cache, &database)
                             .await
                         {
-                            Ok(user_id) => {
-                                tracing::info!(
-                                    "sending report_status_update message to {user_id:?} for report {}",
-                                    report.id
-                                );
-                                if let Some(user_id) = user_id {
-                                    if let Some(sender) = user_connection_map.get(&user_id) {
-                                        let _ = sender.send(event_message).await.map_err(|err| {
-                                            tracing::error!(
-                                                "Failed to send message to {user_id:?}"
-                                            );
-                                            err
-                                        });
-                                    }
-                                }
+                            Ok(Some(user_id)) => {
+                                send_to_user(&mut subscribers, user_id, event_message).await;
                             }
+                            Ok(None) => {}
                             Err(err) => {
                                 tracing::error!("{err:?}");
                             }
                         }
                     }
-                    ServerSentEventMessage::NewReport(new_report) => {
-                        tracing::info!(
-                            "Storing report {} in the cache for user {}",
-                            new_report.report_id,
-                            new_report.user_id
-                        );
-                        report_status_cache.push(
-                            new_report.report_id,
-                            (new_report.user_id, new_report.report_status),
+                    ServerSentEventMessage::ReportDeleted(_) => {
+                        tracing::warn!(
+                            "deleted reports should be sent as `AppEvent::ReportDeleted`"
                         );
-
-                        tracing::info!(
-                            "Storing report {} in the database for user {}",
-                            new_report.report_id,
-                            new_report.user_id
-                        );
-                        if let Err(err) = database.insert_report(new_report.clone()).await {
-                            tracing::error!("could not store the report in the database {err:?}");
-                            // TODO(ytmimi) probably should add the report to some dead letter
-                            // queue so we can add it to the database later
-                        }
                     }
                 }
             }
@@ -88,8 +281,282 @@ where
     }
 }
 
+/// Everyone who wants to hear about report changes
+struct Subscribers {
+    /// Users can have more than one connection open, e.g. multiple browser tabs
+    users: HashMap<Uuid, Vec<UserConnection>>,
+    /// Connections that only want to hear about a single report
+    reports: HashMap<Uuid, Vec<Sender<ServerSentEventMessage>>>,
+    /// Admins watching every report transition
+    admins: Vec<(AdminFilter, Sender<ReportTransition>)>,
+    /// Connections that want to know when users join and leave
+    presence: Vec<Sender<PresenceEvent>>,
+    /// Hands transitions to the webhook delivery task
+    webhooks: Sender<ReportTransition>,
+    /// Recent events sent to each user, including long-polling requests waiting for the next one
+    events: EventLog,
+}
+
+/// One of a user's open connections
+struct UserConnection {
+    sender: Sender<LoggedEvent>,
+    connected_at: DateTime<Utc>,
+}
+
+/// Send a successful status change to the report's owner, its subscribers, any admins, and the
+/// owner's webhooks
+async fn broadcast_status_update(
+    state_machine: &StateMachine,
+    subscribers: &mut Subscribers,
+    user_id: Uuid,
+    old_status: ReportStatus,
+    update: &ReportStatusUpdate,
+) {
+    tracing::info!(
+        "sending report_status_update message to {user_id:?} for report {}",
+        update.id
+    );
+
+    let transition = ReportTransition {
+        user_id,
+        report_id: update.id,
+        old_status,
+        new_status: update.status,
+        source: update.source,
+        reason: update.reason.clone(),
+    };
+    send_to_admins(&subscribers.admins, &transition);
+    // Webhook deliveries can take a while, so they happen on their own task
+    if let Err(TrySendError::Full(transition)) = subscribers.webhooks.try_send(transition) {
+        tracing::warn!("webhook deliveries are falling behind. Dropping {transition:?}");
+    }
+
+    let message = ServerSentEventMessage::ReportStatusUpdate(update.clone());
+
+    if state_machine.is_terminal(update.status) {
+        // The report won't change again, so there's nothing left
+        // to stream. Dropping the subscribers closes their streams
+        if let Some(report_subscribers) = subscribers.reports.remove(&update.id) {
+            send_to_all(&report_subscribers, message.clone()).await;
+        }
+    } else if let Some(report_subscribers) = subscribers.reports.get(&update.id) {
+        send_to_all(report_subscribers, message.clone()).await;
+    }
+
+    send_to_user(subscribers, user_id, message).await;
+}
+
+/// Log a message for the user and send it to all of their open connections
+async fn send_to_user(
+    subscribers: &mut Subscribers,
+    user_id: Uuid,
+    message: ServerSentEventMessage,
+) {
+    let event = subscribers.events.push(user_id, message);
+    if let Some(connections) = subscribers.users.get(&user_id) {
+        for connection in connections {
+            let _ = connection
+                .sender
+                .send(event.clone())
+                .await
+                .inspect_err(|_| {
+                    tracing::error!("Failed to send message to connection");
+                });
+        }
+    }
+}
+
+async fn send_to_all(senders: &[Sender<ServerSentEventMessage>], message: ServerSentEventMessage) {
+    for sender in senders {
+        let _ = sender.send(message.clone()).await.inspect_err(|_| {
+            tracing::error!("Failed to send message to connection");
+        });
+    }
+}
+
+/// Send a transition to every admin whose filter matches.
+///
+/// The admin stream sees every transition in the app, so we don't wait on slow admin connections.
+/// Holding up this loop would delay events for every other user.
+fn send_to_admins(
+    admin_subscriptions: &[(AdminFilter, Sender<ReportTransition>)],
+    transition: &ReportTransition,
+) {
+    for (filter, sender) in admin_subscriptions {
+        if !filter.matches(transition) {
+            continue;
+        }
+        if let Err(TrySendError::Full(_)) = sender.try_send(transition.clone()) {
+            tracing::warn!("admin connection is falling behind. Dropping {transition:?}");
+        }
+    }
+}
+
+/// Tell everyone who opted in that a user joined or left.
+///
+/// Like the admin stream this doesn't wait on slow connections. Subscribers are only removed
+/// here, once their streams have been dropped.
+fn send_to_presence_subscribers(presence: &mut Vec<Sender<PresenceEvent>>, event: PresenceEvent) {
+    presence.retain(|sender| match sender.try_send(event.clone()) {
+        Ok(()) => true,
+        Err(TrySendError::Full(_)) => {
+            tracing::warn!("presence connection is falling behind. Dropping {event:?}");
+            true
+        }
+        Err(TrySendError::Closed(_)) => false,
+    });
+}
+
+/// How many times a status update is validated again after the report changed underneath it
+const STATUS_UPDATE_ATTEMPTS: usize = 3;
+
+/// Validate and store a status update.
+///
+/// The update is only written if the report still has the status it was validated against.
+/// Workers change statuses outside of this loop, so when the status changed the update is
+/// validated again against the status in the database.
+///
+/// On success returns the owner of the report and the report's previous status.
 async fn update_report_status<D>(
     report_status_update: &ReportStatusUpdate,
+    state_machine: &StateMachine,
+    report_status_cache: &mut LruCache<Uuid, (Uuid, ReportStatus)>,
+    database: &D,
+) -> Result<(Uuid, ReportStatus), ReportStatusError>
+where
+    D: super::database::Database,
+    <D as super::database::Database>::Error: std::fmt::Debug,
+{
+    for _ in 0..STATUS_UPDATE_ATTEMPTS {
+        let Some(current_status) =
+            get_current_report_status(report_status_update.id, report_status_cache, database).await
+        else {
+            return Err(ReportStatusError::ReportNotFound(
+                report_status_update.id,
+                report_status_update.status,
+            ));
+        };
+
+        if let Some(expected) = report_status_update.expected_status {
+            if expected != current_status {
+                return Err(ReportStatusError::UnexpectedStatus {
+                    expected,
+                    current: current_status,
+                });
+            }
+        }
+
+        let new_status = state_machine.transition(current_status, report_status_update.status)?;
+
+        let user_id = database
+            .update_report_status(current_status, report_status_update)
+            .await
+            .map_err(|err| {
+                // log the error if we could not write to the DB
+                tracing::error!("could not updated the database: {err:?}");
+                ReportStatusError::DatabaseUpdateFailed
+            })?;
+
+        let Some(user_id) = user_id else {
+            // The report was deleted or changed after we cached its status
+            report_status_cache.pop(&report_status_update.id);
+            continue;
+        };
+
+        // The write succeeded against the status we expected, so the cache is up to date
+        report_status_cache.push(report_status_update.id, (user_id, new_status));
+        return Ok((user_id, current_status));
+    }
+
+    tracing::error!(
+        "report {} kept changing while writing {:?}",
+        report_status_update.id,
+        report_status_update.status
+    );
+    Err(ReportStatusError::DatabaseUpdateFailed)
+}
+
+/// Validate and store many status updates.
+///
+/// Returns the outcome of every update in order, along with the owner and previous status of
+/// each report that was changed.
+async fn update_report_statuses<D>(
+    updates: Vec<ReportStatusUpdate>,
+    state_machine: &StateMachine,
+    report_status_cache: &mut LruCache<Uuid, (Uuid, ReportStatus)>,
+    database: &D,
+) -> (
+    Vec<BulkStatusUpdateResult>,
+    Vec<(Uuid, ReportStatus, ReportStatusUpdate)>,
+)
+where
+    D: super::database::Database,
+    <D as super::database::Database>::Error: std::fmt::Debug,
+{
+    let mut results = Vec::with_capacity(updates.len());
+    // index into `results`, owner, and current status of every valid update
+    let mut valid = vec![];
+    let mut seen = HashSet::new();
+
+    for update in updates {
+        if !seen.insert(update.id) {
+            results.push(BulkStatusUpdateResult::rejected(
+                &update,
+                "the report appears more than once in the batch",
+            ));
+            continue;
+        }
+
+        let Some((user_id, current_status)) =
+            get_report_owner_and_status(update.id, report_status_cache, database).await
+        else {
+            let err = ReportStatusError::ReportNotFound(update.id, update.status);
+            results.push(BulkStatusUpdateResult::rejected(&update, err));
+            continue;
+        };
+
+        match state_machine.transition(current_status, update.status) {
+            Ok(_) => {
+                results.push(BulkStatusUpdateResult::applied(&update));
+                valid.push((results.len() - 1, user_id, current_status, update));
+            }
+            Err(err) => results.push(BulkStatusUpdateResult::rejected(&update, err)),
+        }
+    }
+
+    let mut applied = vec![];
+    for chunk in valid.chunks(MAX_TRANSACTION_SIZE) {
+        let updates: Vec<_> = chunk
+            .iter()
+            .map(|(_, _, current_status, update)| (*current_status, update.clone()))
+            .collect();
+
+        match database.update_report_statuses(&updates).await {
+            Ok(()) => {
+                for (_, user_id, current_status, update) in chunk {
+                    report_status_cache.push(update.id, (*user_id, update.status));
+                    applied.push((*user_id, *current_status, update.clone()));
+                }
+            }
+            Err(err) => {
+                // One of the reports might have changed since we cached its status
+                tracing::error!("could not updated the database: {err:?}");
+                for (index, _, _, update) in chunk {
+                    report_status_cache.pop(&update.id);
+                    results[*index] = BulkStatusUpdateResult::rejected(
+                        update,
+                        ReportStatusError::DatabaseUpdateFailed,
+                    );
+                }
+            }
+        }
+    }
+
+    (results, applied)
+}
+
+async fn update_report_progress<D>(
+    report_progress: &ReportProgress,
     report_status_cache: &mut LruCache<Uuid, (Uuid, ReportStatus)>,
     database: &D,
 ) -> Result<Option<Uuid>, ReportStatusError>
@@ -98,31 +565,28 @@ where
     <D as super::database::Database>::Error: std::fmt::Debug,
 {
     let Some(current_status) =
-        get_current_report_status(report_status_update.id, report_status_cache, database).await
+        get_current_report_status(report_progress.id, report_status_cache, database).await
     else {
         return Err(ReportStatusError::ReportNotFound(
-            report_status_update.id,
-            report_status_update.status,
+            report_progress.id,
+            ReportStatus::Processing,
         ));
     };
 
-    let new_status = current_status.transition(report_status_update.status)?;
+    if current_status != ReportStatus::Processing {
+        return Err(ReportStatusError::ProgressWhileNotProcessing(
+            report_progress.id,
+            current_status,
+        ));
+    }
 
-    let user_id = database
-        .update_report_status(report_status_update)
+    database
+        .update_report_progress(report_progress)
         .await
         .map_err(|err| {
-            // log the error if we could not write to the DB
             tracing::error!("could not updated the database: {err:?}");
             ReportStatusError::DatabaseUpdateFailed
-        })?;
-
-    if let Some(user_id) = user_id {
-        // update the status in the cache.
-        report_status_cache.push(report_status_update.id, (user_id, new_status));
-    }
-
-    Ok(user_id)
+        })
 }
 
 async fn get_current_report_status<D>(
@@ -143,20 +607,135 @@ where
     database.get_report_status(report_id).await.ok()?
 }
 
-/// Helper task that notifies the main async loop that a user has disconnected
-pub(super) async fn handle_user_disconnect(
+/// Like [get_current_report_status], but also returns who owns the report
+async fn get_report_owner_and_status<D>(
+    report_id: Uuid,
+    report_status_cache: &mut LruCache<Uuid, (Uuid, ReportStatus)>,
+    database: &D,
+) -> Option<(Uuid, ReportStatus)>
+where
+    D: super::database::Database,
+    <D as super::database::Database>::Error: std::fmt::Debug,
+{
+    if let Some(cached) = report_status_cache.get(&report_id) {
+        return Some(*cached);
+    }
+
+    let report = database.get_report(report_id).await.ok()??;
+    let owner_and_status = (report.user_id, report.report_status);
+    report_status_cache.push(report_id, owner_and_status);
+    Some(owner_and_status)
+}
+
+/// Helper task that notifies the main async loop that a connection was closed.
+///
+/// `closed` is sent to the main loop once the receiving end of `sse_sender` is dropped.
+pub(super) async fn handle_disconnect<T>(
     app_command_sender: Sender<AppEvent>,
-    user_sse_sender: Sender<ServerSentEventMessage>,
-    user_id: Uuid,
+    sse_sender: Sender<T>,
+    closed: AppEvent,
 ) {
     // `closed()` will wait for the receiving end of the stream to be dropped.
     // The receiving end is dropped when the user disconnects from the server
-    user_sse_sender.closed().await;
-
-    let closed = AppEvent::UserDisconnected { user_id };
+    sse_sender.closed().await;
 
     if let Err(err) = app_command_sender.send(closed).await {
         tracing::error!("{err:?}");
-        tracing::warn!("Can't issue closed event for {user_id:?}");
+        tracing::warn!("Can't issue closed event");
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::v4::app_events::{NewReport, Report};
+    use crate::v4::database::Database;
+    use crate::v4::memory::InMemoryDatabase;
+
+    #[tokio::test]
+    async fn test_status_updates_are_checked_against_the_stored_status() {
+        let database = InMemoryDatabase::new();
+        let state_machine = StateMachine::default();
+        let mut report = Report::new(
+            Uuid::new_v4(),
+            NewReport::new("raced".to_owned(), Default::default()),
+        );
+        report.report_status = ReportStatus::Queued;
+        let report_id = report.report_id;
+        database.insert_report(report.clone()).await.unwrap();
+
+        // A worker claimed the report behind the cache's back
+        let mut cache = LruCache::new(NonZeroUsize::new(10).unwrap());
+        cache.push(report_id, (report.user_id, ReportStatus::Queued));
+        let claimed = ReportStatusUpdate::new(report_id, ReportStatus::Processing);
+        let written = database
+            .update_report_status(ReportStatus::Queued, &claimed)
+            .await;
+        assert_eq!(written.unwrap(), Some(report.user_id));
+
+        // A cancel is fine for the cached `queued`, but not once the report is `processing`
+        let update = ReportStatusUpdate::new(report_id, ReportStatus::Canceled);
+        let result = update_report_status(&update, &state_machine, &mut cache, &database).await;
+        assert!(matches!(
+            result,
+            Err(ReportStatusError::InvalidStatusTransition {
+                current: ReportStatus::Processing,
+                next_status: ReportStatus::Canceled,
+            })
+        ));
+        let stored = database.get_report_status(report_id).await.unwrap();
+        assert_eq!(stored, Some(ReportStatus::Processing));
+        // The stale status was dropped from the cache
+        assert!(cache.get(&report_id).is_none());
+
+        let update = ReportStatusUpdate::new(report_id, ReportStatus::Completed);
+        let result = update_report_status(&update, &state_machine, &mut cache, &database).await;
+        assert_eq!(result.unwrap(), (report.user_id, ReportStatus::Processing));
+        assert_eq!(
+            cache.get(&report_id),
+            Some(&(report.user_id, ReportStatus::Completed))
+        );
+
+        // The report is deleted while the cache still thinks it's being processed
+        cache.push(report_id, (report.user_id, ReportStatus::Processing));
+        database.delete_report(report_id).await.unwrap();
+        let update = ReportStatusUpdate::new(report_id, ReportStatus::Completed);
+        let result = update_report_status(&update, &state_machine, &mut cache, &database).await;
+        assert!(matches!(result, Err(ReportStatusError::ReportNotFound(..))));
+        assert!(cache.get(&report_id).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_updates_for_another_status_are_left_alone() {
+        let database = InMemoryDatabase::new();
+        let state_machine = StateMachine::default();
+        let mut report = Report::new(
+            Uuid::new_v4(),
+            NewReport::new("picked up".to_owned(), Default::default()),
+        );
+        report.report_status = ReportStatus::Processing;
+        let report_id = report.report_id;
+        database.insert_report(report).await.unwrap();
+        let mut cache = LruCache::new(NonZeroUsize::new(10).unwrap());
+
+        // The watchdog found the report stuck in `queued`, but a worker picked it up since
+        let mut update = ReportStatusUpdate::new(report_id, ReportStatus::Failed);
+        update.expected_status = Some(ReportStatus::Queued);
+        let result = update_report_status(&update, &state_machine, &mut cache, &database).await;
+        assert!(matches!(
+            result,
+            Err(ReportStatusError::UnexpectedStatus {
+                expected: ReportStatus::Queued,
+                current: ReportStatus::Processing,
+            })
+        ));
+        let stored = database.get_report_status(report_id).await.unwrap();
+        assert_eq!(stored, Some(ReportStatus::Processing));
+
+        update.expected_status = Some(ReportStatus::Processing);
+        let result = update_report_status(&update, &state_machine, &mut cache, &database).await;
+        assert!(result.is_ok());
+        let stored = database.get_report_status(report_id).await.unwrap();
+        assert_eq!(stored, Some(ReportStatus::Failed));
     }
 }
@@ -0,0 +1,178 @@
+//! Background task that fails reports which have been stuck in the same status for too long,
+//! e.g. because the worker processing them died.
+use std::time::Duration;
+
+use chrono::Utc;
+use tokio::sync::mpsc::Sender;
+
+use super::app_events::{AppEvent, ReportStatusUpdate, UpdateSource};
+use super::database::Database;
+use super::report_status::ReportStatus;
+use crate::config::WatchdogConfig;
+
+/// Stuck reports are failed through the app's event loop like any other status update, so the
+/// state machine still decides whether they can fail. The update only applies while the report
+/// has the stale status, so a report that moved on in the meantime is left alone
+pub(super) async fn watch_for_stuck_reports<D>(
+    app_event_sender: Sender<AppEvent>,
+    database: D,
+    config: WatchdogConfig,
+) where
+    D: Database,
+    <D as Database>::Error: std::fmt::Debug,
+{
+    if config.timeouts.is_empty() {
+        tracing::info!("report watchdog is disabled");
+        return;
+    }
+
+    let mut interval = tokio::time::interval(config.interval);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+    loop {
+        interval.tick().await;
+
+        for (status, timeout) in &config.timeouts {
+            let Ok(changed_before) = chrono::Duration::from_std(*timeout).map(|t| Utc::now() - t)
+            else {
+                continue;
+            };
+
+            let reports = match database.list_stale_reports(*status, changed_before).await {
+                Ok(reports) => reports,
+                Err(err) => {
+                    tracing::error!("could not look for stuck {status} reports: {err:?}");
+                    continue;
+                }
+            };
+
+            for report in reports {
+                let mut update = ReportStatusUpdate::new(report.report_id(), ReportStatus::Failed);
+                update.source = UpdateSource::Watchdog;
+                update.reason = Some(format!(
+                    "timed out after {} in {status}",
+                    format_timeout(*timeout)
+                ));
+                update.expected_status = Some(*status);
+
+                tracing::info!(
+                    "failing report {} after {} in {status}",
+                    report.report_id(),
+                    format_timeout(*timeout)
+                );
+
+                let event = AppEvent::report_status_update_message(update);
+                if app_event_sender.send(event).await.is_err() {
+                    // The app is shutting down
+                    return;
+                }
+            }
+        }
+    }
+}
+
+fn format_timeout(timeout: Duration) -> String {
+    let secs = timeout.as_secs();
+    match secs {
+        _ if secs.is_multiple_of(3600) => format!("{}h", secs / 3600),
+        _ if secs.is_multiple_of(60) => format!("{}m", secs / 60),
+        _ => format!("{secs}s"),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::Utc;
+    use tokio::sync::mpsc;
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::v4::app_events::{NewReport, Report, ServerSentEventMessage};
+    use crate::v4::memory::InMemoryDatabase;
+
+    async fn insert_report(
+        database: &InMemoryDatabase,
+        status: ReportStatus,
+        age: chrono::Duration,
+    ) -> Report {
+        let mut report = Report::new(
+            Uuid::new_v4(),
+            NewReport::new("stuck".to_owned(), Default::default()),
+        );
+        report.report_status = status;
+        report.status_changed_at = Utc::now() - age;
+        database.insert_report(report.clone()).await.unwrap();
+        report
+    }
+
+    #[tokio::test]
+    async fn test_stuck_reports_are_failed() {
+        let database = InMemoryDatabase::new();
+        let hour = chrono::Duration::hours(1);
+        let stuck = insert_report(&database, ReportStatus::Queued, hour).await;
+        let recent = insert_report(&database, ReportStatus::Queued, chrono::Duration::zero()).await;
+        let done = insert_report(&database, ReportStatus::Completed, hour).await;
+
+        let config = WatchdogConfig {
+            interval: Duration::from_millis(10),
+            timeouts: vec![
+                (ReportStatus::Queued, Duration::from_secs(60)),
+                (ReportStatus::Processing, Duration::from_secs(60)),
+            ],
+        };
+        let (sender, mut receiver) = mpsc::channel(10);
+        let watchdog = tokio::spawn(watch_for_stuck_reports(sender, database.clone(), config));
+
+        let event = tokio::time::timeout(Duration::from_secs(2), receiver.recv())
+            .await
+            .expect("the stuck report is failed")
+            .unwrap();
+        let AppEvent::UserMessage(ServerSentEventMessage::ReportStatusUpdate(update)) = event
+        else {
+            panic!("expected a status update");
+        };
+        assert_eq!(update.id, stuck.report_id);
+        assert_eq!(update.status, ReportStatus::Failed);
+        assert_eq!(update.source, UpdateSource::Watchdog);
+        assert_eq!(update.expected_status, Some(ReportStatus::Queued));
+        assert_eq!(
+            update.reason.as_deref(),
+            Some("timed out after 1m in queued")
+        );
+
+        // The watchdog only asks for the report to fail. Until the event loop applies the update
+        // it's found again on the next pass, but nothing else is
+        let event = tokio::time::timeout(Duration::from_secs(2), receiver.recv())
+            .await
+            .expect("the stuck report is found again")
+            .unwrap();
+        let AppEvent::UserMessage(ServerSentEventMessage::ReportStatusUpdate(update)) = event
+        else {
+            panic!("expected a status update");
+        };
+        assert_eq!(update.id, stuck.report_id);
+
+        // Writing the update is left to the event loop
+        for (report, status) in [
+            (stuck, ReportStatus::Queued),
+            (recent, ReportStatus::Queued),
+            (done, ReportStatus::Completed),
+        ] {
+            let report = database
+                .get_report(report.report_id)
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(report.report_status, status);
+        }
+
+        watchdog.abort();
+    }
+
+    #[test]
+    fn test_format_timeout() {
+        assert_eq!(format_timeout(Duration::from_secs(7200)), "2h");
+        assert_eq!(format_timeout(Duration::from_secs(120)), "2m");
+        assert_eq!(format_timeout(Duration::from_secs(90)), "90s");
+    }
+}
@@ -0,0 +1,591 @@
+//! Outgoing webhooks for clients that can't keep a server sent event stream open.
+//!
+//! Users register URLs that every successful status change of their reports is POSTed to.
+//! Each request is signed with the webhook's secret so that receivers can check it came from us:
+//! the `X-Webhook-Signature` header holds `sha256=<hex HMAC-SHA256 of "{timestamp}.{body}">`, where
+//! `timestamp` is the value of the `X-Webhook-Timestamp` header.
+//!
+//! Failed deliveries are retried with exponential backoff, and every attempt is recorded so users
+//! can see what happened. Webhooks and deliveries are only kept in memory.
+//!
+//! Unless private targets are allowed, webhooks can't point at loopback, private or link-local
+//! addresses, so users can't make the app call internal services like the cloud metadata endpoint.
+//! Hostnames are checked when the webhook is registered and again on every delivery, since they
+//! can start resolving somewhere else.
+use std::collections::{HashMap, VecDeque};
+use std::fmt::Debug;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+
+use axum::extract::{Json, Path, State};
+use axum::http::StatusCode;
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use sha2::Sha256;
+use tokio::sync::mpsc::Receiver;
+use uuid::Uuid;
+
+use super::app_events::ReportTransition;
+use super::limits::RateLimited;
+use super::V4AppState;
+use crate::auth::UserId;
+use crate::config::WebhookConfig;
+
+pub(super) const SIGNATURE_HEADER: &str = "X-Webhook-Signature";
+pub(super) const TIMESTAMP_HEADER: &str = "X-Webhook-Timestamp";
+pub(super) const WEBHOOK_ID_HEADER: &str = "X-Webhook-Id";
+
+/// How many delivery attempts are remembered for each webhook
+const MAX_RECORDED_ATTEMPTS: usize = 100;
+
+#[derive(Debug, Clone, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(super) struct Webhook {
+    id: Uuid,
+    user_id: Uuid,
+    url: String,
+    /// Only returned when the webhook is registered
+    #[serde(skip_serializing)]
+    secret: String,
+    created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(super) struct DeliveryAttempt {
+    /// Shared by every attempt to deliver the same transition
+    delivery_id: Uuid,
+    report_id: Uuid,
+    attempt: u32,
+    attempted_at: DateTime<Utc>,
+    /// The webhook's response status, if it responded at all
+    #[serde(skip_serializing_if = "Option::is_none")]
+    status_code: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    success: bool,
+}
+
+/// Registered webhooks and their recent delivery attempts
+#[derive(Debug, Default)]
+pub(super) struct WebhookRegistry {
+    webhooks: HashMap<Uuid, Webhook>,
+    attempts: HashMap<Uuid, VecDeque<DeliveryAttempt>>,
+}
+
+pub(super) type SharedWebhookRegistry = Arc<Mutex<WebhookRegistry>>;
+
+impl WebhookRegistry {
+    fn user_webhooks(&self, user_id: Uuid) -> Vec<Webhook> {
+        let mut webhooks: Vec<_> = self
+            .webhooks
+            .values()
+            .filter(|webhook| webhook.user_id == user_id)
+            .cloned()
+            .collect();
+        webhooks.sort_by_key(|webhook| webhook.created_at);
+        webhooks
+    }
+
+    fn record_attempt(&mut self, webhook_id: Uuid, attempt: DeliveryAttempt) {
+        // The webhook may have been deleted while we were delivering to it
+        if !self.webhooks.contains_key(&webhook_id) {
+            return;
+        }
+        let attempts = self.attempts.entry(webhook_id).or_default();
+        if attempts.len() == MAX_RECORDED_ATTEMPTS {
+            attempts.pop_front();
+        }
+        attempts.push_back(attempt);
+    }
+
+    fn is_registered(&self, webhook_id: Uuid) -> bool {
+        self.webhooks.contains_key(&webhook_id)
+    }
+}
+
+fn lock(registry: &SharedWebhookRegistry) -> std::sync::MutexGuard<'_, WebhookRegistry> {
+    // Nothing panics while holding the lock, but if something did the registry is still usable
+    registry
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub(super) struct NewWebhook {
+    url: String,
+}
+
+#[derive(Debug, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(super) struct RegisteredWebhook {
+    #[serde(flatten)]
+    webhook: Webhook,
+    /// Used to verify the signature of each delivery. It can't be fetched again later
+    secret: String,
+}
+
+fn generate_secret() -> String {
+    let mut secret = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut secret);
+    hex(&secret)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+/// `sha256=<hex HMAC>` of the timestamp and body, joined with a `.`
+pub(super) fn sign(secret: &str, timestamp: &str, body: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any size");
+    mac.update(timestamp.as_bytes());
+    mac.update(b".");
+    mac.update(body);
+    format!("sha256={}", hex(&mac.finalize().into_bytes()))
+}
+
+/// Addresses on the app's own host or network
+fn is_private_address(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            // Link-local includes the cloud metadata endpoint, 169.254.169.254
+            ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+        }
+        IpAddr::V6(ip) => {
+            let unique_local = (ip.segments()[0] & 0xfe00) == 0xfc00;
+            let link_local = (ip.segments()[0] & 0xffc0) == 0xfe80;
+            ip.is_loopback()
+                || ip.is_unspecified()
+                || unique_local
+                || link_local
+                || ip
+                    .to_ipv4_mapped()
+                    .is_some_and(|ip| is_private_address(IpAddr::V4(ip)))
+        }
+    }
+}
+
+/// Make sure the webhook doesn't point at a private address, whether directly or through DNS
+async fn check_public_target(url: &reqwest::Url) -> Result<(), String> {
+    let Some(host) = url.host_str() else {
+        return Err("the webhook url has no host".to_owned());
+    };
+    // IPv6 hosts are written in brackets
+    let host = host
+        .strip_prefix('[')
+        .and_then(|host| host.strip_suffix(']'))
+        .unwrap_or(host);
+    let addresses: Vec<IpAddr> = match host.parse() {
+        Ok(ip) => vec![ip],
+        Err(_) => {
+            let port = url.port_or_known_default().unwrap_or(80);
+            tokio::net::lookup_host((host, port))
+                .await
+                .map_err(|err| format!("unable to resolve {host}: {err}"))?
+                .map(|address| address.ip())
+                .collect()
+        }
+    };
+
+    match addresses.into_iter().find(|ip| is_private_address(*ip)) {
+        Some(ip) => Err(format!(
+            "webhooks can't be sent to private addresses like {ip}"
+        )),
+        None => Ok(()),
+    }
+}
+
+/// Resolves hostnames for webhook deliveries, leaving out private addresses
+#[derive(Debug)]
+struct PublicOnlyResolver;
+
+impl reqwest::dns::Resolve for PublicOnlyResolver {
+    fn resolve(&self, name: reqwest::dns::Name) -> reqwest::dns::Resolving {
+        Box::pin(async move {
+            let addresses: Vec<SocketAddr> = tokio::net::lookup_host((name.as_str(), 0))
+                .await?
+                .filter(|address| !is_private_address(address.ip()))
+                .collect();
+            if addresses.is_empty() {
+                return Err(format!("{} only resolves to private addresses", name.as_str()).into());
+            }
+            Ok(Box::new(addresses.into_iter()) as reqwest::dns::Addrs)
+        })
+    }
+}
+
+/// Register a new webhook for the user
+pub(super) async fn create_webhook<D>(
+    State(state): State<V4AppState<D>>,
+    RateLimited(user_id): RateLimited,
+    Json(new_webhook): Json<NewWebhook>,
+) -> Result<(StatusCode, Json<RegisteredWebhook>), (StatusCode, String)> {
+    let config = &state.config.webhooks;
+    match reqwest::Url::parse(&new_webhook.url) {
+        Ok(url) if matches!(url.scheme(), "http" | "https") => {
+            if !config.allow_private_targets {
+                check_public_target(&url)
+                    .await
+                    .map_err(|err| (StatusCode::BAD_REQUEST, err))?;
+            }
+        }
+        Ok(url) => {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                format!("unsupported webhook scheme {:?}", url.scheme()),
+            ))
+        }
+        Err(err) => {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                format!("invalid webhook url: {err}"),
+            ))
+        }
+    }
+
+    let webhook = Webhook {
+        id: Uuid::new_v4(),
+        user_id,
+        url: new_webhook.url,
+        secret: generate_secret(),
+        created_at: Utc::now(),
+    };
+
+    {
+        let mut registry = lock(&state.webhooks);
+        if registry.user_webhooks(user_id).len() >= config.max_per_user {
+            return Err((
+                StatusCode::CONFLICT,
+                format!(
+                    "users can register at most {} webhooks",
+                    config.max_per_user
+                ),
+            ));
+        }
+        registry.webhooks.insert(webhook.id, webhook.clone());
+    }
+    tracing::info!(
+        "registered webhook {} for user {}",
+        webhook.id,
+        webhook.user_id
+    );
+
+    let secret = webhook.secret.clone();
+    Ok((
+        StatusCode::CREATED,
+        Json(RegisteredWebhook { webhook, secret }),
+    ))
+}
+
+/// List the user's webhooks. Secrets aren't included
+pub(super) async fn list_webhooks<D>(
+    State(state): State<V4AppState<D>>,
+    UserId(user_id): UserId,
+) -> Json<Vec<Webhook>> {
+    Json(lock(&state.webhooks).user_webhooks(user_id))
+}
+
+fn check_owner(
+    registry: &WebhookRegistry,
+    webhook_id: Uuid,
+    user_id: Uuid,
+) -> Result<(), (StatusCode, String)> {
+    match registry.webhooks.get(&webhook_id) {
+        None => Err((
+            StatusCode::NOT_FOUND,
+            format!("webhook {webhook_id} not found"),
+        )),
+        Some(webhook) if webhook.user_id != user_id => Err((
+            StatusCode::FORBIDDEN,
+            format!("webhook {webhook_id} belongs to another user"),
+        )),
+        Some(_) => Ok(()),
+    }
+}
+
+pub(super) async fn delete_webhook<D>(
+    State(state): State<V4AppState<D>>,
+    Path(webhook_id): Path<Uuid>,
+    RateLimited(user_id): RateLimited,
+) -> Result<StatusCode, (StatusCode, String)> {
+    let mut registry = lock(&state.webhooks);
+    check_owner(&registry, webhook_id, user_id)?;
+    registry.webhooks.remove(&webhook_id);
+    registry.attempts.remove(&webhook_id);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Recent delivery attempts for one of the user's webhooks, oldest first
+pub(super) async fn list_deliveries<D>(
+    State(state): State<V4AppState<D>>,
+    Path(webhook_id): Path<Uuid>,
+    UserId(user_id): UserId,
+) -> Result<Json<Vec<DeliveryAttempt>>, (StatusCode, String)> {
+    let registry = lock(&state.webhooks);
+    check_owner(&registry, webhook_id, user_id)?;
+    let attempts = registry
+        .attempts
+        .get(&webhook_id)
+        .map(|attempts| attempts.iter().cloned().collect())
+        .unwrap_or_default();
+    Ok(Json(attempts))
+}
+
+/// Body of every webhook request
+#[derive(Debug, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+struct WebhookPayload<'a> {
+    event: &'static str,
+    delivery_id: Uuid,
+    #[serde(flatten)]
+    transition: &'a ReportTransition,
+}
+
+/// Deliver every transition to the owner's webhooks.
+///
+/// Each delivery runs on its own task so that a slow or failing webhook doesn't hold up the rest.
+pub(super) async fn deliver_webhooks(
+    mut receiver: Receiver<ReportTransition>,
+    registry: SharedWebhookRegistry,
+    config: WebhookConfig,
+) {
+    let mut client = reqwest::Client::builder().timeout(config.timeout);
+    if !config.allow_private_targets {
+        // A redirect could point anywhere, so a webhook has to answer itself
+        client = client
+            .dns_resolver(Arc::new(PublicOnlyResolver))
+            .redirect(reqwest::redirect::Policy::none());
+    }
+    let client = match client.build() {
+        Ok(client) => client,
+        Err(err) => {
+            tracing::error!("unable to create the webhook client. {err:?}");
+            return;
+        }
+    };
+
+    while let Some(transition) = receiver.recv().await {
+        let webhooks = lock(&registry).user_webhooks(transition.user_id);
+        if webhooks.is_empty() {
+            continue;
+        }
+
+        let transition = Arc::new(transition);
+        for webhook in webhooks {
+            tokio::spawn(deliver(
+                client.clone(),
+                registry.clone(),
+                config.clone(),
+                webhook,
+                transition.clone(),
+            ));
+        }
+    }
+}
+
+async fn deliver(
+    client: reqwest::Client,
+    registry: SharedWebhookRegistry,
+    config: WebhookConfig,
+    webhook: Webhook,
+    transition: Arc<ReportTransition>,
+) {
+    let delivery_id = Uuid::new_v4();
+    let payload = WebhookPayload {
+        event: "report_transition",
+        delivery_id,
+        transition: &transition,
+    };
+    let body = serde_json::to_vec(&payload).expect("webhook payloads serialize to json");
+
+    let mut backoff = config.initial_backoff;
+    for attempt in 1..=config.max_attempts {
+        let timestamp = Utc::now().timestamp().to_string();
+        let result = client
+            .post(&webhook.url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .header(WEBHOOK_ID_HEADER, webhook.id.to_string())
+            .header(TIMESTAMP_HEADER, &timestamp)
+            .header(SIGNATURE_HEADER, sign(&webhook.secret, &timestamp, &body))
+            .body(body.clone())
+            .send()
+            .await;
+
+        let (status_code, error) = match &result {
+            Ok(response) if response.status().is_success() => (Some(response.status()), None),
+            Ok(response) => (
+                Some(response.status()),
+                Some(format!("webhook responded with {}", response.status())),
+            ),
+            Err(err) => (err.status(), Some(err.to_string())),
+        };
+        let success = error.is_none();
+
+        {
+            let mut registry = lock(&registry);
+            registry.record_attempt(
+                webhook.id,
+                DeliveryAttempt {
+                    delivery_id,
+                    report_id: transition.report_id,
+                    attempt,
+                    attempted_at: Utc::now(),
+                    status_code: status_code.map(|status| status.as_u16()),
+                    error: error.clone(),
+                    success,
+                },
+            );
+
+            if success || !registry.is_registered(webhook.id) {
+                return;
+            }
+        }
+
+        tracing::warn!(
+            "attempt {attempt} to deliver {delivery_id} to webhook {} failed. {}",
+            webhook.id,
+            error.unwrap_or_default()
+        );
+
+        if attempt < config.max_attempts {
+            tokio::time::sleep(backoff).await;
+            backoff = (backoff * 2).min(config.max_backoff);
+        }
+    }
+
+    tracing::error!(
+        "giving up on delivering {delivery_id} to webhook {} after {} attempts",
+        webhook.id,
+        config.max_attempts
+    );
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::v4::app_events::UpdateSource;
+    use crate::v4::report_status::ReportStatus;
+    use axum::http::HeaderMap;
+    use axum::routing::post;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    type Request = (HeaderMap, Vec<u8>);
+
+    #[derive(Clone, Default)]
+    struct StandIn {
+        requests: Arc<Mutex<Vec<Request>>>,
+        calls: Arc<AtomicUsize>,
+    }
+
+    /// Fails the first request and accepts the rest
+    async fn stand_in_handler(
+        State(stand_in): State<StandIn>,
+        headers: HeaderMap,
+        body: axum::body::Bytes,
+    ) -> StatusCode {
+        stand_in
+            .requests
+            .lock()
+            .unwrap()
+            .push((headers, body.to_vec()));
+        if stand_in.calls.fetch_add(1, Ordering::SeqCst) == 0 {
+            StatusCode::SERVICE_UNAVAILABLE
+        } else {
+            StatusCode::OK
+        }
+    }
+
+    #[tokio::test]
+    async fn test_delivery_is_signed_retried_and_recorded() {
+        let stand_in = StandIn::default();
+        let app = axum::Router::new()
+            .route("/hook", post(stand_in_handler))
+            .with_state(stand_in.clone());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let webhook = Webhook {
+            id: Uuid::new_v4(),
+            user_id: Uuid::new_v4(),
+            url: format!("http://{address}/hook"),
+            secret: generate_secret(),
+            created_at: Utc::now(),
+        };
+        let registry = SharedWebhookRegistry::default();
+        lock(&registry).webhooks.insert(webhook.id, webhook.clone());
+
+        let transition = ReportTransition {
+            user_id: webhook.user_id,
+            report_id: Uuid::new_v4(),
+            old_status: ReportStatus::Processing,
+            new_status: ReportStatus::Completed,
+            source: UpdateSource::Api,
+            reason: None,
+        };
+        let config = WebhookConfig {
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(10),
+            ..Default::default()
+        };
+
+        deliver(
+            reqwest::Client::new(),
+            registry.clone(),
+            config,
+            webhook.clone(),
+            Arc::new(transition.clone()),
+        )
+        .await;
+
+        let requests = stand_in.requests.lock().unwrap();
+        assert_eq!(requests.len(), 2);
+        for (headers, body) in requests.iter() {
+            let timestamp = headers[TIMESTAMP_HEADER].to_str().unwrap();
+            assert_eq!(
+                headers[SIGNATURE_HEADER].to_str().unwrap(),
+                sign(&webhook.secret, timestamp, body)
+            );
+            let payload: serde_json::Value = serde_json::from_slice(body).unwrap();
+            assert_eq!(payload["event"], "report_transition");
+            assert_eq!(payload["reportId"], transition.report_id.to_string());
+            assert_eq!(payload["newStatus"], "completed");
+        }
+
+        let registry = lock(&registry);
+        let attempts = &registry.attempts[&webhook.id];
+        assert_eq!(attempts.len(), 2);
+        assert_eq!(attempts[0].status_code, Some(503));
+        assert!(!attempts[0].success);
+        assert_eq!(attempts[1].attempt, 2);
+        assert!(attempts[1].success);
+        assert_eq!(attempts[0].delivery_id, attempts[1].delivery_id);
+    }
+
+    #[tokio::test]
+    async fn test_private_targets_are_rejected() {
+        for url in [
+            "http://127.0.0.1:8080/hook",
+            "http://localhost/hook",
+            "http://10.0.0.5/hook",
+            "http://192.168.1.1/hook",
+            "http://169.254.169.254/latest/meta-data",
+            "http://[::1]/hook",
+            "http://[fd00::1]/hook",
+            "http://[::ffff:172.16.0.1]/hook",
+        ] {
+            let url = reqwest::Url::parse(url).unwrap();
+            assert!(check_public_target(&url).await.is_err(), "{url}");
+        }
+
+        let url = reqwest::Url::parse("https://93.184.216.34/hook").unwrap();
+        assert!(check_public_target(&url).await.is_ok());
+    }
+}
@@ -0,0 +1,251 @@
+//! WebSocket transport for clients that can't use Server Sent Events, e.g. because a proxy
+//! buffers `text/event-stream` responses.
+//!
+//! The connection is registered with the event loop just like [sse_handler_v4], and every
+//! message is sent as a JSON text frame with the same payload the SSE stream would send:
+//!
+//! ```json
+//! {"id": 1700000000001, "event": "report_status_update", "data": {"id": "...", "status": "processing", "source": "kafka"}}
+//! ```
+//!
+//! Clients can also change the status of their own reports over the socket:
+//!
+//! ```json
+//! {"command": "change_report_status", "id": "...", "status": "canceled"}
+//! ```
+//!
+//! [sse_handler_v4]: super::request_handlers::sse_handler_v4
+use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
+use axum::extract::State;
+use axum::response::{IntoResponse, Response};
+use std::fmt::Debug;
+use std::time::Duration;
+use tokio::sync::mpsc::{channel, Receiver, Sender};
+use uuid::Uuid;
+
+use super::app_events::{AppEvent, ReportStatusUpdate, UpdateSource};
+use super::database::Database;
+use super::event_log::LoggedEvent;
+use super::limits::ConnectionGuard;
+use super::request_handlers::{event_payload, get_owned_report};
+use super::tasks::handle_disconnect;
+use super::V4AppState;
+use crate::auth::UserId;
+
+/// How often the server pings the client to keep the connection open
+const PING_INTERVAL: Duration = Duration::from_secs(30);
+
+/// A message sent from the server to the client
+#[derive(Debug, serde::Serialize)]
+struct OutgoingMessage<'a> {
+    /// The event's id in the user's event log. Errors don't have one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id: Option<u64>,
+    event: &'a str,
+    data: serde_json::Value,
+}
+
+/// A message sent from the client to the server
+#[derive(Debug, serde::Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+enum Command {
+    ChangeReportStatus(ReportStatusUpdate),
+}
+
+pub(super) async fn ws_handler_v4<D>(
+    State(state): State<V4AppState<D>>,
+    UserId(user_id): UserId,
+    ws: WebSocketUpgrade,
+) -> Response
+where
+    D: Database + Clone + Send + Sync + 'static,
+    D::Error: Debug,
+{
+    let connection = match state.limits.open_connection(user_id) {
+        Ok(connection) => connection,
+        Err(err) => return err.into_response(),
+    };
+    ws.on_upgrade(move |socket| handle_socket(socket, state, user_id, connection))
+}
+
+/// `_connection` keeps the socket counted towards the connection limits until it closes
+async fn handle_socket<D>(
+    mut socket: WebSocket,
+    state: V4AppState<D>,
+    user_id: Uuid,
+    _connection: ConnectionGuard,
+) where
+    D: Database + Clone + Send + Sync,
+    D::Error: Debug,
+{
+    let (ws_sender, ws_receiver): (Sender<LoggedEvent>, Receiver<LoggedEvent>) = channel(100);
+
+    let connect = AppEvent::UserConnected {
+        user_id,
+        sender: ws_sender.clone(),
+        last_event_id: None,
+    };
+
+    if state.app_event_sender.send(connect).await.is_err() {
+        tracing::error!("Error Connecting {user_id}");
+        let _ = socket.send(Message::Close(None)).await;
+        return;
+    }
+
+    // Dropping the receiver once the socket closes lets the event loop forget the connection
+    tokio::spawn(handle_disconnect(
+        state.app_event_sender.clone(),
+        ws_sender,
+        AppEvent::UserDisconnected { user_id },
+    ));
+
+    forward_messages(&mut socket, ws_receiver, &state, user_id).await;
+    tracing::info!("websocket for user {user_id} closed");
+}
+
+/// Relay messages in both directions until either side goes away
+async fn forward_messages<D>(
+    socket: &mut WebSocket,
+    mut ws_receiver: Receiver<LoggedEvent>,
+    state: &V4AppState<D>,
+    user_id: Uuid,
+) where
+    D: Database + Clone + Send + Sync,
+    D::Error: Debug,
+{
+    let mut ping = tokio::time::interval(PING_INTERVAL);
+    ping.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+    loop {
+        tokio::select! {
+            message = ws_receiver.recv() => {
+                let Some(logged_event) = message else {
+                    return;
+                };
+                let Some((event, data)) = event_payload(&logged_event.message) else {
+                    continue;
+                };
+                let message = OutgoingMessage { id: Some(logged_event.id), event, data };
+                if send_json(socket, &message).await.is_err() {
+                    return;
+                }
+            }
+            incoming = socket.recv() => {
+                let text = match incoming {
+                    Some(Ok(Message::Text(text))) => text,
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => return,
+                    // Pings are answered automatically
+                    Some(Ok(_)) => continue,
+                };
+                if let Err(err) = handle_command(&text, state, user_id).await {
+                    let data = serde_json::json!({ "message": err });
+                    let message = OutgoingMessage { id: None, event: "error", data };
+                    if send_json(socket, &message).await.is_err() {
+                        return;
+                    }
+                }
+            }
+            _ = ping.tick() => {
+                if socket.send(Message::Ping(vec![])).await.is_err() {
+                    return;
+                }
+            }
+        }
+    }
+}
+
+async fn send_json(socket: &mut WebSocket, message: &OutgoingMessage<'_>) -> Result<(), ()> {
+    let Ok(text) = serde_json::to_string(message) else {
+        return Ok(());
+    };
+    socket.send(Message::Text(text)).await.map_err(|err| {
+        tracing::debug!("unable to send websocket message. {err:?}");
+    })
+}
+
+/// Commands go through the same channels as the HTTP endpoints so that every transport shares
+/// the same validation and fan-out
+async fn handle_command<D>(text: &str, state: &V4AppState<D>, user_id: Uuid) -> Result<(), String>
+where
+    D: Database + Clone + Send + Sync,
+    D::Error: Debug,
+{
+    let command: Command =
+        serde_json::from_str(text).map_err(|err| format!("invalid command: {err}"))?;
+    state
+        .limits
+        .check_rate(user_id)
+        .map_err(|err| err.to_string())?;
+
+    match command {
+        Command::ChangeReportStatus(mut update) => {
+            get_owned_report(&state.database, user_id, update.id)
+                .await
+                .map_err(|(_, err)| err)?;
+            update.source = UpdateSource::WebSocket;
+            // Only the watchdog and workers explain why a report changed status
+            update.reason = None;
+            state
+                .report_status_sender
+                .send(update)
+                .await
+                .map_err(|err| {
+                    tracing::error!(
+                        "Unable to to send report status update message for user {user_id}. {err:?}"
+                    );
+                    "unable to update the report status".to_owned()
+                })
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::config::V4Config;
+    use crate::v4::app_events::{NewReport, Report};
+    use crate::v4::memory::InMemoryDatabase;
+    use crate::v4::{limits, webhooks};
+
+    #[tokio::test]
+    async fn test_users_can_only_change_their_own_reports() {
+        let database = InMemoryDatabase::new();
+        let owner = Uuid::new_v4();
+        let report = Report::new(owner, NewReport::new("mine".to_owned(), Default::default()));
+        database.insert_report(report.clone()).await.unwrap();
+
+        let config = V4Config::default();
+        let (app_event_sender, _app_events) = channel(10);
+        let (report_status_sender, mut updates) = channel(10);
+        let state = V4AppState {
+            app_event_sender,
+            report_status_sender,
+            database,
+            limits: Arc::new(limits::Limits::new(config.limits.clone())),
+            config: Arc::new(config),
+            webhooks: webhooks::SharedWebhookRegistry::default(),
+        };
+
+        let command = serde_json::json!({
+            "command": "change_report_status",
+            "id": report.report_id,
+            "status": "canceled",
+            "reason": "made up",
+        })
+        .to_string();
+
+        let err = handle_command(&command, &state, Uuid::new_v4())
+            .await
+            .unwrap_err();
+        assert!(err.contains("belongs to another user"), "{err}");
+        assert!(updates.try_recv().is_err());
+
+        handle_command(&command, &state, owner).await.unwrap();
+        let update = updates.try_recv().unwrap();
+        assert_eq!(update.id, report.report_id);
+        assert_eq!(update.source, UpdateSource::WebSocket);
+        assert_eq!(update.reason, None);
+    }
+}
@@ -0,0 +1,396 @@
+//! Endpoints for the workers that process queued reports.
+//!
+//! A worker claims the oldest `queued` report, which moves it to `processing` under a [Lease].
+//! While it works on the report it sends heartbeats to renew the lease, and finishes by moving
+//! the report to `completed` or `failed`. If the lease runs out first the report is requeued so
+//! another worker can pick it up.
+//!
+//! Every endpoint requires the worker token, or the admin token, as a bearer token.
+use axum::extract::{Json, Path, State};
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use chrono::Utc;
+use std::fmt::Debug;
+use tokio::sync::mpsc::Sender;
+use uuid::Uuid;
+
+use super::admin::{bearer_token, constant_time_eq};
+use super::app_events::{AppEvent, Report, ReportStatusUpdate, UpdateSource};
+use super::database::{Database, Lease};
+use super::report_status::ReportStatus;
+use super::V4AppState;
+use crate::config::{V4Config, WorkerConfig};
+
+#[derive(Debug, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(super) struct ClaimedReport {
+    report: Report,
+    lease: Lease,
+}
+
+#[derive(Debug, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(super) struct Heartbeat {
+    lease_token: Uuid,
+}
+
+#[derive(Debug, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(super) struct FinishReport {
+    lease_token: Uuid,
+    status: ReportStatus,
+    /// Why the report failed
+    reason: Option<String>,
+}
+
+/// Workers can use either the worker token or the admin token
+fn authorize_worker(config: &V4Config, headers: &HeaderMap) -> Result<(), (StatusCode, String)> {
+    let tokens: Vec<&str> = [
+        config.workers.token.as_deref(),
+        config.admin_token.as_deref(),
+    ]
+    .into_iter()
+    .flatten()
+    .collect();
+    if tokens.is_empty() {
+        return Err((
+            StatusCode::FORBIDDEN,
+            "the worker endpoints are disabled".to_owned(),
+        ));
+    }
+
+    match bearer_token(headers) {
+        Some(token)
+            if tokens
+                .iter()
+                .any(|expected| constant_time_eq(token.as_bytes(), expected.as_bytes())) =>
+        {
+            Ok(())
+        }
+        Some(_) => Err((StatusCode::FORBIDDEN, "invalid worker token".to_owned())),
+        None => Err((StatusCode::UNAUTHORIZED, "missing worker token".to_owned())),
+    }
+}
+
+fn new_lease(config: &WorkerConfig) -> Lease {
+    Lease {
+        token: Uuid::new_v4(),
+        expires_at: Utc::now() + config.lease_duration,
+    }
+}
+
+fn lease_lost(report_id: Uuid) -> (StatusCode, String) {
+    (
+        StatusCode::CONFLICT,
+        format!("the lease on report {report_id} expired or was released"),
+    )
+}
+
+/// Let the event loop cache the new status and notify the report's owner
+async fn notify_status_changed(
+    app_event_sender: &Sender<AppEvent>,
+    user_id: Uuid,
+    old_status: ReportStatus,
+    update: ReportStatusUpdate,
+) {
+    let report_id = update.id;
+    let message = AppEvent::ReportStatusChanged {
+        user_id,
+        old_status,
+        update,
+    };
+    if let Err(err) = app_event_sender.send(message).await {
+        tracing::warn!("unable to send status change message for {report_id}. {err:?}");
+    }
+}
+
+/// Claim the oldest queued report. Responds with `204 No Content` when nothing is queued
+pub(super) async fn claim_report<D>(
+    State(state): State<V4AppState<D>>,
+    headers: HeaderMap,
+) -> Result<Response, (StatusCode, String)>
+where
+    D: Database + Clone + Sync + Send,
+    D::Error: Debug,
+{
+    authorize_worker(&state.config, &headers)?;
+
+    if let Err(err) = state
+        .config
+        .state_machine
+        .transition(ReportStatus::Queued, ReportStatus::Processing)
+    {
+        return Err((StatusCode::CONFLICT, err.to_string()));
+    }
+
+    let lease = new_lease(&state.config.workers);
+
+    let report = match state.database.claim_report(&lease).await {
+        Ok(Some(report)) => report,
+        Ok(None) => return Ok(StatusCode::NO_CONTENT.into_response()),
+        Err(err) => {
+            tracing::error!("Unable to claim a report. {err:?}");
+            return Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "unable to claim a report".to_owned(),
+            ));
+        }
+    };
+
+    let mut update = ReportStatusUpdate::new(report.report_id, ReportStatus::Processing);
+    update.source = UpdateSource::Worker;
+    notify_status_changed(
+        &state.app_event_sender,
+        report.user_id,
+        ReportStatus::Queued,
+        update,
+    )
+    .await;
+
+    Ok(Json(ClaimedReport { report, lease }).into_response())
+}
+
+/// Extend the lease on a report the worker is still processing
+pub(super) async fn heartbeat<D>(
+    State(state): State<V4AppState<D>>,
+    headers: HeaderMap,
+    Path(report_id): Path<Uuid>,
+    Json(heartbeat): Json<Heartbeat>,
+) -> Result<Json<Lease>, (StatusCode, String)>
+where
+    D: Database + Clone + Sync + Send,
+    D::Error: Debug,
+{
+    authorize_worker(&state.config, &headers)?;
+
+    let lease = Lease {
+        token: heartbeat.lease_token,
+        ..new_lease(&state.config.workers)
+    };
+
+    match state.database.renew_lease(report_id, &lease).await {
+        Ok(true) => Ok(Json(lease)),
+        Ok(false) => Err(lease_lost(report_id)),
+        Err(err) => {
+            tracing::error!("Unable to renew the lease on report {report_id}. {err:?}");
+            Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "unable to renew the lease".to_owned(),
+            ))
+        }
+    }
+}
+
+/// Move a leased report to `completed` or `failed` and release the lease
+pub(super) async fn finish_report<D>(
+    State(state): State<V4AppState<D>>,
+    headers: HeaderMap,
+    Path(report_id): Path<Uuid>,
+    Json(finish): Json<FinishReport>,
+) -> Result<StatusCode, (StatusCode, String)>
+where
+    D: Database + Clone + Sync + Send,
+    D::Error: Debug,
+{
+    authorize_worker(&state.config, &headers)?;
+
+    if !matches!(
+        finish.status,
+        ReportStatus::Completed | ReportStatus::Failed
+    ) {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!(
+                "workers can only finish reports as completed or failed, not {}",
+                finish.status
+            ),
+        ));
+    }
+
+    if let Err(err) = state
+        .config
+        .state_machine
+        .transition(ReportStatus::Processing, finish.status)
+    {
+        return Err((StatusCode::BAD_REQUEST, err.to_string()));
+    }
+
+    let mut update = ReportStatusUpdate::new(report_id, finish.status);
+    update.source = UpdateSource::Worker;
+    update.reason = finish.reason;
+
+    let user_id = match state
+        .database
+        .release_lease(finish.lease_token, &update)
+        .await
+    {
+        Ok(Some(user_id)) => user_id,
+        Ok(None) => return Err(lease_lost(report_id)),
+        Err(err) => {
+            tracing::error!("Unable to finish report {report_id}. {err:?}");
+            return Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "unable to finish the report".to_owned(),
+            ));
+        }
+    };
+
+    notify_status_changed(
+        &state.app_event_sender,
+        user_id,
+        ReportStatus::Processing,
+        update,
+    )
+    .await;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Put reports back on the queue when their worker stops sending heartbeats.
+///
+/// Clients can't move a report from `processing` back to `queued`, so this bypasses the state
+/// machine. Only the worker holding the lease could have finished the report
+pub(super) async fn requeue_expired_leases<D>(
+    app_event_sender: Sender<AppEvent>,
+    database: D,
+    config: WorkerConfig,
+) where
+    D: Database,
+    D::Error: Debug,
+{
+    let mut interval = tokio::time::interval(config.lease_check_interval);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+    loop {
+        interval.tick().await;
+
+        let expired = match database.list_expired_leases(Utc::now()).await {
+            Ok(expired) => expired,
+            Err(err) => {
+                tracing::error!("could not look for expired leases: {err:?}");
+                continue;
+            }
+        };
+
+        for (report_id, lease) in expired {
+            let mut update = ReportStatusUpdate::new(report_id, ReportStatus::Queued);
+            update.source = UpdateSource::Worker;
+            update.reason = Some("the worker's lease expired".to_owned());
+
+            // The worker could have finished the report since we looked, in which case
+            // the lease is already gone and there's nothing to do
+            let user_id = match database.release_lease(lease.token, &update).await {
+                Ok(Some(user_id)) => user_id,
+                Ok(None) => continue,
+                Err(err) => {
+                    tracing::error!("could not requeue report {report_id}: {err:?}");
+                    continue;
+                }
+            };
+
+            tracing::info!("requeued report {report_id} after its lease expired");
+            notify_status_changed(&app_event_sender, user_id, ReportStatus::Processing, update)
+                .await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use tokio::sync::mpsc;
+
+    use super::*;
+    use crate::v4::app_events::NewReport;
+    use crate::v4::memory::InMemoryDatabase;
+
+    #[tokio::test]
+    async fn test_expired_leases_are_requeued() {
+        let database = InMemoryDatabase::new();
+        let mut report = Report::new(
+            Uuid::new_v4(),
+            NewReport::new("leased".to_owned(), Default::default()),
+        );
+        report.report_status = ReportStatus::Queued;
+        database.insert_report(report.clone()).await.unwrap();
+
+        let expired = Lease {
+            token: Uuid::new_v4(),
+            expires_at: Utc::now() - chrono::Duration::seconds(1),
+        };
+        database.claim_report(&expired).await.unwrap().unwrap();
+
+        let config = WorkerConfig {
+            lease_check_interval: Duration::from_millis(10),
+            ..WorkerConfig::default()
+        };
+        let (sender, mut receiver) = mpsc::channel(10);
+        let requeue = tokio::spawn(requeue_expired_leases(sender, database.clone(), config));
+
+        let event = tokio::time::timeout(Duration::from_secs(2), receiver.recv())
+            .await
+            .expect("the report is requeued")
+            .unwrap();
+        let AppEvent::ReportStatusChanged {
+            user_id,
+            old_status,
+            update,
+        } = event
+        else {
+            panic!("expected a status change");
+        };
+        requeue.abort();
+
+        assert_eq!(user_id, report.user_id);
+        assert_eq!(old_status, ReportStatus::Processing);
+        assert_eq!(update.status, ReportStatus::Queued);
+        assert_eq!(update.source, UpdateSource::Worker);
+
+        let requeued = database
+            .get_report(report.report_id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(requeued.report_status, ReportStatus::Queued);
+        assert_eq!(
+            requeued.status_reason.as_deref(),
+            Some("the worker's lease expired")
+        );
+
+        // The worker that lost the lease can't keep going
+        assert!(!database
+            .renew_lease(report.report_id, &expired)
+            .await
+            .unwrap());
+        let finish = ReportStatusUpdate::new(report.report_id, ReportStatus::Completed);
+        assert_eq!(
+            database
+                .release_lease(expired.token, &finish)
+                .await
+                .unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn test_authorize_worker() {
+        let bearer = |token: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert("authorization", format!("Bearer {token}").parse().unwrap());
+            headers
+        };
+
+        let mut config = V4Config::default();
+        let (status, _) = authorize_worker(&config, &bearer("anything")).unwrap_err();
+        assert_eq!(status, StatusCode::FORBIDDEN);
+
+        config.workers.token = Some("worker".to_owned());
+        config.admin_token = Some("admin".to_owned());
+        assert!(authorize_worker(&config, &bearer("worker")).is_ok());
+        assert!(authorize_worker(&config, &bearer("admin")).is_ok());
+        let (status, _) = authorize_worker(&config, &bearer("wrong")).unwrap_err();
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let (status, _) = authorize_worker(&config, &HeaderMap::new()).unwrap_err();
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+}
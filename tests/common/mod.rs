@@ -0,0 +1,307 @@
+//! Runs the v4 app on a random port with an in-memory database and without Kafka, so tests can
+//! talk to it over HTTP like any other client.
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use futures::stream::{BoxStream, StreamExt};
+use reqwest::StatusCode;
+pub use server_sent_events::sse_parser::SseEvent;
+use server_sent_events::sse_parser::SseParser;
+use server_sent_events::{
+    create_app_v4, BulkStatusUpdateResult, Config, InMemoryDatabase, MessageBus, NewReport, Report,
+    ReportList, ReportStatus, ReportStatusUpdate,
+};
+use uuid::Uuid;
+
+/// Workers authenticate with this token
+pub const WORKER_TOKEN: &str = "worker-secret";
+/// Token for the admin endpoints and metrics
+pub const ADMIN_TOKEN: &str = "admin-secret";
+
+pub struct TestApp {
+    base_url: String,
+    client: reqwest::Client,
+}
+
+impl TestApp {
+    pub async fn spawn() -> Self {
+        let mut config = Config::default();
+        // Users are picked with `?user_id=`, which keeps the tests short
+        config.auth.insecure_query_auth = true;
+        config.v4.message_bus = MessageBus::InProcess;
+        // The watchdog and lease expiry would only make the tests timing dependent
+        config.v4.background_tasks = false;
+        config.v4.workers.token = Some(WORKER_TOKEN.to_owned());
+        config.v4.admin_token = Some(ADMIN_TOKEN.to_owned());
+        // Long polls that don't get an event would otherwise hold up a test for 30 seconds
+        config.v4.event_log.long_poll_timeout = Duration::from_millis(500);
+        // Nothing is written here unless a test records a connection
+        config.v4.recordings_dir =
+            Some(std::env::temp_dir().join(format!("sse-test-recordings-{}", Uuid::new_v4())));
+
+        let app = create_app_v4(InMemoryDatabase::new(), config);
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("bind to a random port");
+        let address = listener.local_addr().expect("listener has an address");
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        Self {
+            base_url: format!("http://{address}/v4"),
+            client: reqwest::Client::new(),
+        }
+    }
+
+    pub async fn create_report(&self, user_id: Uuid, name: &str) -> Report {
+        let response = self
+            .client
+            .post(format!("{}/new/report?user_id={user_id}", self.base_url))
+            .json(&NewReport::new(name.to_owned(), Default::default()))
+            .send()
+            .await
+            .expect("create the report");
+        assert_eq!(response.status(), StatusCode::CREATED);
+        response.json().await.expect("the new report")
+    }
+
+    /// Returns as soon as the update is accepted. The transition is validated afterwards
+    pub async fn change_status(&self, user_id: Uuid, report_id: Uuid, status: ReportStatus) {
+        assert_eq!(
+            self.try_change_status(user_id, report_id, status).await,
+            StatusCode::ACCEPTED
+        );
+    }
+
+    /// Like [TestApp::change_status], but returns the response status whatever it is
+    pub async fn try_change_status(
+        &self,
+        user_id: Uuid,
+        report_id: Uuid,
+        status: ReportStatus,
+    ) -> StatusCode {
+        self.client
+            .put(format!("{}/report?user_id={user_id}", self.base_url))
+            .json(&ReportStatusUpdate::new(report_id, status))
+            .send()
+            .await
+            .expect("change the report status")
+            .status()
+    }
+
+    pub async fn change_progress(
+        &self,
+        user_id: Uuid,
+        report_id: Uuid,
+        progress: u8,
+    ) -> StatusCode {
+        self.client
+            .put(format!(
+                "{}/report/progress?user_id={user_id}",
+                self.base_url
+            ))
+            .json(&serde_json::json!({ "id": report_id, "progress": progress }))
+            .send()
+            .await
+            .expect("change the report progress")
+            .status()
+    }
+
+    /// Change many statuses at once. The results are in the same order as `updates`
+    pub async fn bulk_change_status(
+        &self,
+        user_id: Uuid,
+        updates: &[(Uuid, ReportStatus)],
+    ) -> Vec<BulkStatusUpdateResult> {
+        let updates: Vec<_> = updates
+            .iter()
+            .map(|(report_id, status)| ReportStatusUpdate::new(*report_id, *status))
+            .collect();
+        let response = self
+            .client
+            .put(format!("{}/reports?user_id={user_id}", self.base_url))
+            .json(&updates)
+            .send()
+            .await
+            .expect("change the report statuses");
+        assert_eq!(response.status(), StatusCode::OK);
+        response.json().await.expect("the result of every update")
+    }
+
+    /// `query` is added to the request as is, e.g. `include_archived=true`
+    pub async fn list_reports(&self, user_id: Uuid, query: &str) -> ReportList {
+        let response = self.try_list_reports(user_id, query).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        response.json().await.expect("a page of reports")
+    }
+
+    /// Like [TestApp::list_reports], but returns the response whatever its status
+    pub async fn try_list_reports(&self, user_id: Uuid, query: &str) -> reqwest::Response {
+        self.client
+            .get(format!(
+                "{}/reports?user_id={user_id}&{query}",
+                self.base_url
+            ))
+            .send()
+            .await
+            .expect("list the reports")
+    }
+
+    pub async fn get_report(&self, user_id: Uuid, report_id: Uuid) -> reqwest::Response {
+        self.get(&format!("report/{report_id}?user_id={user_id}"))
+            .send()
+            .await
+            .expect("get the report")
+    }
+
+    pub async fn delete_report(&self, user_id: Uuid, report_id: Uuid) -> StatusCode {
+        self.client
+            .delete(format!(
+                "{}/report/{report_id}?user_id={user_id}",
+                self.base_url
+            ))
+            .send()
+            .await
+            .expect("delete the report")
+            .status()
+    }
+
+    pub async fn archive_report(
+        &self,
+        user_id: Uuid,
+        report_id: Uuid,
+        archived: bool,
+    ) -> StatusCode {
+        self.client
+            .put(format!(
+                "{}/report/{report_id}/archive?user_id={user_id}",
+                self.base_url
+            ))
+            .json(&serde_json::json!({ "archived": archived }))
+            .send()
+            .await
+            .expect("archive the report")
+            .status()
+    }
+
+    /// Open the user's SSE stream. The connection is registered by the time this returns
+    pub async fn subscribe(&self, user_id: Uuid) -> SseStream {
+        let response = self.open_stream(&format!("sse?user_id={user_id}")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        SseStream::new(response)
+    }
+
+    /// Reconnect to the user's SSE stream like a browser would, after seeing `last_event_id`
+    pub async fn resubscribe(&self, user_id: Uuid, last_event_id: &str) -> SseStream {
+        let response = self
+            .get(&format!("sse?user_id={user_id}"))
+            .header("last-event-id", last_event_id)
+            .send()
+            .await
+            .expect("open the sse stream");
+        assert_eq!(response.status(), StatusCode::OK);
+        SseStream::new(response)
+    }
+
+    /// Long-poll for the events after `since`
+    pub async fn poll(&self, user_id: Uuid, since: Option<&str>) -> serde_json::Value {
+        let mut path = format!("poll?user_id={user_id}");
+        if let Some(since) = since {
+            path.push_str(&format!("&since={since}"));
+        }
+        let response = self.get(&path).send().await.expect("poll for events");
+        assert_eq!(response.status(), StatusCode::OK);
+        response.json().await.expect("the polled events")
+    }
+
+    /// Open the SSE stream for a single report
+    pub async fn subscribe_to_report(&self, user_id: Uuid, report_id: Uuid) -> SseStream {
+        let response = self
+            .open_stream(&format!("report/{report_id}/sse?user_id={user_id}"))
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        SseStream::new(response)
+    }
+
+    pub async fn create_webhook(&self, user_id: Uuid, url: &str) -> reqwest::Response {
+        self.client
+            .post(format!("{}/webhooks?user_id={user_id}", self.base_url))
+            .json(&serde_json::json!({ "url": url }))
+            .send()
+            .await
+            .expect("create the webhook")
+    }
+
+    /// Start a request to one of the `/v4/worker` endpoints, e.g. `claim`, without any token
+    pub fn worker_request(&self, path: &str) -> reqwest::RequestBuilder {
+        self.client.post(format!("{}/worker/{path}", self.base_url))
+    }
+
+    /// Start a request to one of the `/v4/worker` endpoints as a worker
+    pub fn worker(&self, path: &str) -> reqwest::RequestBuilder {
+        self.worker_request(path).bearer_auth(WORKER_TOKEN)
+    }
+
+    /// Start a `GET` request to any `/v4` endpoint, e.g. `metrics`
+    pub fn get(&self, path_and_query: &str) -> reqwest::RequestBuilder {
+        self.client
+            .get(format!("{}/{path_and_query}", self.base_url))
+    }
+
+    /// Start a request to one of the streaming endpoints, e.g. `sse?user_id=...`
+    pub async fn open_stream(&self, path_and_query: &str) -> reqwest::Response {
+        self.client
+            .get(format!("{}/{path_and_query}", self.base_url))
+            .send()
+            .await
+            .expect("open the sse stream")
+    }
+}
+
+pub struct SseStream {
+    body: BoxStream<'static, reqwest::Result<Vec<u8>>>,
+    parser: SseParser,
+    /// Events that were parsed but haven't been read yet
+    events: VecDeque<SseEvent>,
+    /// The server ended the response
+    closed: bool,
+}
+
+impl SseStream {
+    pub fn new(response: reqwest::Response) -> Self {
+        Self {
+            body: response
+                .bytes_stream()
+                .map(|chunk| chunk.map(|bytes| bytes.to_vec()))
+                .boxed(),
+            parser: SseParser::default(),
+            events: VecDeque::new(),
+            closed: false,
+        }
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.closed
+    }
+
+    /// The next event, or `None` if nothing arrives within `timeout` or the stream ends
+    pub async fn next_event(&mut self, timeout: Duration) -> Option<SseEvent> {
+        tokio::time::timeout(timeout, self.read_event())
+            .await
+            .ok()
+            .flatten()
+    }
+
+    async fn read_event(&mut self) -> Option<SseEvent> {
+        loop {
+            if let Some(event) = self.events.pop_front() {
+                return Some(event);
+            }
+
+            let Some(chunk) = self.body.next().await else {
+                self.closed = true;
+                return None;
+            };
+            self.events.extend(self.parser.feed(&chunk.ok()?));
+        }
+    }
+}
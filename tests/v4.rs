@@ -0,0 +1,823 @@
+mod common;
+
+use std::time::Duration;
+
+use common::{SseEvent, SseStream, TestApp, ADMIN_TOKEN};
+use reqwest::StatusCode;
+use server_sent_events::{LimitsConfig, Report, ReportStatus, WebhookConfig};
+use uuid::Uuid;
+
+/// Long enough for the event loop to handle an update, short enough to keep the tests fast
+const TIMEOUT: Duration = Duration::from_secs(2);
+
+fn status_update(event: &SseEvent) -> (String, String) {
+    assert_eq!(event.event.as_deref(), Some("report_status_update"));
+    let data = event.json();
+    (
+        data["id"].as_str().unwrap().to_owned(),
+        data["status"].as_str().unwrap().to_owned(),
+    )
+}
+
+#[tokio::test]
+async fn test_only_valid_transitions_are_streamed() {
+    let app = TestApp::spawn().await;
+    let user_id = Uuid::new_v4();
+    let mut stream = app.subscribe(user_id).await;
+
+    let report = app.create_report(user_id, "quarterly numbers").await;
+    let report_id = report.report_id();
+    assert_eq!(report.report_status(), ReportStatus::Pending);
+
+    // `queued` can't go straight to `completed`, so that update is dropped
+    for status in [
+        ReportStatus::Queued,
+        ReportStatus::Completed,
+        ReportStatus::Processing,
+        ReportStatus::Completed,
+    ] {
+        app.change_status(user_id, report_id, status).await;
+    }
+
+    let mut updates = vec![];
+    for _ in 0..3 {
+        let event = stream.next_event(TIMEOUT).await.expect("a status update");
+        assert!(event.id.is_some(), "events can be resumed from their id");
+        updates.push(status_update(&event));
+    }
+
+    let report_id = report_id.to_string();
+    assert_eq!(
+        updates,
+        [
+            (report_id.clone(), "queued".to_owned()),
+            (report_id.clone(), "processing".to_owned()),
+            (report_id.clone(), "completed".to_owned()),
+        ]
+    );
+    assert!(stream
+        .next_event(Duration::from_millis(500))
+        .await
+        .is_none());
+}
+
+#[tokio::test]
+async fn test_users_only_see_their_own_reports() {
+    let app = TestApp::spawn().await;
+    let owner = Uuid::new_v4();
+    let someone_else = Uuid::new_v4();
+    let mut owner_stream = app.subscribe(owner).await;
+    let mut other_stream = app.subscribe(someone_else).await;
+
+    let report = app.create_report(owner, "private report").await;
+    app.change_status(owner, report.report_id(), ReportStatus::Canceled)
+        .await;
+
+    let event = owner_stream
+        .next_event(TIMEOUT)
+        .await
+        .expect("a status update");
+    assert_eq!(
+        status_update(&event),
+        (report.report_id().to_string(), "canceled".to_owned())
+    );
+    assert!(other_stream
+        .next_event(Duration::from_millis(500))
+        .await
+        .is_none());
+}
+
+#[tokio::test]
+async fn test_archive_and_delete_reports() {
+    let app = TestApp::spawn().await;
+    let owner = Uuid::new_v4();
+    let someone_else = Uuid::new_v4();
+    let mut stream = app.subscribe(owner).await;
+
+    let report = app.create_report(owner, "old report").await;
+    let report_id = report.report_id();
+
+    assert_eq!(
+        app.archive_report(someone_else, report_id, true).await,
+        StatusCode::FORBIDDEN
+    );
+    assert_eq!(
+        app.archive_report(owner, report_id, true).await,
+        StatusCode::NO_CONTENT
+    );
+    assert!(app.list_reports(owner, "").await.reports().is_empty());
+    let archived = app.list_reports(owner, "include_archived=true").await;
+    assert_eq!(archived.reports().len(), 1);
+    assert!(archived.reports()[0].archived());
+
+    // Archived reports can still be fetched on their own, but only by their owner
+    let fetched: Report = app
+        .get_report(owner, report_id)
+        .await
+        .json()
+        .await
+        .expect("the archived report");
+    assert_eq!(fetched.report_id(), report_id);
+    assert!(fetched.archived());
+    assert_eq!(
+        app.get_report(someone_else, report_id).await.status(),
+        StatusCode::FORBIDDEN
+    );
+
+    assert_eq!(
+        app.delete_report(someone_else, report_id).await,
+        StatusCode::FORBIDDEN
+    );
+    assert_eq!(
+        app.delete_report(owner, report_id).await,
+        StatusCode::NO_CONTENT
+    );
+
+    let event = stream.next_event(TIMEOUT).await.expect("a deleted event");
+    assert_eq!(event.event.as_deref(), Some("report_deleted"));
+    assert_eq!(event.json()["id"], report_id.to_string());
+
+    // Updates to a deleted report are turned away
+    assert_eq!(
+        app.try_change_status(owner, report_id, ReportStatus::Queued)
+            .await,
+        StatusCode::NOT_FOUND
+    );
+    assert!(stream
+        .next_event(Duration::from_millis(500))
+        .await
+        .is_none());
+    assert!(app
+        .list_reports(owner, "include_archived=true")
+        .await
+        .reports()
+        .is_empty());
+    assert_eq!(
+        app.delete_report(owner, report_id).await,
+        StatusCode::NOT_FOUND
+    );
+    assert_eq!(
+        app.get_report(owner, report_id).await.status(),
+        StatusCode::NOT_FOUND
+    );
+    assert_eq!(
+        app.archive_report(owner, report_id, false).await,
+        StatusCode::NOT_FOUND
+    );
+}
+
+#[tokio::test]
+async fn test_new_reports_can_be_changed_right_away() {
+    let app = TestApp::spawn().await;
+
+    // The report is stored by the time it's created, so nothing can get ahead of it.
+    // Every round has its own user to stay under the rate limit
+    for index in 0..20 {
+        let owner = Uuid::new_v4();
+        let report = app.create_report(owner, &format!("report {index}")).await;
+        assert_eq!(
+            app.archive_report(owner, report.report_id(), true).await,
+            StatusCode::NO_CONTENT
+        );
+        assert_eq!(
+            app.delete_report(owner, report.report_id()).await,
+            StatusCode::NO_CONTENT
+        );
+    }
+}
+
+#[tokio::test]
+async fn test_every_connection_of_a_user_gets_events() {
+    let app = TestApp::spawn().await;
+    let user_id = Uuid::new_v4();
+    let mut first_tab = app.subscribe(user_id).await;
+    let mut second_tab = app.subscribe(user_id).await;
+
+    let report = app.create_report(user_id, "shared report").await;
+    app.change_status(user_id, report.report_id(), ReportStatus::Queued)
+        .await;
+    for stream in [&mut first_tab, &mut second_tab] {
+        let event = stream.next_event(TIMEOUT).await.expect("a status update");
+        assert_eq!(status_update(&event).1, "queued");
+    }
+
+    // Closing one tab doesn't disconnect the other
+    drop(first_tab);
+    app.change_status(user_id, report.report_id(), ReportStatus::Processing)
+        .await;
+    let event = second_tab
+        .next_event(TIMEOUT)
+        .await
+        .expect("a status update");
+    assert_eq!(status_update(&event).1, "processing");
+}
+
+#[tokio::test]
+async fn test_paginate_and_filter_reports() {
+    let app = TestApp::spawn().await;
+    let user_id = Uuid::new_v4();
+    let mut created = vec![];
+    for index in 0..5 {
+        let report = app.create_report(user_id, &format!("report {index}")).await;
+        created.push(report.report_id());
+    }
+    app.change_status(user_id, created[0], ReportStatus::Queued)
+        .await;
+    app.change_status(user_id, created[1], ReportStatus::Canceled)
+        .await;
+    // The report for someone else must never show up
+    app.create_report(Uuid::new_v4(), "not mine").await;
+
+    // Walk every page
+    let mut listed = vec![];
+    let mut query = "page_size=2".to_owned();
+    loop {
+        let page = app.list_reports(user_id, &query).await;
+        assert!(page.reports().len() <= 2);
+        listed.extend(page.reports().iter().map(|report| report.report_id()));
+        match page.next_cursor() {
+            Some(cursor) => query = format!("page_size=2&cursor={cursor}"),
+            None => break,
+        }
+    }
+    listed.sort();
+    created.sort();
+    assert_eq!(listed, created);
+
+    // Updates are applied in the background, so wait until both have landed
+    let mut filtered = vec![];
+    for _ in 0..20 {
+        let page = app.list_reports(user_id, "status=queued,canceled").await;
+        filtered = page
+            .reports()
+            .iter()
+            .map(|report| report.report_status())
+            .collect();
+        if filtered.len() == 2 {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    filtered.sort_by_key(|status| status.to_string());
+    assert_eq!(filtered, [ReportStatus::Canceled, ReportStatus::Queued]);
+
+    let response = app.try_list_reports(user_id, "status=bogus").await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn test_reject_tampered_and_foreign_cursors() {
+    let app = TestApp::spawn().await;
+    let user_id = Uuid::new_v4();
+    for index in 0..2 {
+        app.create_report(user_id, &format!("report {index}")).await;
+    }
+    let page = app.list_reports(user_id, "page_size=1").await;
+    let cursor = page.next_cursor().expect("a second page").to_owned();
+
+    let someone_else = Uuid::new_v4();
+    let response = app
+        .try_list_reports(someone_else, &format!("cursor={cursor}"))
+        .await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+    let response = app
+        .try_list_reports(user_id, &format!("cursor={cursor}x"))
+        .await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn test_report_stream_starts_with_the_current_status_and_ends_when_done() {
+    let app = TestApp::spawn().await;
+    let owner = Uuid::new_v4();
+    let report = app.create_report(owner, "watched report").await;
+    let report_id = report.report_id();
+
+    let response = app
+        .open_stream(&format!(
+            "report/{report_id}/sse?user_id={}",
+            Uuid::new_v4()
+        ))
+        .await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+    let mut stream = app.subscribe_to_report(owner, report_id).await;
+    let event = stream
+        .next_event(TIMEOUT)
+        .await
+        .expect("the current status");
+    assert_eq!(
+        status_update(&event),
+        (report_id.to_string(), "pending".to_owned())
+    );
+
+    for status in [
+        ReportStatus::Queued,
+        ReportStatus::Processing,
+        ReportStatus::Completed,
+    ] {
+        app.change_status(owner, report_id, status).await;
+        let event = stream.next_event(TIMEOUT).await.expect("a status update");
+        assert_eq!(status_update(&event).1, status.to_string());
+    }
+    // `completed` is terminal, so the server closes the stream
+    assert!(stream.next_event(TIMEOUT).await.is_none());
+    assert!(stream.is_closed());
+
+    // Subscribing to a finished report only sends its final status
+    let mut stream = app.subscribe_to_report(owner, report_id).await;
+    let event = stream
+        .next_event(TIMEOUT)
+        .await
+        .expect("the current status");
+    assert_eq!(status_update(&event).1, "completed");
+    assert!(stream.next_event(TIMEOUT).await.is_none());
+    assert!(stream.is_closed());
+}
+
+#[tokio::test]
+async fn test_workers_claim_renew_and_finish_reports() {
+    let app = TestApp::spawn().await;
+    let user_id = Uuid::new_v4();
+    let mut stream = app.subscribe(user_id).await;
+
+    let response = app.worker_request("claim").send().await.unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    let response = app
+        .worker_request("claim")
+        .bearer_auth("not the token")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+    let response = app.worker("claim").send().await.unwrap();
+    assert_eq!(
+        response.status(),
+        StatusCode::NO_CONTENT,
+        "nothing is queued"
+    );
+
+    let report = app.create_report(user_id, "claimed report").await;
+    let report_id = report.report_id().to_string();
+    app.change_status(user_id, report.report_id(), ReportStatus::Queued)
+        .await;
+    let event = stream.next_event(TIMEOUT).await.expect("a status update");
+    assert_eq!(
+        status_update(&event),
+        (report_id.clone(), "queued".to_owned())
+    );
+
+    let response = app.worker("claim").send().await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let claimed: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(claimed["report"]["reportId"], report_id.as_str());
+    assert_eq!(claimed["report"]["reportStatus"], "processing");
+    let lease_token = claimed["lease"]["token"].as_str().unwrap().to_owned();
+
+    let event = stream.next_event(TIMEOUT).await.expect("a status update");
+    assert_eq!(
+        status_update(&event),
+        (report_id.clone(), "processing".to_owned())
+    );
+    assert_eq!(event.json()["source"], "worker");
+
+    let heartbeat = format!("report/{report_id}/heartbeat");
+    let response = app
+        .worker(&heartbeat)
+        .json(&serde_json::json!({ "leaseToken": lease_token }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let lease: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(lease["token"], lease_token.as_str());
+
+    let response = app
+        .worker(&heartbeat)
+        .json(&serde_json::json!({ "leaseToken": Uuid::new_v4() }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::CONFLICT, "not our lease");
+
+    let finish = format!("report/{report_id}/finish");
+    let response = app
+        .worker(&finish)
+        .json(&serde_json::json!({ "leaseToken": lease_token, "status": "queued" }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+    let response = app
+        .worker(&finish)
+        .json(&serde_json::json!({ "leaseToken": lease_token, "status": "completed" }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+    let event = stream.next_event(TIMEOUT).await.expect("a status update");
+    assert_eq!(
+        status_update(&event),
+        (report_id.clone(), "completed".to_owned())
+    );
+
+    // Finishing released the lease
+    let response = app
+        .worker(&finish)
+        .json(&serde_json::json!({ "leaseToken": lease_token, "status": "failed" }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::CONFLICT);
+    let response = app
+        .worker(&heartbeat)
+        .json(&serde_json::json!({ "leaseToken": lease_token }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::CONFLICT);
+}
+
+#[tokio::test]
+async fn test_bulk_updates_only_change_the_callers_reports() {
+    let app = TestApp::spawn().await;
+    let owner = Uuid::new_v4();
+    let someone_else = Uuid::new_v4();
+    let mut owner_stream = app.subscribe(owner).await;
+    let mut other_stream = app.subscribe(someone_else).await;
+
+    let mine = app.create_report(owner, "mine").await;
+    let theirs = app.create_report(someone_else, "theirs").await;
+    let missing = Uuid::new_v4();
+
+    let results = app
+        .bulk_change_status(
+            owner,
+            &[
+                (theirs.report_id(), ReportStatus::Canceled),
+                (mine.report_id(), ReportStatus::Queued),
+                (missing, ReportStatus::Queued),
+            ],
+        )
+        .await;
+
+    let ids: Vec<_> = results.iter().map(|result| result.id()).collect();
+    assert_eq!(ids, [theirs.report_id(), mine.report_id(), missing]);
+    assert!(!results[0].updated());
+    assert!(results[0]
+        .error()
+        .unwrap()
+        .contains("belongs to another user"));
+    assert!(results[1].updated(), "{:?}", results[1].error());
+    assert!(!results[2].updated());
+    assert!(results[2].error().unwrap().contains("not found"));
+
+    let event = owner_stream
+        .next_event(TIMEOUT)
+        .await
+        .expect("a status update");
+    assert_eq!(
+        status_update(&event),
+        (mine.report_id().to_string(), "queued".to_owned())
+    );
+    assert!(other_stream
+        .next_event(Duration::from_millis(500))
+        .await
+        .is_none());
+
+    let theirs_now = app.list_reports(someone_else, "").await;
+    assert_eq!(
+        theirs_now.reports()[0].report_status(),
+        ReportStatus::Pending
+    );
+}
+
+#[tokio::test]
+async fn test_webhooks_must_be_public_and_are_limited_per_user() {
+    let app = TestApp::spawn().await;
+    let user_id = Uuid::new_v4();
+
+    let response = app
+        .create_webhook(user_id, "http://169.254.169.254/latest/meta-data")
+        .await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let response = app
+        .create_webhook(user_id, "http://localhost:3000/v4")
+        .await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+    let max_per_user = WebhookConfig::default().max_per_user;
+    for i in 0..max_per_user {
+        let url = format!("https://93.184.216.34/hooks/{i}");
+        let response = app.create_webhook(user_id, &url).await;
+        assert_eq!(response.status(), StatusCode::CREATED);
+    }
+    let response = app
+        .create_webhook(user_id, "https://93.184.216.34/one-too-many")
+        .await;
+    assert_eq!(response.status(), StatusCode::CONFLICT);
+
+    // The limit is per user
+    let response = app
+        .create_webhook(Uuid::new_v4(), "https://93.184.216.34/hooks")
+        .await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+}
+
+#[tokio::test]
+async fn test_users_can_only_update_their_own_reports() {
+    let app = TestApp::spawn().await;
+    let owner = Uuid::new_v4();
+    let someone_else = Uuid::new_v4();
+    let mut owner_stream = app.subscribe(owner).await;
+
+    let report = app.create_report(owner, "private report").await;
+    let report_id = report.report_id();
+
+    assert_eq!(
+        app.try_change_status(someone_else, report_id, ReportStatus::Canceled)
+            .await,
+        StatusCode::FORBIDDEN
+    );
+    assert_eq!(
+        app.try_change_status(owner, Uuid::new_v4(), ReportStatus::Canceled)
+            .await,
+        StatusCode::NOT_FOUND
+    );
+    assert_eq!(
+        app.change_progress(someone_else, report_id, 50).await,
+        StatusCode::FORBIDDEN
+    );
+    assert!(owner_stream
+        .next_event(Duration::from_millis(500))
+        .await
+        .is_none());
+
+    app.change_status(owner, report_id, ReportStatus::Queued)
+        .await;
+    let event = owner_stream
+        .next_event(TIMEOUT)
+        .await
+        .expect("a status update");
+    assert_eq!(
+        status_update(&event),
+        (report_id.to_string(), "queued".to_owned())
+    );
+}
+
+#[tokio::test]
+async fn test_metrics_require_the_admin_token() {
+    let app = TestApp::spawn().await;
+
+    let response = app.get("metrics").send().await.unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    let response = app
+        .get("metrics")
+        .bearer_auth("not the token")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+    let response = app
+        .get("metrics")
+        .bearer_auth(ADMIN_TOKEN)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn test_long_polls_count_towards_the_connection_limit() {
+    let app = TestApp::spawn().await;
+    let user_id = Uuid::new_v4();
+
+    let max_per_user = LimitsConfig::default().max_connections_per_user.unwrap();
+    let mut streams = vec![];
+    for _ in 0..max_per_user {
+        streams.push(app.subscribe(user_id).await);
+    }
+
+    let response = app
+        .get(&format!("poll?user_id={user_id}"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+
+    // Once a stream closes there's room for the poll again
+    drop(streams.pop());
+    let mut accepted = false;
+    for _ in 0..20 {
+        let response = app
+            .get(&format!("poll?user_id={user_id}&since=0"))
+            .send()
+            .await
+            .unwrap();
+        if response.status() == StatusCode::OK {
+            accepted = true;
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    assert!(accepted, "the poll is accepted once a stream closes");
+}
+
+#[tokio::test]
+async fn test_recorded_connections_can_be_replayed() {
+    let app = TestApp::spawn().await;
+    let user_id = Uuid::new_v4();
+
+    // Connections opened at the same time still get recordings of their own
+    let with_presence = format!("sse?user_id={user_id}&record=true&presence=true");
+    let without_presence = format!("sse?user_id={user_id}&record=true");
+    let (first, second) = tokio::join!(
+        app.open_stream(&with_presence),
+        app.open_stream(&without_presence),
+    );
+    assert_eq!(first.status(), StatusCode::OK);
+    assert_eq!(second.status(), StatusCode::OK);
+    let recording = first.headers()["x-recording"].to_str().unwrap().to_owned();
+    assert_ne!(recording, second.headers()["x-recording"]);
+    assert!(recording.starts_with(&user_id.to_string()));
+    let mut stream = SseStream::new(first);
+
+    let someone_else = Uuid::new_v4();
+    let _other_stream = app.subscribe(someone_else).await;
+    let report = app.create_report(user_id, "recorded report").await;
+    app.change_status(user_id, report.report_id(), ReportStatus::Queued)
+        .await;
+
+    // Presence events are recorded along with the report's events
+    let mut received = vec![];
+    let mut seen = (false, false);
+    while seen != (true, true) {
+        let event = stream.next_event(TIMEOUT).await.expect("another event");
+        match event.event.as_deref() {
+            Some("user_joined") if event.json()["userId"] == someone_else.to_string() => {
+                assert!(event.id.is_none());
+                seen.0 = true;
+            }
+            Some("report_status_update") => seen.1 = true,
+            _ => {}
+        }
+        received.push(event);
+    }
+    drop(stream);
+
+    // The recording is written in the background, so it can take a moment to catch up
+    let path = format!("recordings/{recording}/sse?user_id={user_id}&speed=100");
+    let mut replayed = vec![];
+    for _ in 0..20 {
+        let response = app.open_stream(&path).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let mut replay = SseStream::new(response);
+        replayed.clear();
+        while let Some(event) = replay.next_event(TIMEOUT).await {
+            replayed.push(event);
+        }
+        assert!(replay.is_closed(), "the replay ends with the recording");
+        if replayed.len() >= received.len() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    assert_eq!(replayed[..received.len()], received);
+}
+
+#[tokio::test]
+async fn test_replays_are_limited_to_the_owner_and_sane_speeds() {
+    let app = TestApp::spawn().await;
+    let user_id = Uuid::new_v4();
+    let response = app
+        .open_stream(&format!("sse?user_id={user_id}&record=true"))
+        .await;
+    let recording = response.headers()["x-recording"]
+        .to_str()
+        .unwrap()
+        .to_owned();
+
+    let replay = |user_id: Uuid, speed: &str| {
+        app.get(&format!(
+            "recordings/{recording}/sse?user_id={user_id}&speed={speed}"
+        ))
+        .send()
+    };
+    for speed in ["0.001", "0", "-1", "101", "NaN", "inf"] {
+        let response = replay(user_id, speed).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST, "{speed}");
+    }
+    for speed in ["0.01", "100"] {
+        let response = replay(user_id, speed).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK, "{speed}");
+    }
+    let response = replay(Uuid::new_v4(), "1").await.unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn test_reconnecting_clients_get_the_events_they_missed() {
+    let app = TestApp::spawn().await;
+    let user_id = Uuid::new_v4();
+    let mut stream = app.subscribe(user_id).await;
+    let report = app.create_report(user_id, "resumed report").await;
+    let report_id = report.report_id();
+
+    app.change_status(user_id, report_id, ReportStatus::Queued)
+        .await;
+    let seen = stream.next_event(TIMEOUT).await.expect("a status update");
+    drop(stream);
+
+    // Events sent while the client was away
+    let mut missed = vec![];
+    for status in [ReportStatus::Processing, ReportStatus::Completed] {
+        app.change_status(user_id, report_id, status).await;
+        let event = app.poll(user_id, None).await["events"]
+            .as_array()
+            .unwrap()
+            .last()
+            .cloned()
+            .unwrap();
+        missed.push(event);
+    }
+
+    let mut stream = app.resubscribe(user_id, seen.id.as_deref().unwrap()).await;
+    for expected in missed {
+        let event = stream.next_event(TIMEOUT).await.expect("a missed event");
+        assert_eq!(event.id, Some(expected["id"].to_string()));
+        assert_eq!(event.json(), expected["data"]);
+    }
+    assert!(stream
+        .next_event(Duration::from_millis(500))
+        .await
+        .is_none());
+}
+
+#[tokio::test]
+async fn test_poll_returns_events_after_since() {
+    let app = TestApp::spawn().await;
+    let user_id = Uuid::new_v4();
+    let report = app.create_report(user_id, "polled report").await;
+
+    app.change_status(user_id, report.report_id(), ReportStatus::Queued)
+        .await;
+    // Only gets events sent after it connects
+    let mut stream = app.subscribe(user_id).await;
+    let first = app.poll(user_id, None).await;
+    let events = first["events"].as_array().unwrap();
+    assert_eq!(events.len(), 1);
+    assert_eq!(events[0]["event"], "report_status_update");
+    assert_eq!(events[0]["data"]["status"], "queued");
+    let since = first["lastEventId"].to_string();
+    assert_eq!(since, events[0]["id"].to_string());
+
+    // Nothing new, so the poll waits for the long-poll timeout and comes back empty
+    let empty = app.poll(user_id, Some(&since)).await;
+    assert_eq!(empty["events"], serde_json::json!([]));
+    assert_eq!(empty["lastEventId"].to_string(), since);
+
+    // A waiting poll gets the next event as soon as it's sent
+    let (next, ()) = tokio::join!(app.poll(user_id, Some(&since)), async {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        app.change_status(user_id, report.report_id(), ReportStatus::Processing)
+            .await;
+    });
+    let events = next["events"].as_array().unwrap();
+    assert_eq!(events.len(), 1);
+    assert_eq!(events[0]["data"]["status"], "processing");
+
+    // Streams and polls see the same events
+    let event = stream.next_event(TIMEOUT).await.expect("a status update");
+    assert_eq!(event.id, Some(events[0]["id"].to_string()));
+}
+
+#[tokio::test]
+async fn test_presence_events_are_opt_in() {
+    let app = TestApp::spawn().await;
+    let mut not_watching = app.subscribe(Uuid::new_v4()).await;
+    let watcher = Uuid::new_v4();
+    let response = app
+        .open_stream(&format!("sse?user_id={watcher}&presence=true"))
+        .await;
+    let mut watching = SseStream::new(response);
+
+    let visitor = Uuid::new_v4();
+    let visit = app.subscribe(visitor).await;
+    let joined = watching.next_event(TIMEOUT).await.expect("user_joined");
+    assert_eq!(joined.event.as_deref(), Some("user_joined"));
+    assert_eq!(joined.json()["userId"], visitor.to_string());
+
+    drop(visit);
+    let left = watching.next_event(TIMEOUT).await.expect("user_left");
+    assert_eq!(left.event.as_deref(), Some("user_left"));
+    assert_eq!(left.json()["userId"], visitor.to_string());
+
+    assert!(not_watching
+        .next_event(Duration::from_millis(500))
+        .await
+        .is_none());
+}